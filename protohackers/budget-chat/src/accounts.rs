@@ -0,0 +1,229 @@
+use crate::{write_atomically, CommandError, Shared};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, error, info};
+
+/// A name somebody registered, and what it takes to prove it's theirs.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    /// Picked at random for every password, hex encoded.
+    salt: String,
+    /// How many rounds of PBKDF2 the hash went through.
+    rounds: u32,
+    /// PBKDF2-HMAC-SHA256 of the password, hex encoded.
+    hash: String,
+}
+
+impl Account {
+    const SALT_BYTES: usize = 16;
+
+    pub fn new(name: &str, password: &str, rounds: u32) -> Self {
+        let salt = encode_hex(&rand::random::<[u8; Self::SALT_BYTES]>());
+        Self {
+            name: name.to_string(),
+            hash: encode_hex(&hash(password, &salt, rounds)),
+            salt,
+            rounds,
+        }
+    }
+
+    /// Check a password against the account, taking just as long however close it got.
+    pub fn check_password(&self, password: &str) -> bool {
+        let expected = hash(password, &self.salt, self.rounds);
+        let expected = encode_hex(&expected);
+        expected.len() == self.hash.len()
+            && expected
+                .bytes()
+                .zip(self.hash.bytes())
+                .fold(0, |difference, (left, right)| difference | (left ^ right))
+                == 0
+    }
+}
+
+fn hash(password: &str, salt: &str, rounds: u32) -> [u8; 32] {
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    hash
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Every registered name, shared between staging (which asks for their passwords) and
+/// the rooms (which register them), and written to disk on every change if we have a path.
+/// Names are looked up case-insensitively, same as claimed names, so registering `alice`
+/// covers `Alice` too.
+///
+/// Hashing a password is slow on purpose, so everything that checks or sets one
+/// blocks, and belongs on [spawn_blocking](tokio::task::spawn_blocking).
+#[derive(Debug, Clone)]
+pub struct Accounts {
+    /// Keyed by the lowercased name.
+    accounts: Shared<HashMap<String, Account>>,
+    path: Option<PathBuf>,
+    rounds: u32,
+    guests: bool,
+    /// How long a peer who claimed a registered name has to give its password.
+    password_timeout: Duration,
+    /// How many wrong passwords a single connection can give before we hang up on it.
+    max_attempts: u32,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Self {
+            accounts: Default::default(),
+            path: None,
+            rounds: Self::DEFAULT_ROUNDS,
+            guests: true,
+            password_timeout: Self::DEFAULT_PASSWORD_TIMEOUT,
+            max_attempts: Self::DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+}
+
+impl Accounts {
+    pub const DEFAULT_ROUNDS: u32 = 100_000;
+    pub const DEFAULT_PASSWORD_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+    /// Load whatever accounts were saved at the given path, and keep saving them there.
+    /// A missing file means nobody's registered yet.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let accounts: Vec<Account> = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        info!(path = %path.display(), accounts = accounts.len(), "Loaded accounts.");
+        Ok(Self {
+            accounts: Arc::new(Mutex::new(
+                accounts
+                    .into_iter()
+                    .map(|account| (key(&account.name), account))
+                    .collect(),
+            )),
+            path: Some(path),
+            ..Default::default()
+        })
+    }
+
+    /// Let anyone in under a name nobody registered, or only those who know the password to one.
+    pub fn with_guests(mut self, guests: bool) -> Self {
+        self.guests = guests;
+        self
+    }
+
+    /// Hash new passwords with this many rounds of PBKDF2. Existing ones keep theirs.
+    pub fn with_rounds(mut self, rounds: u32) -> Self {
+        self.rounds = rounds;
+        self
+    }
+
+    /// Give up on peers who claimed a registered name and don't give its password within this long,
+    /// so nobody can keep the name from its owner by sitting on the password prompt.
+    pub fn with_password_timeout(mut self, password_timeout: Duration) -> Self {
+        self.password_timeout = password_timeout;
+        self
+    }
+
+    /// Hang up on a connection once it's given this many wrong passwords.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn password_timeout(&self) -> Duration {
+        self.password_timeout
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn allows_guests(&self) -> bool {
+        self.guests
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&key(name))
+    }
+
+    /// Check a password for a name. Names nobody registered have no password.
+    pub fn check_password(&self, name: &str, password: &str) -> bool {
+        let Some(account) = self.accounts.lock().unwrap().get(&key(name)).cloned() else {
+            return false;
+        };
+        account.check_password(password)
+    }
+
+    /// Register a name nobody has registered yet.
+    pub fn register(&self, name: &str, password: &str) -> Result<(), CommandError> {
+        if self.is_registered(name) {
+            return Err(CommandError::AlreadyRegistered(name.to_string()));
+        }
+        let account = Account::new(name, password, self.rounds);
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&key(name)) {
+            return Err(CommandError::AlreadyRegistered(name.to_string()));
+        }
+        debug!(name, "Registering.");
+        accounts.insert(key(name), account);
+        if let Err(err) = self.save(&accounts) {
+            accounts.remove(&key(name));
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Change the password of a registered name, given its current one.
+    pub fn change_password(&self, name: &str, old: &str, new: &str) -> Result<(), CommandError> {
+        if !self.is_registered(name) {
+            return Err(CommandError::NotRegistered(name.to_string()));
+        }
+        if !self.check_password(name, old) {
+            return Err(CommandError::IncorrectPassword(name.to_string()));
+        }
+        let account = Account::new(name, new, self.rounds);
+        let mut accounts = self.accounts.lock().unwrap();
+        debug!(name, "Changing password.");
+        let previous = accounts.insert(key(name), account);
+        if let Err(err) = self.save(&accounts) {
+            accounts.extend(previous.map(|previous| (key(name), previous)));
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Write the accounts out, if we have a path. Changes that couldn't be saved are undone
+    /// by whoever made them, so nobody's told a change stuck when a restart would lose it.
+    fn save(&self, accounts: &HashMap<String, Account>) -> Result<(), CommandError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut accounts = accounts.values().collect::<Vec<_>>();
+        accounts.sort_by(|left, right| left.name.cmp(&right.name));
+        serde_json::to_vec_pretty(&accounts)
+            .map_err(io::Error::from)
+            .and_then(|contents| write_atomically(path, &contents))
+            .map_err(|err| {
+                error!(path = %path.display(), "Failed to save accounts: {}", err);
+                CommandError::AccountsNotSaved(err.to_string())
+            })
+    }
+}
+
+/// What an account is kept under, so names that only differ in case share one.
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
@@ -0,0 +1,64 @@
+use budget_chat::chat_log::{ChatEvent, ChatEventKind, ChatLog};
+use clap::Parser;
+use std::{path::PathBuf, time::SystemTime};
+
+/// Print the events in a budget chat log (and its rotated files), oldest first.
+#[derive(Debug, Parser)]
+#[clap(author, version, about)]
+pub struct Args {
+    /// The chat log the server was started with.
+    path: PathBuf,
+
+    /// Only show events at or after this time, e.g. `2023-08-01T12:00:00Z`.
+    #[clap(long, value_parser = humantime::parse_rfc3339_weak)]
+    since: Option<SystemTime>,
+
+    /// Only show events before this time, e.g. `2023-08-01 13:00:00`.
+    #[clap(long, value_parser = humantime::parse_rfc3339_weak)]
+    until: Option<SystemTime>,
+
+    /// Only show events by this member.
+    #[clap(short, long)]
+    member: Option<String>,
+
+    /// Only show events in this room.
+    #[clap(short, long)]
+    room: Option<String>,
+}
+
+impl Args {
+    fn matches(&self, event: &ChatEvent) -> bool {
+        self.since.is_none_or(|since| event.at() >= since)
+            && self.until.is_none_or(|until| event.at() < until)
+            && self
+                .member
+                .as_ref()
+                .is_none_or(|member| &event.member == member)
+            && self.room.as_ref().is_none_or(|room| &event.room == room)
+    }
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    for event in ChatLog::read_all(&args.path)?
+        .iter()
+        .filter(|event| args.matches(event))
+    {
+        let at = humantime::format_rfc3339_millis(event.at());
+        match &event.kind {
+            ChatEventKind::Join => println!(
+                "{} #{} * {} has entered the room",
+                at, event.room, event.member
+            ),
+            ChatEventKind::Leave => println!(
+                "{} #{} * {} has left the room",
+                at, event.room, event.member
+            ),
+            ChatEventKind::Chat { text } => {
+                println!("{} #{} [{}] {}", at, event.room, event.member, text)
+            }
+        }
+    }
+    Ok(())
+}
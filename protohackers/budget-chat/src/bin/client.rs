@@ -1,8 +1,18 @@
+use budget_chat::{
+    client::{stay_connected, Client, ReconnectConfig},
+    tui::{Action, ChatView},
+    CHANNEL_CAPACITY,
+};
 use clap::Parser;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use futures::StreamExt;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
+    io::{AsyncBufReadExt, BufReader, Lines, Stdin},
     select,
+    sync::mpsc,
 };
 use tracing::error;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -13,10 +23,94 @@ pub struct Args {
     server_port: u16,
     #[clap(short = 'u', long, default_value_t = String::from("localhost"))]
     server_url: String,
+    /// What to be called. Asked for on stdin if not given.
+    #[clap(short, long)]
+    name: Option<String>,
+    /// Ask for the password for the name on the terminal, rather than reading it from
+    /// BUDGET_CHAT_PASSWORD. Only registered names need one.
+    #[clap(long)]
+    ask_password: bool,
+    /// The password for the name, right on the command line. Insecure, since anyone
+    /// who can list processes or read the shell history can see it. Prefer BUDGET_CHAT_PASSWORD
+    /// or --ask-password.
+    #[clap(long)]
+    insecure_password: Option<String>,
+    /// Print what the server says line by line, and send whatever's read from stdin,
+    /// instead of taking over the terminal.
+    #[clap(long)]
+    plain: bool,
 }
 
+/// Where the password for the name is read from, unless it's asked for.
+const PASSWORD_VAR: &str = "BUDGET_CHAT_PASSWORD";
+
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let stdin = BufReader::new(tokio::io::stdin());
+    let mut lines_from_stdin = stdin.lines();
+
+    let name = match args.name.clone() {
+        Some(name) => name,
+        None => {
+            eprintln!("What shall I call you?");
+            lines_from_stdin.next_line().await?.unwrap_or_default()
+        }
+    };
+    let password = password(&args, &name).await?;
+    match args.plain {
+        true => plain(args, &name, password, lines_from_stdin).await,
+        false => full_screen(args, &name, password).await,
+    }
+}
+
+/// The password for the name, from wherever we were told to get it, if anywhere.
+async fn password(args: &Args, name: &str) -> std::io::Result<Option<String>> {
+    if let Some(password) = args.insecure_password.clone() {
+        eprintln!("Warning: a password given with --insecure-password can be seen by anyone who can list processes.");
+        return Ok(Some(password));
+    }
+    if !args.ask_password {
+        return Ok(std::env::var(PASSWORD_VAR).ok());
+    }
+    eprint!("Password for {}: ", name);
+    let password = tokio::task::spawn_blocking(read_password).await?;
+    eprintln!();
+    password.map(Some)
+}
+
+/// Read a line from the terminal without echoing it.
+fn read_password() -> std::io::Result<String> {
+    terminal::enable_raw_mode()?;
+    let mut password = String::new();
+    let read = loop {
+        let key = match crossterm::event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(err) => break Err(err),
+        };
+        match key.code {
+            KeyCode::Enter => break Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                break Err(std::io::ErrorKind::Interrupted.into())
+            }
+            KeyCode::Char(c) => password.push(c),
+            KeyCode::Backspace => _ = password.pop(),
+            _ => {}
+        }
+    };
+    terminal::disable_raw_mode()?;
+    read.map(|_| password)
+}
+
+/// Show what the server says as it says it, and send it every line read from stdin.
+async fn plain(
+    args: Args,
+    name: &str,
+    password: Option<String>,
+    mut lines_from_stdin: Lines<BufReader<Stdin>>,
+) -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -24,21 +118,20 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
-    let args = Args::parse();
 
-    let stream = TcpStream::connect((args.server_url, args.server_port)).await?;
-    let (reader, mut writer) = stream.into_split();
-    let reader = BufReader::new(reader);
-    let mut lines_from_server = reader.lines();
-
-    let stdin = BufReader::new(tokio::io::stdin());
-    let mut lines_from_stdin = stdin.lines();
+    let client = Client::connect_with_password(
+        (args.server_url, args.server_port),
+        name,
+        password.as_deref(),
+    )
+    .await?;
+    let (mut events, mut sender) = client.split();
 
     loop {
         select! {
-            line = lines_from_server.next_line() => match line {
-                Ok(Some(line)) => {
-                    println!("{}", line);
+            event = events.next_event() => match event {
+                Ok(Some(event)) => {
+                    println!("{}", event);
                 },
                 Ok(None) => break,
                 Err(err) => {
@@ -48,9 +141,7 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
             },
             line = lines_from_stdin.next_line() => match line {
                 Ok(Some(line)) => {
-                    // eprintln!("Will send: {}", line);
-                    writer.write_all(line.as_bytes()).await?;
-                    writer.write_all(b"\n").await?;
+                    sender.send(&line).await?;
                 },
                 Ok(None) => break,
                 Err(err) => {
@@ -62,3 +153,47 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     Ok(())
 }
+
+/// Take over the terminal, and stay connected under the same name until the member quits.
+/// Nothing is logged here, since it would only scribble over the screen.
+async fn full_screen(
+    args: Args,
+    name: &str,
+    password: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = ReconnectConfig::new(&format!("{}:{}", args.server_url, args.server_port), name)
+        .with_password(password);
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (updates_tx, mut updates) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::spawn(stay_connected(config, outgoing_rx, updates_tx));
+
+    let mut view = ChatView::new(name);
+    let mut keys = EventStream::new();
+    let mut terminal = ratatui::init();
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| view.render(frame)) {
+            break Err(err);
+        }
+        select! {
+            Some(update) = updates.recv() => view.apply(update),
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    match view.handle_key(key) {
+                        Some(Action::Quit) => break Ok(()),
+                        Some(Action::Send(line)) if view.is_connected() => {
+                            view.sent(&line);
+                            _ = outgoing_tx.send(line).await;
+                        }
+                        Some(Action::Send(_)) => view.note("* Not connected, so that wasn't sent"),
+                        None => {}
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => break Err(err),
+                None => break Ok(()),
+            },
+        }
+    };
+    ratatui::restore();
+    Ok(result?)
+}
@@ -0,0 +1,480 @@
+use crate::{registry::RoomRegistry, Shared, CHANNEL_CAPACITY};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Something that sits in a room like a member, without a connection of its own.
+///
+/// Every bot runs in its own task, hearing about whoever enters or leaves its room and
+/// everything said there, one event at a time. A bot that's slow to handle them only
+/// misses some, and never holds up the rooms.
+pub trait Bot: fmt::Debug + Send + 'static {
+    /// The bot just entered its room. Keep the poster around to say things whenever
+    /// there's something to say, not just in reply to someone.
+    fn start(&mut self, _poster: &Poster) {}
+
+    fn on_join(&mut self, _member: &str, _poster: &Poster) {}
+
+    fn on_leave(&mut self, _member: &str, _poster: &Poster) {}
+
+    /// Someone else in the room, member or bot, said something.
+    fn on_message(&mut self, _member: &str, _text: &str, _poster: &Poster) {}
+}
+
+/// Something that happened in a bot's room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotEvent {
+    Join { member: String },
+    Leave { member: String },
+    Message { member: String, text: String },
+}
+
+/// Something a bot said to its room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Post {
+    pub bot: String,
+    pub text: String,
+}
+
+/// How a bot says things to its room.
+#[derive(Debug, Clone)]
+pub struct Poster {
+    bot: String,
+    posts: mpsc::Sender<Post>,
+}
+
+impl Poster {
+    /// What the bot is called.
+    pub fn name(&self) -> &str {
+        &self.bot
+    }
+
+    /// Say something to the bot's room. Returns whether the rooms will hear it, which they
+    /// won't if they're behind on what bots are saying, or gone.
+    pub fn post(&self, text: impl Into<String>) -> bool {
+        let post = Post {
+            bot: self.bot.clone(),
+            text: text.into(),
+        };
+        match self.posts.try_send(post) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!(bot = %self.bot, "The rooms are behind on what bots say. Dropping a post.");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Whether the rooms are gone, so there's no point in saying anything anymore.
+    pub fn is_closed(&self) -> bool {
+        self.posts.is_closed()
+    }
+}
+
+/// A bot as the rooms see it.
+#[derive(Debug)]
+struct BotHandle {
+    room: String,
+    events: mpsc::Sender<BotEvent>,
+}
+
+/// Every bot, the room each of them sits in, and what they're saying.
+#[derive(Debug)]
+pub struct Bots {
+    bots: BTreeMap<String, BotHandle>,
+    posts_tx: mpsc::Sender<Post>,
+    posts: mpsc::Receiver<Post>,
+}
+
+impl Default for Bots {
+    fn default() -> Self {
+        let (posts_tx, posts) = mpsc::channel(CHANNEL_CAPACITY);
+        Self {
+            bots: Default::default(),
+            posts_tx,
+            posts,
+        }
+    }
+}
+
+impl Bots {
+    /// Start a bot in its own task, to sit in the given room under the given name.
+    /// The name should be claimed already, so no member gets it too.
+    pub fn spawn(&mut self, name: &str, room: &str, mut bot: Box<dyn Bot>) {
+        let (events_tx, mut events) = mpsc::channel(CHANNEL_CAPACITY);
+        let poster = Poster {
+            bot: name.to_string(),
+            posts: self.posts_tx.clone(),
+        };
+        info!(bot = %name, room = %room, "Starting bot.");
+        tokio::spawn(async move {
+            bot.start(&poster);
+            while let Some(event) = events.recv().await {
+                match event {
+                    BotEvent::Join { member } => bot.on_join(&member, &poster),
+                    BotEvent::Leave { member } => bot.on_leave(&member, &poster),
+                    BotEvent::Message { member, text } => bot.on_message(&member, &text, &poster),
+                }
+            }
+            debug!(bot = %poster.name(), "Bot stopped.");
+        });
+        self.bots.insert(
+            name.to_string(),
+            BotHandle {
+                room: room.to_string(),
+                events: events_tx,
+            },
+        );
+    }
+
+    /// Every bot's name and the room it sits in, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.bots
+            .iter()
+            .map(|(name, handle)| (name.as_str(), handle.room.as_str()))
+    }
+
+    /// The room a bot sits in, if there is such a bot.
+    pub fn room_of(&self, name: &str) -> Option<&str> {
+        self.bots.get(name).map(|handle| handle.room.as_str())
+    }
+
+    /// Tell every bot in a room what happened there, except whichever bot made it happen.
+    pub fn tell(&self, room_name: &str, event: BotEvent, except: Option<&str>) {
+        self.bots
+            .iter()
+            .filter(|(name, handle)| handle.room == room_name && Some(name.as_str()) != except)
+            .for_each(|(name, handle)| {
+                if handle.events.try_send(event.clone()).is_err() {
+                    warn!(bot = %name, "Bot is behind on what's happening. Dropping an event.");
+                }
+            });
+    }
+
+    /// Wait for the next thing any bot says. Never ends, even without any bots.
+    pub async fn next(&mut self) -> Option<Post> {
+        self.posts.recv().await
+    }
+}
+
+/// How bots are listed alongside members, e.g. `dice (bot)`.
+pub fn marked(name: &str) -> String {
+    format!("{} (bot)", name)
+}
+
+/// Rolls dice for anyone who says `!roll`, or `!roll 2d20` for something other than one six-sided die.
+#[derive(Debug, Default)]
+pub struct Dice;
+
+impl Dice {
+    pub const MAX_DICE: u32 = 20;
+    pub const MAX_SIDES: u32 = 1000;
+
+    /// Parse dice like `2d20`, or `d6` for a single one.
+    fn parse(dice: &str) -> Option<(u32, u32)> {
+        let (count, sides) = dice.split_once('d')?;
+        let count = match count {
+            "" => 1,
+            count => count.parse().ok()?,
+        };
+        let sides = sides.parse().ok()?;
+        ((1..=Self::MAX_DICE).contains(&count) && (2..=Self::MAX_SIDES).contains(&sides))
+            .then_some((count, sides))
+    }
+}
+
+impl Bot for Dice {
+    fn on_message(&mut self, member: &str, text: &str, poster: &Poster) {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("!roll") {
+            return;
+        }
+        let dice = words.next().unwrap_or("1d6");
+        let Some((count, sides)) = Self::parse(dice) else {
+            poster.post(format!(
+                "{}: roll up to {} dice with 2 to {} sides, e.g. !roll 2d6",
+                member,
+                Self::MAX_DICE,
+                Self::MAX_SIDES
+            ));
+            return;
+        };
+        let rolls: Vec<u32> = (0..count).map(|_| rand::random_range(1..=sides)).collect();
+        let total: u32 = rolls.iter().sum();
+        let rolls: Vec<_> = rolls.iter().map(u32::to_string).collect();
+        poster.post(format!(
+            "{} rolled {}d{}: {} ({})",
+            member,
+            count,
+            sides,
+            rolls.join(", "),
+            total
+        ));
+    }
+}
+
+/// Reminds anyone who says `!remind 10m stretch` to stretch, ten minutes later.
+///
+/// Every reminder waits in a task of its own, so there's only so many of them anyone can
+/// have waiting, and only so many the bot keeps track of at once.
+#[derive(Debug)]
+pub struct Reminders {
+    max_per_member: usize,
+    max_pending: usize,
+    /// How many reminders each member has waiting.
+    pending: Shared<HashMap<String, usize>>,
+}
+
+impl Default for Reminders {
+    fn default() -> Self {
+        Self {
+            max_per_member: Self::DEFAULT_MAX_PER_MEMBER,
+            max_pending: Self::DEFAULT_MAX_PENDING,
+            pending: Default::default(),
+        }
+    }
+}
+
+impl Reminders {
+    /// The furthest ahead anyone can be reminded of anything.
+    pub const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+    pub const DEFAULT_MAX_PER_MEMBER: usize = 5;
+    pub const DEFAULT_MAX_PENDING: usize = 1_000;
+
+    /// The most reminders a single member can have waiting.
+    pub fn with_max_per_member(mut self, max_per_member: usize) -> Self {
+        self.max_per_member = max_per_member;
+        self
+    }
+
+    /// The most reminders everyone can have waiting, all together.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Count another reminder as waiting for the member, unless they or everyone
+    /// already have as many as they can. Returns why not, if not.
+    fn reserve(&self, member: &str) -> Result<(), String> {
+        let mut pending = self.pending.lock().unwrap();
+        let waiting = pending.get(member).copied().unwrap_or_default();
+        if waiting >= self.max_per_member {
+            return Err(format!(
+                "{}: you can only have {} reminders waiting at once",
+                member, self.max_per_member
+            ));
+        }
+        if pending.values().sum::<usize>() >= self.max_pending {
+            return Err(format!(
+                "{}: I have too many reminders to keep track of, try again later",
+                member
+            ));
+        }
+        *pending.entry(member.to_string()).or_default() += 1;
+        Ok(())
+    }
+}
+
+impl Bot for Reminders {
+    fn on_message(&mut self, member: &str, text: &str, poster: &Poster) {
+        let Some(request) = text.strip_prefix("!remind") else {
+            return;
+        };
+        let mut words = request.trim().splitn(2, char::is_whitespace);
+        let (Some(delay), Some(reminder)) = (words.next(), words.next()) else {
+            poster.post(format!("{}: try !remind 10m stretch", member));
+            return;
+        };
+        let delay = match humantime::parse_duration(delay) {
+            Ok(delay) if delay <= Self::MAX_DELAY => delay,
+            _ => {
+                poster.post(format!(
+                    "{}: reminders can be up to {} away, like 90s, 10m or 1h30m",
+                    member,
+                    humantime::format_duration(Self::MAX_DELAY)
+                ));
+                return;
+            }
+        };
+        if let Err(refusal) = self.reserve(member) {
+            poster.post(refusal);
+            return;
+        }
+        poster.post(format!(
+            "{}: I'll remind you in {}",
+            member,
+            humantime::format_duration(delay)
+        ));
+        let (poster, pending, member, reminder) = (
+            poster.clone(),
+            self.pending.clone(),
+            member.to_string(),
+            format!("{}: reminder: {}", member, reminder.trim()),
+        );
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            poster.post(reminder);
+            let mut pending = pending.lock().unwrap();
+            if let Some(waiting) = pending.get_mut(&member) {
+                *waiting -= 1;
+                if *waiting == 0 {
+                    pending.remove(&member);
+                }
+            }
+        });
+    }
+}
+
+/// Keeps an eye on a file some build writes its status to, and tells the room whenever
+/// it changes. Anyone can ask for the latest with `!build`.
+#[derive(Debug)]
+pub struct BuildStatus {
+    path: PathBuf,
+    poll_interval: Duration,
+    status: Shared<Option<String>>,
+}
+
+impl BuildStatus {
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            poll_interval: Self::DEFAULT_POLL_INTERVAL,
+            status: Default::default(),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    fn describe(status: &str) -> String {
+        format!("Build status: {}", status)
+    }
+}
+
+impl Bot for BuildStatus {
+    fn start(&mut self, poster: &Poster) {
+        let (path, poll_interval, status, poster) = (
+            self.path.clone(),
+            self.poll_interval,
+            self.status.clone(),
+            poster.clone(),
+        );
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(poll_interval);
+            while !poster.is_closed() {
+                interval.tick().await;
+                // Nothing to report until the build writes something.
+                let Ok(contents) = tokio::fs::read_to_string(&path).await else {
+                    continue;
+                };
+                let Some(latest) = contents
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                else {
+                    continue;
+                };
+                let changed = {
+                    let mut status = status.lock().unwrap();
+                    let changed = status.as_deref() != Some(latest);
+                    *status = Some(latest.to_string());
+                    changed
+                };
+                if changed {
+                    poster.post(Self::describe(latest));
+                }
+            }
+        });
+    }
+
+    fn on_message(&mut self, _member: &str, text: &str, poster: &Poster) {
+        if text.trim() != "!build" {
+            return;
+        }
+        match self.status.lock().unwrap().as_deref() {
+            Some(status) => poster.post(Self::describe(status)),
+            None => poster.post("No build has reported its status yet"),
+        };
+    }
+}
+
+/// One of the bots that come with the server, to start along with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotKind {
+    Dice,
+    Reminders,
+    BuildStatus { path: PathBuf },
+}
+
+/// A bot that comes with the server, what to call it, and which room to sit it in.
+///
+/// Reads like `dice`, `reminders@games` or `builds=/var/run/build-status@ci`: the kind of bot
+/// (which is also its name), whatever it needs to know, and its room, the lobby unless given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotConfig {
+    pub name: String,
+    pub room: String,
+    pub kind: BotKind,
+}
+
+impl BotConfig {
+    pub fn build(&self) -> Box<dyn Bot> {
+        match &self.kind {
+            BotKind::Dice => Box::new(Dice),
+            BotKind::Reminders => Box::new(Reminders::default()),
+            BotKind::BuildStatus { path } => Box::new(BuildStatus::new(path)),
+        }
+    }
+}
+
+impl FromStr for BotConfig {
+    type Err = String;
+
+    fn from_str(bot: &str) -> Result<Self, Self::Err> {
+        let (bot, room) = bot.rsplit_once('@').unwrap_or((bot, RoomRegistry::LOBBY));
+        let (name, argument) = match bot.split_once('=') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (bot, None),
+        };
+        let kind = match (name, argument) {
+            ("dice", None) => BotKind::Dice,
+            ("reminders", None) => BotKind::Reminders,
+            ("builds", Some(path)) if !path.is_empty() => {
+                BotKind::BuildStatus { path: path.into() }
+            }
+            ("builds", _) => {
+                return Err(
+                    "builds needs a file to watch, e.g. builds=/var/run/build-status".to_string(),
+                )
+            }
+            (name, Some(_)) if name == "dice" || name == "reminders" => {
+                return Err(format!("{} doesn't take anything after an =", name))
+            }
+            (name, _) => {
+                return Err(format!(
+                    "There's no {} bot. Try dice, reminders or builds=<file>",
+                    name
+                ))
+            }
+        };
+        if !crate::registry::is_room_name_valid(room) {
+            return Err(format!("Bots can't sit in a room called {}", room));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            room: room.to_string(),
+            kind,
+        })
+    }
+}
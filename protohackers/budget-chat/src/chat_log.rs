@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+/// Something that happened in a room, worth remembering across restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChatEventKind {
+    Join,
+    Leave,
+    Chat { text: String },
+}
+
+/// A single line of the chat log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatEvent {
+    /// Milliseconds since the unix epoch.
+    pub at_ms: u64,
+    pub room: String,
+    pub member: String,
+    #[serde(flatten)]
+    pub kind: ChatEventKind,
+}
+
+impl ChatEvent {
+    pub fn new(room: &str, member: &str, kind: ChatEventKind) -> Self {
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            at_ms,
+            room: room.to_string(),
+            member: member.to_string(),
+            kind,
+        }
+    }
+
+    pub fn at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.at_ms)
+    }
+}
+
+/// An append-only, newline-delimited JSON log of every join, leave and chat message,
+/// rotated once the current file grows past a given size.
+///
+/// The current file lives at `path`, and older ones at `path.1`, `path.2`, ... with
+/// higher numbers being older. Once there are `max_files` rotated files, the oldest is dropped.
+#[derive(Debug)]
+pub struct ChatLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl ChatLog {
+    pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+    pub const DEFAULT_MAX_FILES: usize = 5;
+
+    /// Open (or create) the log at the given path, appending to whatever's already there.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        info!(path = %path.display(), written, "Opened chat log.");
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_bytes: Self::DEFAULT_MAX_BYTES,
+            max_files: Self::DEFAULT_MAX_FILES,
+        })
+    }
+
+    /// Rotate the log once the current file is at least this many bytes.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Keep at most this many rotated files around, besides the current one.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Make sure everything appended so far is on disk, not just handed to the OS.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Append an event to the log, rotating first if the current file is full.
+    /// The event is handed to the OS straight away, but is only sure to be on disk after [ChatLog::sync].
+    pub fn append(&mut self, event: &ChatEvent) -> io::Result<()> {
+        if self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift every rotated file one number up, dropping the oldest, and start a fresh current file.
+    fn rotate(&mut self) -> io::Result<()> {
+        debug!(path = %self.path.display(), "Rotating chat log.");
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.written = 0;
+            return Ok(());
+        }
+        let oldest = rotated_path(&self.path, self.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for index in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Read every event from the log at the given path, and all of its rotated files, oldest first.
+    ///
+    /// Lines that can't be parsed (say, one that was cut short by a crash) are skipped.
+    pub fn read_all(path: impl AsRef<Path>) -> io::Result<Vec<ChatEvent>> {
+        let path = path.as_ref();
+        let mut files: Vec<PathBuf> = (1..)
+            .map(|index| rotated_path(path, index))
+            .take_while(|rotated| rotated.exists())
+            .collect();
+        files.reverse();
+        files.push(path.to_path_buf());
+
+        let mut events = vec![];
+        for file in files.iter().filter(|file| file.exists()) {
+            for (number, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(event) => events.push(event),
+                    Err(err) => warn!(
+                        file = %file.display(),
+                        line = number + 1,
+                        "Skipping unreadable chat log line: {}", err
+                    ),
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Writes events to a [ChatLog] from its own blocking task, so a slow disk (or a rotation)
+/// never holds up whoever's logging. Needs a tokio runtime.
+#[derive(Debug)]
+pub struct ChatLogWriter {
+    path: PathBuf,
+    events: mpsc::Sender<ChatEvent>,
+    writing: JoinHandle<()>,
+}
+
+impl ChatLogWriter {
+    /// How many events can wait to be written before new ones are dropped.
+    pub const CAPACITY: usize = 1024;
+
+    /// Start writing to the given log.
+    pub fn spawn(mut chat_log: ChatLog) -> Self {
+        let path = chat_log.path().to_path_buf();
+        let (events, mut events_rx) = mpsc::channel::<ChatEvent>(Self::CAPACITY);
+        let writing = tokio::task::spawn_blocking(move || {
+            while let Some(event) = events_rx.blocking_recv() {
+                if let Err(err) = chat_log.append(&event) {
+                    error!(path = %chat_log.path().display(), "Failed to write to chat log: {}", err);
+                }
+            }
+            if let Err(err) = chat_log.sync() {
+                error!(path = %chat_log.path().display(), "Failed to sync chat log: {}", err);
+            }
+        });
+        Self {
+            path,
+            events,
+            writing,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hand an event over to be written. If the writer has fallen too far behind,
+    /// the event is dropped rather than making us wait.
+    pub fn write(&self, event: ChatEvent) {
+        if let Err(err) = self.events.try_send(event) {
+            error!(path = %self.path.display(), "Dropped a chat log event: {}", err);
+        }
+    }
+
+    /// Write out everything handed over so far, make sure it's on disk, and stop.
+    pub async fn close(self) {
+        drop(self.events);
+        if let Err(err) = self.writing.await {
+            error!(path = %self.path.display(), "Chat log writer stopped: {}", err);
+        }
+    }
+}
+
+/// Where the rotated file with the given number lives, e.g. `chat.log.2`.
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{}", index));
+    rotated.into()
+}
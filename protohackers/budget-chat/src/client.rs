@@ -0,0 +1,350 @@
+use crate::{room::Room, server::Staging, ClientError};
+use std::{collections::VecDeque, fmt, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    select,
+    sync::mpsc,
+};
+use tracing::{debug, info, trace};
+
+/// Something a budget chat server told us, made sense of.
+///
+/// Only what the protocol itself promises is told apart. Everything else the server says,
+/// like replies to commands, direct messages or replayed history, is [Unknown](ServerEvent::Unknown).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// Whatever the server greeted us with, before we gave it a name.
+    Welcome(String),
+    /// Who else was in the room as we entered it.
+    RoomListing(Vec<String>),
+    /// Somebody entered the room.
+    Join(String),
+    /// Somebody left the room.
+    Leave(String),
+    /// Somebody said something to the room.
+    Message { from: String, text: String },
+    /// Anything else, exactly as the server said it.
+    Unknown(String),
+}
+
+impl ServerEvent {
+    const ROOM_LISTING: &'static str = "* The room contains:";
+
+    /// Make sense of a line from the server, once we're in. The welcome can't be told
+    /// apart from anything else by itself, so that's up to whoever knows it came first.
+    pub fn parse(line: &str) -> Self {
+        if let Some(members) = line.strip_prefix(Self::ROOM_LISTING) {
+            return Self::RoomListing(
+                members
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|member| !member.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            );
+        }
+        let notice = |suffix| {
+            line.strip_prefix("* ")
+                .and_then(|line| line.strip_suffix(suffix))
+                .filter(|name| is_name(name))
+                .map(str::to_string)
+        };
+        if let Some(name) = notice(" has entered the room") {
+            return Self::Join(name);
+        }
+        if let Some(name) = notice(" has left the room") {
+            return Self::Leave(name);
+        }
+        let message = line
+            .strip_prefix('[')
+            .and_then(|line| line.split_once("] "))
+            .filter(|(from, _)| is_name(from));
+        match message {
+            Some((from, text)) => Self::Message {
+                from: from.to_string(),
+                text: text.to_string(),
+            },
+            None => Self::Unknown(line.to_string()),
+        }
+    }
+}
+
+/// Names never have spaces in them, so anything that does is something else.
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(char::is_whitespace)
+}
+
+/// Shows the event the way the server said it.
+impl fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Welcome(line) | Self::Unknown(line) => write!(f, "{}", line),
+            Self::RoomListing(members) => {
+                write!(f, "{} {}", Self::ROOM_LISTING, members.join(", "))
+            }
+            Self::Join(name) => write!(f, "* {} has entered the room", name),
+            Self::Leave(name) => write!(f, "* {} has left the room", name),
+            Self::Message { from, text } => write!(f, "{}", Room::format_message(from, text)),
+        }
+    }
+}
+
+/// A member of a budget chat, connected over TCP and through the name ceremony.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), budget_chat::ClientError> {
+/// use budget_chat::client::{Client, ServerEvent};
+///
+/// let mut client = Client::connect("localhost:12003", "alice").await?;
+/// client.send("hi everyone").await?;
+/// while let Some(event) = client.next_event().await? {
+///     if let ServerEvent::Message { from, text } = event {
+///         println!("{} said {}", from, text);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    events: ClientEvents,
+    sender: ClientSender,
+}
+
+impl Client {
+    /// Connect to a server and take the given name.
+    pub async fn connect(addr: impl ToSocketAddrs, name: &str) -> Result<Self, ClientError> {
+        Self::connect_with_password(addr, name, None).await
+    }
+
+    /// Connect to a server and take the given name, giving the password if the name is
+    /// registered there.
+    ///
+    /// Fails if the server turns the name down for any reason, with whatever it said,
+    /// or as [Closed](ClientError::Closed) if it hangs up without saying, as the protocol allows.
+    pub async fn connect_with_password(
+        addr: impl ToSocketAddrs,
+        name: &str,
+        password: Option<&str>,
+    ) -> Result<Self, ClientError> {
+        let (read_half, write_half) = TcpStream::connect(addr).await?.into_split();
+        let mut events = ClientEvents {
+            lines: BufReader::new(read_half).lines(),
+            pending: Default::default(),
+        };
+        let mut sender = ClientSender { write_half };
+
+        let welcome = events.next_line().await?.ok_or(ClientError::Closed)?;
+        sender.send(name).await?;
+        let mut reply = events.next_line().await?.ok_or(ClientError::Closed)?;
+        if reply == Staging::PASSWORD_PROMPT {
+            let Some(password) = password else {
+                return Err(ClientError::PasswordRequired(name.to_string()));
+            };
+            sender.send(password).await?;
+            reply = events.next_line().await?.ok_or(ClientError::Closed)?;
+        }
+        let listing @ ServerEvent::RoomListing(_) = ServerEvent::parse(&reply) else {
+            return Err(ClientError::Rejected(reply));
+        };
+        debug!(name, "Made it into the chat.");
+        events
+            .pending
+            .extend([ServerEvent::Welcome(welcome), listing]);
+        Ok(Self { events, sender })
+    }
+
+    /// The next thing the server says, or nothing once it hangs up. The welcome and the
+    /// room listing from the name ceremony come first, so nothing the server said is missed.
+    pub async fn next_event(&mut self) -> Result<Option<ServerEvent>, ClientError> {
+        self.events.next_event().await
+    }
+
+    /// Say something to the room, or use a command if the server has them.
+    pub async fn send(&mut self, line: &str) -> Result<(), ClientError> {
+        self.sender.send(line).await
+    }
+
+    /// Split the client in two, to wait on the server and send to it from different places.
+    pub fn split(self) -> (ClientEvents, ClientSender) {
+        (self.events, self.sender)
+    }
+}
+
+/// Everything the server says to a [Client].
+#[derive(Debug)]
+pub struct ClientEvents {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    /// What the server said during the name ceremony, still to be handed out.
+    pending: VecDeque<ServerEvent>,
+}
+
+impl ClientEvents {
+    /// See [Client::next_event].
+    pub async fn next_event(&mut self) -> Result<Option<ServerEvent>, ClientError> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+        Ok(self
+            .next_line()
+            .await?
+            .map(|line| ServerEvent::parse(&line)))
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>, ClientError> {
+        let line = self.lines.next_line().await?;
+        trace!(?line, "Line from server.");
+        Ok(line)
+    }
+}
+
+/// How a [Client] talks to the server.
+#[derive(Debug)]
+pub struct ClientSender {
+    write_half: OwnedWriteHalf,
+}
+
+impl ClientSender {
+    /// Send a line to the server. Anything after a newline would be a line of its own,
+    /// so each of those is sent separately.
+    pub async fn send(&mut self, line: &str) -> Result<(), ClientError> {
+        for line in line.lines() {
+            self.write_half
+                .write_all(format!("{}\n", line).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Let the server know we're done, without waiting for it to hang up.
+    pub async fn quit(mut self) -> Result<(), ClientError> {
+        Ok(self.write_half.shutdown().await?)
+    }
+}
+
+/// Where a client that [stays connected](stay_connected) connects to, and as whom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    pub addr: String,
+    pub name: String,
+    pub password: Option<String>,
+    /// How long to wait after the first time the connection drops or fails to come up.
+    /// Every failure in a row after that waits twice as long, up to the maximum.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectConfig {
+    pub const DEFAULT_MIN_DELAY: Duration = Duration::from_secs(1);
+    pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+    pub fn new(addr: &str, name: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            name: name.to_string(),
+            password: None,
+            min_delay: Self::DEFAULT_MIN_DELAY,
+            max_delay: Self::DEFAULT_MAX_DELAY,
+        }
+    }
+
+    pub fn with_password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+
+    pub fn with_delays(mut self, min_delay: Duration, max_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+/// How a client that [stays connected](stay_connected) is getting on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionUpdate {
+    /// We made it through the name ceremony. Events from this connection follow.
+    Connected,
+    Event(ServerEvent),
+    /// We lost the connection, or couldn't get one, for the given reason.
+    Disconnected(String),
+    /// We'll try again after the given delay.
+    Reconnecting(Duration),
+}
+
+/// Stay connected to a server under the same name, connecting again whenever the connection
+/// drops, and passing along every line to send and everything that happens.
+///
+/// Lines to send while disconnected are dropped rather than sent to a connection they weren't
+/// meant for. Returns once there's nothing left to send lines from, or nobody to tell.
+pub async fn stay_connected(
+    config: ReconnectConfig,
+    mut outgoing: mpsc::Receiver<String>,
+    updates: mpsc::Sender<ConnectionUpdate>,
+) {
+    let mut delay = config.min_delay;
+    loop {
+        let connected = Client::connect_with_password(
+            config.addr.as_str(),
+            &config.name,
+            config.password.as_deref(),
+        )
+        .await;
+        let reason = match connected {
+            Ok(client) => {
+                info!(addr = %config.addr, name = %config.name, "Connected.");
+                delay = config.min_delay;
+                if updates.send(ConnectionUpdate::Connected).await.is_err() {
+                    return;
+                }
+                let (mut events, mut sender) = client.split();
+                loop {
+                    select! {
+                        event = events.next_event() => match event {
+                            Ok(Some(event)) => {
+                                if updates.send(ConnectionUpdate::Event(event)).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(None) => break ClientError::Closed.to_string(),
+                            Err(err) => break err.to_string(),
+                        },
+                        line = outgoing.recv() => match line {
+                            Some(line) => {
+                                if let Err(err) = sender.send(&line).await {
+                                    break err.to_string();
+                                }
+                            }
+                            None => return,
+                        },
+                    }
+                }
+            }
+            Err(err) => err.to_string(),
+        };
+        debug!(%reason, ?delay, "Disconnected. Reconnecting...");
+        let told = updates
+            .send(ConnectionUpdate::Disconnected(reason))
+            .await
+            .and(updates.send(ConnectionUpdate::Reconnecting(delay)).await);
+        if told.is_err() {
+            return;
+        }
+        let wait = tokio::time::sleep(delay);
+        tokio::pin!(wait);
+        loop {
+            select! {
+                _ = &mut wait => break,
+                line = outgoing.recv() => match line {
+                    Some(line) => debug!(%line, "Not connected. Dropping line."),
+                    None => return,
+                },
+            }
+        }
+        delay = (delay * 2).min(config.max_delay);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{CommandError, MemberID};
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+/// What a line a member sent turns out to be.
+///
+/// Lines starting with a `/` and a letter are commands. Anything else is chat, including
+/// lines starting with `//`, which say whatever follows the first `/` to the room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    /// Something to say to the room, with any escaping undone.
+    Chat(&'a str),
+    /// `/<name> [arguments]`
+    Command { name: &'a str, arguments: &'a str },
+}
+
+impl<'a> Line<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        let trimmed = line.trim_start();
+        if let Some(escaped) = trimmed
+            .strip_prefix('/')
+            .filter(|escaped| escaped.starts_with('/'))
+        {
+            return Self::Chat(escaped);
+        }
+        let Some(command) = trimmed
+            .strip_prefix('/')
+            .filter(|command| command.starts_with(|char: char| char.is_ascii_alphabetic()))
+        else {
+            return Self::Chat(line);
+        };
+        let (name, arguments) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        Self::Command {
+            name,
+            arguments: arguments.trim(),
+        }
+    }
+
+    /// Make sure a line is said to the room as is, even if it looks like a command.
+    pub fn escape(line: &str) -> String {
+        let trimmed = line.trim_start();
+        match trimmed.starts_with('/') {
+            true => format!("/{}", trimmed),
+            false => line.to_string(),
+        }
+    }
+}
+
+/// Whatever followed a command's name, along with how the command should be used,
+/// for turning down anything that doesn't fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arguments<'a> {
+    raw: &'a str,
+    usage: &'static str,
+}
+
+impl<'a> Arguments<'a> {
+    pub fn new(raw: &'a str, usage: &'static str) -> Self {
+        Self {
+            raw: raw.trim(),
+            usage,
+        }
+    }
+
+    fn usage(&self) -> CommandError {
+        CommandError::Usage(self.usage)
+    }
+
+    /// Everything after the command's name, as the member typed it.
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+
+    /// Everything after the command's name, unless there's nothing.
+    pub fn rest(&self) -> Option<&'a str> {
+        (!self.raw.is_empty()).then_some(self.raw)
+    }
+
+    pub fn none(&self) -> Result<(), CommandError> {
+        match self.raw.is_empty() {
+            true => Ok(()),
+            false => Err(self.usage()),
+        }
+    }
+
+    pub fn one(&self) -> Result<&'a str, CommandError> {
+        let mut words = self.raw.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(argument), None) => Ok(argument),
+            _ => Err(self.usage()),
+        }
+    }
+
+    pub fn two(&self) -> Result<(&'a str, &'a str), CommandError> {
+        let mut words = self.raw.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some(first), Some(second), None) => Ok((first, second)),
+            _ => Err(self.usage()),
+        }
+    }
+
+    /// Split `<argument> <text>` into the argument and the text, keeping the
+    /// text as the member typed it.
+    pub fn one_and_text(&self) -> Result<(&'a str, &'a str), CommandError> {
+        match self.raw.split_once(char::is_whitespace) {
+            Some((argument, text)) if !text.trim().is_empty() => Ok((argument, text.trim())),
+            _ => Err(self.usage()),
+        }
+    }
+
+    /// Parse `<argument> [duration]`, where the duration reads like `90s`, `10m` or `1h 30m`.
+    pub fn one_and_duration(&self) -> Result<(&'a str, Option<Duration>), CommandError> {
+        let mut words = self.raw.split_whitespace();
+        let Some(argument) = words.next() else {
+            return Err(self.usage());
+        };
+        let duration = words.collect::<Vec<_>>().join(" ");
+        if duration.is_empty() {
+            return Ok((argument, None));
+        }
+        match humantime::parse_duration(&duration) {
+            Ok(duration) => Ok((argument, Some(duration))),
+            Err(_) => Err(CommandError::InvalidDuration(duration)),
+        }
+    }
+}
+
+/// A command a member used: who used it, from which room, and with what arguments.
+#[derive(Debug, Clone, Copy)]
+pub struct Invocation<'a> {
+    pub sender: MemberID,
+    pub room: &'a str,
+    pub arguments: Arguments<'a>,
+}
+
+/// What runs a command, given whatever the commands act on.
+pub type Handler<C> = fn(&mut C, Invocation<'_>) -> Result<(), CommandError>;
+
+/// A command members can use, and what handles it.
+pub struct CommandSpec<C> {
+    /// What members type after the `/`.
+    pub name: &'static str,
+    /// How to use the command, e.g. `/join <room>`.
+    pub usage: &'static str,
+    /// What the command does, in a few words.
+    pub summary: &'static str,
+    /// Only operators can use the command, or even hear about it.
+    pub operators_only: bool,
+    pub handler: Handler<C>,
+}
+
+impl<C> Clone for CommandSpec<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C> Copy for CommandSpec<C> {}
+
+impl<C> fmt::Debug for CommandSpec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandSpec")
+            .field("name", &self.name)
+            .field("usage", &self.usage)
+            .field("operators_only", &self.operators_only)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C> CommandSpec<C> {
+    pub fn new(
+        name: &'static str,
+        usage: &'static str,
+        summary: &'static str,
+        handler: Handler<C>,
+    ) -> Self {
+        Self {
+            name,
+            usage,
+            summary,
+            operators_only: false,
+            handler,
+        }
+    }
+
+    pub fn operators_only(mut self) -> Self {
+        self.operators_only = true;
+        self
+    }
+
+    /// How the command is listed in `/help`, e.g. `/join <room>: leave this room for another`.
+    pub fn help(&self) -> String {
+        format!("{}: {}", self.usage, self.summary)
+    }
+}
+
+/// Every command members can use, by name.
+#[derive(Debug)]
+pub struct Commands<C> {
+    specs: BTreeMap<&'static str, CommandSpec<C>>,
+}
+
+impl<C> Default for Commands<C> {
+    fn default() -> Self {
+        Self {
+            specs: Default::default(),
+        }
+    }
+}
+
+impl<C> Commands<C> {
+    /// Let members use a command, replacing any other command with the same name.
+    pub fn with(mut self, spec: CommandSpec<C>) -> Self {
+        self.specs.insert(spec.name, spec);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CommandSpec<C>> {
+        self.specs.get(name)
+    }
+
+    /// Every command, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = &CommandSpec<C>> {
+        self.specs.values()
+    }
+}
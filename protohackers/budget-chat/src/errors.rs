@@ -8,6 +8,14 @@ pub enum BudgetChatError {
     Initialization(#[from] ClientInitializationError),
     #[error(transparent)]
     Reunite(#[from] tokio::net::tcp::ReuniteError),
+    #[error(transparent)]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Federation(#[from] FederationError),
+    #[error("The chat rooms are gone")]
+    RoomsGone,
+    #[error("The chat rooms stopped: {0}")]
+    RoomsStopped(#[from] tokio::task::JoinError),
     #[error("Member (id: {0}) is not known")]
     UnknownMember(String),
     #[error("No messages to read...")]
@@ -17,10 +25,98 @@ pub enum BudgetChatError {
 /// Any error at initialization warrants a disconnection.
 #[derive(Debug, Error)]
 pub enum ClientInitializationError {
-    #[error("Client provided bad name: {0}")]
+    #[error("Names must be made of only letters and digits: {0}")]
     InvalidName(String),
+    #[error("Names can be at most {max} characters long: {name}")]
+    NameTooLong { name: String, max: usize },
+    #[error("Somebody is already called {0}")]
+    NameTaken(String),
+    #[error("Nobody can be called {0}")]
+    NameReserved(String),
+    #[error("You are banned from this server: {0}")]
+    Banned(String),
+    #[error("Only registered names are allowed here, and nobody registered {0}")]
+    GuestsNotAllowed(String),
+    #[error("That's not the password for {0}")]
+    WrongPassword(String),
+    #[error("Too many wrong passwords")]
+    TooManyWrongPasswords,
+    #[error("Took too long to give the password for {0}")]
+    PasswordTimeout(String),
     #[error("Connection was reset by the client...")]
     ConnectionResetByClient,
 }
 
+/// Anything that went wrong with a command a member issued. These are
+/// reported back to the member, and never warrant a disconnection.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+    #[error("Room names must be 1 to {max} letters, digits, dashes or underscores: {name}")]
+    InvalidRoomName { name: String, max: usize },
+    #[error("You are already in {0}")]
+    AlreadyInRoom(String),
+    #[error("Nobody called {0} is here")]
+    NoSuchMember(String),
+    #[error("Durations look like 90s, 10m or 1h 30m, not: {0}")]
+    InvalidDuration(String),
+    #[error("Only operators can do that")]
+    NotOperator,
+    #[error("That's not the operator password for {0}")]
+    WrongPassword(String),
+    #[error("{0} isn't banned")]
+    NotBanned(String),
+    #[error("{0} isn't muted")]
+    NotMuted(String),
+    #[error("You are muted")]
+    Muted,
+    #[error("Accounts aren't enabled on this server")]
+    AccountsDisabled,
+    #[error("{0} is already registered")]
+    AlreadyRegistered(String),
+    #[error("{0} isn't registered")]
+    NotRegistered(String),
+    #[error("That's not the password for {0}")]
+    IncorrectPassword(String),
+    #[error("Couldn't save accounts, so nothing changed: {0}")]
+    AccountsNotSaved(String),
+    #[error("You aren't away")]
+    NotAway,
+    #[error("There's no /{0} command. Try /help")]
+    UnknownCommand(String),
+}
+
 pub type Result<T, E = BudgetChatError> = core::result::Result<T, E>;
+
+/// Anything that went wrong with a link to another server. These only ever take down the link.
+#[derive(Debug, Error)]
+pub enum FederationError {
+    #[error("Linked server sent something it shouldn't have")]
+    UnexpectedFrame,
+    #[error("Linked server {0} doesn't know the secret")]
+    WrongSecret(String),
+    #[error("Linked server is called {0}, same as us")]
+    SameName(String),
+    #[error("Linked server took too long with the handshake")]
+    Timeout,
+    #[error("Linked server hung up")]
+    Closed,
+    #[error("Linked server sent a frame longer than we'll read")]
+    FrameTooLong,
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Anything that went wrong for a [Client](crate::client::Client) talking to a server.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error("The server turned us away: {0}")]
+    Rejected(String),
+    #[error("The server wants a password for {0}")]
+    PasswordRequired(String),
+    #[error("The server hung up")]
+    Closed,
+}
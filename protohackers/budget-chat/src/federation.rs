@@ -0,0 +1,635 @@
+use crate::{
+    names::{self, ClaimedNames},
+    FederationError, CHANNEL_CAPACITY,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    select,
+    sync::mpsc,
+    time::timeout,
+};
+use tracing::{debug, error, info, trace, warn};
+
+/// Tells the links between this server and others apart, for as long as this server runs.
+pub type LinkID = u64;
+
+/// How this server links up with others, so that rooms with the same name on every one of
+/// them are really the same room.
+///
+/// Links should form a tree. Every event carries an id so that none is ever applied twice
+/// even if they don't, but a member who can be reached in more than one way is still
+/// dropped as soon as the way we first heard of them goes away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationConfig {
+    /// What this server is called to the others. Every linked server needs a name of its own.
+    pub server_name: String,
+    /// What every linked server has to prove it knows before we believe anything it says.
+    pub secret: String,
+    /// Servers to link to, and keep linking to whenever the link drops.
+    pub peers: Vec<SocketAddr>,
+    /// How long to wait before trying a peer again after a link drops or fails to come up.
+    pub reconnect_delay: Duration,
+}
+
+impl FederationConfig {
+    pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    /// How long a peer has to get through the handshake.
+    pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The longest frame a peer can send, newline included, so nobody can make us
+    /// buffer a line forever, whether or not they know the secret.
+    pub const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+    pub fn new(server_name: &str, secret: &str) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            secret: secret.to_string(),
+            peers: vec![],
+            reconnect_delay: Self::DEFAULT_RECONNECT_DELAY,
+        }
+    }
+
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    pub fn with_reconnect_delay(mut self, reconnect_delay: Duration) -> Self {
+        self.reconnect_delay = reconnect_delay;
+        self
+    }
+}
+
+/// Something that happened to a member on one of the linked servers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// The server that first sent this event, which run of it, and how many events it
+    /// had sent before. Together they tell every event apart, even across restarts.
+    pub origin: String,
+    pub run: u64,
+    pub seq: u64,
+    /// The server the member is connected to, which isn't always where the event comes from.
+    pub server: String,
+    /// The member's name on their own server.
+    pub name: String,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The member is now in this room, and out of whichever one they were in before.
+    Join { room: String },
+    /// The member left their server.
+    Quit,
+    /// The member said something in this room.
+    Chat { room: String, text: String },
+}
+
+/// Everything that goes over a link, one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    /// Who we are, and something for the peer to prove it knows the secret with.
+    Hello {
+        server: String,
+        nonce: String,
+    },
+    /// The proof that we know the secret, for the nonce the peer sent us.
+    Auth {
+        proof: String,
+    },
+    Event {
+        event: Event,
+    },
+}
+
+/// Prove that the given server knows the secret, for the nonce the other side picked.
+pub fn prove(secret: &str, nonce: &str, server: &str) -> String {
+    mac(secret, nonce, server)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Check that the given server really knows the secret, without leaking how close it got.
+pub fn verify(secret: &str, nonce: &str, server: &str, proof: &str) -> bool {
+    let Some(proof) = decode_hex(proof) else {
+        return false;
+    };
+    mac(secret, nonce, server).verify_slice(&proof).is_ok()
+}
+
+fn mac(secret: &str, nonce: &str, server: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(nonce.as_bytes());
+    mac.update(b":");
+    mac.update(server.as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+/// What the links tell the [RoomRegistry](crate::registry::RoomRegistry).
+#[derive(Debug)]
+pub enum LinkEvent {
+    /// A peer made it through the handshake, and wants events sent to it here.
+    Up {
+        link: LinkID,
+        server: String,
+        events: mpsc::Sender<Event>,
+    },
+    Received {
+        link: LinkID,
+        event: Event,
+    },
+    /// The link is gone, and everyone we heard of through it along with it.
+    Down {
+        link: LinkID,
+    },
+}
+
+/// A member on another server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RemoteMember {
+    pub server: String,
+    pub name: String,
+}
+
+impl From<&Event> for RemoteMember {
+    fn from(event: &Event) -> Self {
+        Self {
+            server: event.server.clone(),
+            name: event.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RemoteState {
+    /// The link we heard of them through.
+    via: LinkID,
+    room: String,
+    /// What they're called here: their own name, unless somebody here already had it.
+    display_name: String,
+    /// Whether we claimed their name for them, and should give it back once they're gone.
+    claimed: bool,
+}
+
+#[derive(Debug)]
+struct Link {
+    server: String,
+    events: mpsc::Sender<Event>,
+}
+
+/// Ids of the most recent events we've seen, so none is ever applied or passed on twice.
+#[derive(Debug, Default)]
+struct Seen {
+    order: VecDeque<(String, u64, u64)>,
+    ids: HashSet<(String, u64, u64)>,
+}
+
+impl Seen {
+    const CAPACITY: usize = 4096;
+
+    /// Remember an event. Returns whether it's the first time we've seen it.
+    fn insert(&mut self, event: &Event) -> bool {
+        let id = (event.origin.clone(), event.run, event.seq);
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The state of this server's links, kept by the [RoomRegistry](crate::registry::RoomRegistry):
+/// who we're linked with, which members are on other servers, and which events we've seen.
+#[derive(Debug)]
+pub struct Federation {
+    server_name: String,
+    run: u64,
+    next_seq: u64,
+    link_events: mpsc::Receiver<LinkEvent>,
+    links: HashMap<LinkID, Link>,
+    seen: Seen,
+    remote_members: HashMap<RemoteMember, RemoteState>,
+    /// Names of members on this server, so members on other servers don't take them.
+    claimed: ClaimedNames,
+}
+
+impl Federation {
+    pub fn new(
+        server_name: &str,
+        link_events: mpsc::Receiver<LinkEvent>,
+        claimed: ClaimedNames,
+    ) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            run: rand::random(),
+            next_seq: 0,
+            link_events,
+            links: Default::default(),
+            seen: Default::default(),
+            remote_members: Default::default(),
+            claimed,
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Wait for the next thing to happen on any link.
+    pub async fn next(&mut self) -> Option<LinkEvent> {
+        self.link_events.recv().await
+    }
+
+    /// Start sending events to a peer that made it through the handshake, unless we're
+    /// already linked with it. Returns whether we are now.
+    pub fn link_up(&mut self, link: LinkID, server: &str, events: mpsc::Sender<Event>) -> bool {
+        if self
+            .links
+            .values()
+            .any(|existing| existing.server == server)
+        {
+            warn!(server, "Already linked with server. Dropping the new link.");
+            return false;
+        }
+        info!(server, link, "Linked with server.");
+        self.links.insert(
+            link,
+            Link {
+                server: server.to_string(),
+                events,
+            },
+        );
+        true
+    }
+
+    /// Forget a link that went away. Returns the server it was to, if it was ever up.
+    pub fn link_down(&mut self, link: LinkID) -> Option<String> {
+        let server = self.links.remove(&link)?.server;
+        warn!(server, link, "Lost the link to server.");
+        Some(server)
+    }
+
+    /// Make a new event about a member, as this server.
+    pub fn event(&mut self, server: &str, name: &str, kind: EventKind) -> Event {
+        self.next_seq += 1;
+        let event = Event {
+            origin: self.server_name.clone(),
+            run: self.run,
+            seq: self.next_seq,
+            server: server.to_string(),
+            name: name.to_string(),
+            kind,
+        };
+        // Links between more than two servers can bring our own events back to us.
+        self.seen.insert(&event);
+        event
+    }
+
+    /// Tell every linked server about something that happened to a member here.
+    pub fn publish(&mut self, name: &str, kind: EventKind) {
+        let server = self.server_name.clone();
+        let event = self.event(&server, name, kind);
+        self.forward(None, &event);
+    }
+
+    /// Pass an event along to every link except the one it came from.
+    pub fn forward(&mut self, from: Option<LinkID>, event: &Event) {
+        let links: Vec<_> = self
+            .links
+            .keys()
+            .filter(|&&link| Some(link) != from)
+            .cloned()
+            .collect();
+        for link in links {
+            self.send_to(link, event.clone());
+        }
+    }
+
+    /// Send an event over a single link. A link that can't keep up is dropped, which the
+    /// link notices once it has nothing left to send, and reports as gone.
+    pub fn send_to(&mut self, link: LinkID, event: Event) {
+        let Some(existing) = self.links.get(&link) else {
+            return;
+        };
+        if let Err(err) = existing.events.try_send(event) {
+            error!(
+                server = existing.server,
+                "Can't send to linked server. Dropping the link: {}", err
+            );
+            self.links.remove(&link);
+        }
+    }
+
+    /// Check whether an event is news to us, remembering it if it is.
+    pub fn is_new(&mut self, event: &Event) -> bool {
+        self.seen.insert(event)
+    }
+
+    /// Put a remote member in a room, working out what to call them here if they're new.
+    /// Returns what they're called, and the room they were in before, if any.
+    pub fn join(
+        &mut self,
+        member: RemoteMember,
+        via: LinkID,
+        room: &str,
+    ) -> (String, Option<String>) {
+        if let Some(state) = self.remote_members.get_mut(&member) {
+            let previous = std::mem::replace(&mut state.room, room.to_string());
+            return (state.display_name.clone(), Some(previous));
+        }
+        // Members here keep their names, and whoever shows up second on another server gets
+        // theirs qualified with the server, which no name here can ever look like.
+        let (display_name, claimed) = match self.claimed.claim(&member.name) {
+            Ok(()) => (member.name.clone(), true),
+            Err(_) => (names::qualified(&member.name, &member.server), false),
+        };
+        debug!(
+            server = member.server,
+            name = member.name,
+            display_name,
+            "Remote member joined."
+        );
+        self.remote_members.insert(
+            member,
+            RemoteState {
+                via,
+                room: room.to_string(),
+                display_name: display_name.clone(),
+                claimed,
+            },
+        );
+        (display_name, None)
+    }
+
+    /// Forget a remote member. Returns what they were called here, and the room they were in.
+    pub fn quit(&mut self, member: &RemoteMember) -> Option<(String, String)> {
+        let state = self.remote_members.remove(member)?;
+        if state.claimed {
+            self.claimed.release(&state.display_name);
+        }
+        Some((state.display_name, state.room))
+    }
+
+    /// What a remote member is called here, and the room they're in, if we know of them.
+    pub fn find(&self, member: &RemoteMember) -> Option<(&str, &str)> {
+        self.remote_members
+            .get(member)
+            .map(|state| (state.display_name.as_str(), state.room.as_str()))
+    }
+
+    /// Every remote member we heard of through the given link.
+    pub fn members_via(&self, link: LinkID) -> Vec<RemoteMember> {
+        self.remote_members
+            .iter()
+            .filter(|(_, state)| state.via == link)
+            .map(|(member, _)| member.clone())
+            .collect()
+    }
+
+    /// Every remote member we heard of through any link but the given one, and the room they're in.
+    pub fn members_not_via(&self, link: LinkID) -> Vec<(RemoteMember, String)> {
+        self.remote_members
+            .iter()
+            .filter(|(_, state)| state.via != link)
+            .map(|(member, state)| (member.clone(), state.room.clone()))
+            .collect()
+    }
+}
+
+/// Hands out a [LinkID] to every link, whichever side started it.
+#[derive(Debug, Clone, Default)]
+pub struct LinkIDs(Arc<AtomicU64>);
+
+impl LinkIDs {
+    fn next(&self) -> LinkID {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Accept links from other servers.
+pub async fn listen(
+    listener: TcpListener,
+    config: Arc<FederationConfig>,
+    link_events: mpsc::Sender<LinkEvent>,
+    link_ids: LinkIDs,
+) -> crate::Result<()> {
+    info!("Listening for linked servers on {}", listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!(peer = %addr, "Accepted link");
+        let config = config.clone();
+        let link_events = link_events.clone();
+        let link = link_ids.next();
+        tokio::task::spawn(async move {
+            if let Err(err) = run_link(stream, link, &config, link_events).await {
+                warn!(peer = %addr, "Link failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Link to a peer, and link to it again whenever the link drops, for as long as we run.
+pub async fn connect(
+    peer: SocketAddr,
+    config: Arc<FederationConfig>,
+    link_events: mpsc::Sender<LinkEvent>,
+    link_ids: LinkIDs,
+) {
+    loop {
+        match TcpStream::connect(peer).await {
+            Ok(stream) => {
+                let link = link_ids.next();
+                if let Err(err) = run_link(stream, link, &config, link_events.clone()).await {
+                    warn!(%peer, "Link failed: {}", err);
+                }
+            }
+            Err(err) => debug!(%peer, "Couldn't reach peer: {}", err),
+        }
+        if link_events.is_closed() {
+            return;
+        }
+        tokio::time::sleep(config.reconnect_delay).await;
+    }
+}
+
+async fn write_frame(write_half: &mut OwnedWriteHalf, frame: &Frame) -> crate::Result<()> {
+    let mut line = serde_json::to_string(frame).map_err(FederationError::from)?;
+    line.push('\n');
+    write_half.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// Reads frames off a link a line at a time, never holding on to more than
+/// [FederationConfig::MAX_FRAME_BYTES] of one.
+struct FrameReader {
+    reader: BufReader<OwnedReadHalf>,
+    /// Whatever we have of the next frame so far. Kept here, so nothing's lost when
+    /// a read is cancelled halfway through a frame.
+    line: Vec<u8>,
+}
+
+impl FrameReader {
+    fn new(read_half: OwnedReadHalf) -> Self {
+        Self {
+            reader: BufReader::new(read_half),
+            line: vec![],
+        }
+    }
+
+    async fn read_frame(&mut self) -> crate::Result<Frame> {
+        let remaining = FederationConfig::MAX_FRAME_BYTES.saturating_sub(self.line.len());
+        let bytes = (&mut self.reader)
+            .take(remaining as u64)
+            .read_until(b'\n', &mut self.line)
+            .await?;
+        if self.line.last() != Some(&b'\n') {
+            return Err(match bytes {
+                0 if remaining > 0 => FederationError::Closed,
+                _ => FederationError::FrameTooLong,
+            }
+            .into());
+        }
+        let line = std::mem::take(&mut self.line);
+        Ok(serde_json::from_slice(&line).map_err(FederationError::from)?)
+    }
+}
+
+/// Make sure the peer knows the secret, and find out what it's called. Both sides
+/// do exactly the same thing, so it doesn't matter who connected to whom.
+async fn handshake(
+    frames: &mut FrameReader,
+    write_half: &mut OwnedWriteHalf,
+    config: &FederationConfig,
+) -> crate::Result<String> {
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    write_frame(
+        write_half,
+        &Frame::Hello {
+            server: config.server_name.clone(),
+            nonce: nonce.clone(),
+        },
+    )
+    .await?;
+
+    let Frame::Hello {
+        server,
+        nonce: peer_nonce,
+    } = frames.read_frame().await?
+    else {
+        return Err(FederationError::UnexpectedFrame.into());
+    };
+    if server == config.server_name {
+        return Err(FederationError::SameName(server).into());
+    }
+
+    let proof = prove(&config.secret, &peer_nonce, &config.server_name);
+    write_frame(write_half, &Frame::Auth { proof }).await?;
+
+    let Frame::Auth { proof } = frames.read_frame().await? else {
+        return Err(FederationError::UnexpectedFrame.into());
+    };
+    if !verify(&config.secret, &nonce, &server, &proof) {
+        return Err(FederationError::WrongSecret(server).into());
+    }
+    Ok(server)
+}
+
+/// Go through the handshake with a peer, then pass events back and forth between it and
+/// the chat rooms until either side hangs up.
+#[tracing::instrument(skip_all, fields(link, server))]
+async fn run_link(
+    stream: TcpStream,
+    link: LinkID,
+    config: &FederationConfig,
+    link_events: mpsc::Sender<LinkEvent>,
+) -> crate::Result<()> {
+    stream.set_nodelay(true)?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut frames = FrameReader::new(read_half);
+
+    let server = timeout(
+        FederationConfig::HANDSHAKE_TIMEOUT,
+        handshake(&mut frames, &mut write_half, config),
+    )
+    .await
+    .map_err(|_| FederationError::Timeout)??;
+    tracing::Span::current().record("server", &server);
+    info!("Handshake complete.");
+
+    let (events_tx, mut events_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    if link_events
+        .send(LinkEvent::Up {
+            link,
+            server,
+            events: events_tx,
+        })
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+
+    let result = async {
+        loop {
+            select! {
+                event = events_rx.recv() => match event {
+                    Some(event) => write_frame(&mut write_half, &Frame::Event { event }).await?,
+                    None => {
+                        trace!("The chat rooms dropped this link.");
+                        return Ok(());
+                    }
+                },
+                frame = frames.read_frame() => match frame? {
+                    Frame::Event { event } => {
+                        trace!(?event, "Received event.");
+                        if link_events.send(LinkEvent::Received { link, event }).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    _ => return Err(FederationError::UnexpectedFrame.into()),
+                },
+            }
+        }
+    }
+    .await;
+    _ = link_events.send(LinkEvent::Down { link }).await;
+    result
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+/// How much a single member is allowed to say, and what happens when they say too much.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodPolicy {
+    /// How many lines a member can send back to back before being slowed down.
+    pub burst: u32,
+    /// How many lines per second a member can keep sending once they've used up their burst.
+    pub rate: f64,
+    /// How many lines over the limit we'll warn about before muting the member.
+    pub mute_after: u32,
+    /// How long a mute lasts.
+    pub mute_for: Duration,
+    /// How many mutes it takes before we give up and disconnect the member.
+    pub disconnect_after: u32,
+}
+
+impl Default for FloodPolicy {
+    fn default() -> Self {
+        Self {
+            burst: 20,
+            rate: 5.0,
+            mute_after: 5,
+            mute_for: Duration::from_secs(30),
+            disconnect_after: 3,
+        }
+    }
+}
+
+impl FloodPolicy {
+    /// At least one line always gets through, since a burst of none would silence everyone.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn with_rate(mut self, rate: f64) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Anything below one counts as one.
+    pub fn with_mute_after(mut self, mute_after: u32) -> Self {
+        self.mute_after = mute_after.max(1);
+        self
+    }
+
+    pub fn with_mute_for(mut self, mute_for: Duration) -> Self {
+        self.mute_for = mute_for;
+        self
+    }
+
+    /// Anything below one counts as one.
+    pub fn with_disconnect_after(mut self, disconnect_after: u32) -> Self {
+        self.disconnect_after = disconnect_after.max(1);
+        self
+    }
+}
+
+/// What to do with a line a member just sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let it through.
+    Allow,
+    /// Drop it, and warn the member to slow down.
+    Warn,
+    /// Drop it, and tell the member they're muted for a while.
+    Mute(Duration),
+    /// Drop it silently, since the member already knows they're muted.
+    Muted,
+    /// Drop it, and the member along with it.
+    Disconnect,
+}
+
+/// A token bucket per member, along with a tally of how often they've overstepped it.
+#[derive(Debug, Clone)]
+pub struct FloodGuard {
+    policy: FloodPolicy,
+    tokens: f64,
+    refilled_at: Instant,
+    strikes: u32,
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(policy: FloodPolicy) -> Self {
+        Self {
+            policy,
+            tokens: policy.burst as f64,
+            refilled_at: Instant::now(),
+            strikes: 0,
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Account for a line sent just now.
+    pub fn check(&mut self) -> Verdict {
+        self.check_at(Instant::now())
+    }
+
+    /// Account for a line sent at the given time.
+    pub fn check_at(&mut self, now: Instant) -> Verdict {
+        self.refill(now);
+
+        if let Some(muted_until) = self.muted_until {
+            if now < muted_until {
+                return Verdict::Muted;
+            }
+            self.muted_until = None;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+
+        self.strikes += 1;
+        if self.strikes < self.policy.mute_after {
+            return Verdict::Warn;
+        }
+
+        self.strikes = 0;
+        self.mutes += 1;
+        if self.mutes >= self.policy.disconnect_after {
+            return Verdict::Disconnect;
+        }
+        self.muted_until = Some(now + self.policy.mute_for);
+        Verdict::Mute(self.policy.mute_for)
+    }
+
+    /// Top the bucket up with however many tokens the member earned since we last looked.
+    /// A member who lets their bucket fill all the way up again is forgiven their strikes.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        let burst = self.policy.burst as f64;
+        self.tokens = (self.tokens + elapsed * self.policy.rate).min(burst);
+        self.refilled_at = now;
+        if self.tokens >= burst {
+            self.strikes = 0;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::room::Message;
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+/// How much of what was said in a room we hold on to, so we can
+/// replay it to members who join later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HistoryConfig {
+    /// The most messages we'll replay. Zero turns history off entirely.
+    pub capacity: usize,
+    /// Messages older than this are never replayed, if set.
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryConfig {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_age: None,
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// A message as it was said in a room, and when.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub at: SystemTime,
+    pub message: Message,
+}
+
+/// A bounded ring buffer of the most recent messages said in a room.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    config: HistoryConfig,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            entries: VecDeque::with_capacity(config.capacity),
+        }
+    }
+
+    /// Remember a message said just now, forgetting the oldest one if we're full.
+    pub fn record(&mut self, message: &str) {
+        self.record_at(SystemTime::now(), message);
+    }
+
+    /// Remember a message said at the given time, forgetting the oldest one if we're full.
+    pub fn record_at(&mut self, at: SystemTime, message: &str) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.config.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            at,
+            message: message.to_string(),
+        });
+    }
+
+    /// The messages worth replaying right now, oldest first.
+    pub fn recent(&self) -> impl Iterator<Item = &HistoryEntry> {
+        let now = SystemTime::now();
+        let max_age = self.config.max_age;
+        self.entries.iter().filter(move |entry| match max_age {
+            // Anything from the future (i.e. the clock went backwards) is fair game.
+            Some(max_age) => now
+                .duration_since(entry.at)
+                .map_or(true, |age| age <= max_age),
+            None => true,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,625 @@
+use crate::{
+    command::Line,
+    names::is_display_name,
+    outbox::{Outbound, OutboundReceiver},
+    registry::{is_room_name_valid, RoomRegistry},
+    room::RoomListing,
+    server::{Gateway, Stage, Step},
+    ClientInitializationError, MemberID,
+};
+use std::{collections::BTreeSet, fmt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    select,
+};
+use tracing::{debug, error, info, trace, warn};
+
+/// What we call ourselves, and the host every member appears to connect from, to IRC clients.
+pub const SERVER_NAME: &str = "budget-chat";
+
+/// A single line of the IRC protocol, e.g.
+///
+/// `:alice!alice@budget-chat PRIVMSG #lobby :hi there`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new<I, S>(command: &str, params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            prefix: None,
+            command: command.to_string(),
+            params: params.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Say who the message is from, e.g. another member or the server.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Parse a line from a client, without its line ending. Commands are case-insensitive,
+    /// so they're always given back in upper case. Returns `None` for blank lines.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (prefix, rest) = match line.strip_prefix(':') {
+            Some(rest) => {
+                let (prefix, rest) = rest.split_once(' ')?;
+                (Some(prefix.to_string()), rest)
+            }
+            None => (None, line),
+        };
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        let mut params: Vec<String> = words.map(str::to_string).collect();
+        params.extend(trailing.map(str::to_string));
+        Some(Self {
+            prefix,
+            command,
+            params,
+        })
+    }
+
+    pub fn param(&self, index: usize) -> Option<&str> {
+        self.params.get(index).map(String::as_str)
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            match last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                true => write!(f, " :{}", last)?,
+                false => write!(f, " {}", last)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a member appears to IRC clients, e.g. `alice!alice@budget-chat`.
+fn user_prefix(name: &str) -> String {
+    format!("{}!{}@{}", name, name, SERVER_NAME)
+}
+
+/// What a channel is called on IRC, for a room.
+fn channel_for(room: &str) -> String {
+    format!("#{}", room)
+}
+
+/// The room an IRC channel stands for, if the channel name is one we could ever have.
+fn room_for(channel: &str) -> Option<&str> {
+    channel
+        .strip_prefix('#')
+        .filter(|room| is_room_name_valid(room))
+}
+
+/// What a line the chat rooms sent to a member means, so it can be told to an IRC client
+/// in its own terms. Anything we don't recognise is passed on as a notice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Heard<'a> {
+    /// `[{from}] {text}`
+    Chat { from: &'a str, text: &'a str },
+    /// `[{from} -> {to}] {text}`
+    Direct { from: &'a str, text: &'a str },
+    /// `* {name} has entered the room`
+    Entered(&'a str),
+    /// `* {name} has left the room`
+    Left(&'a str),
+    /// `* [history] {message}`
+    History(&'a str),
+    /// Anything else the server has to say.
+    Notice(&'a str),
+}
+
+impl<'a> Heard<'a> {
+    fn parse(line: &'a str) -> Self {
+        if let Some(rest) = line.strip_prefix("* ") {
+            if let Some(message) = rest.strip_prefix("[history] ") {
+                return Self::History(message);
+            }
+            if let Some(name) = rest
+                .strip_suffix(" has entered the room")
+                .filter(|name| is_display_name(name))
+            {
+                return Self::Entered(name);
+            }
+            if let Some(name) = rest
+                .strip_suffix(" has left the room")
+                .filter(|name| is_display_name(name))
+            {
+                return Self::Left(name);
+            }
+            return Self::Notice(rest);
+        }
+        if let Some((from, text)) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+        {
+            if let Some((from, _)) = from.split_once(" -> ") {
+                return Self::Direct { from, text };
+            }
+            if is_display_name(from) {
+                return Self::Chat { from, text };
+            }
+        }
+        Self::Notice(line)
+    }
+}
+
+/// Start listening for IRC clients, and spawn individual client handlers.
+pub async fn client_loop(listener: TcpListener, gateway: Gateway) -> crate::Result<()> {
+    debug!("Entering IRC client_loop...");
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        debug!(peer = %addr, "Accepted IRC connection");
+        if let Err(err) = socket.set_nodelay(true) {
+            warn!(peer = %addr, "Failed to disable Nagle's algorithm: {}", err);
+        }
+
+        let gateway = gateway.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_client(socket, addr, gateway).await {
+                error!("Handling IRC client failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Register an IRC client as a member, and relay between it and the chat rooms until
+/// either side hangs up.
+#[tracing::instrument(skip_all, fields(peer = %addr, name, kind = "irc"))]
+pub async fn handle_client(
+    socket: TcpStream,
+    addr: MemberID,
+    gateway: Gateway,
+) -> crate::Result<()> {
+    let (read_half, write_half) = socket.into_split();
+    let mut client = IrcClient {
+        lines: BufReader::new(read_half).lines(),
+        write_half,
+        addr,
+        nick: None,
+        password: None,
+        channel: None,
+        members: Default::default(),
+    };
+
+    let mut stage = match gateway.staging.start(&addr) {
+        Ok(stage) => stage,
+        Err(err) => {
+            client.error(&err.to_string()).await?;
+            return Ok(());
+        }
+    };
+    if !client.register(&mut stage).await? {
+        return Ok(());
+    }
+    let nick = stage.admit();
+    tracing::Span::current().record("name", &nick);
+    info!("IRC registration complete... Connecting member with Room.");
+
+    client.welcome().await?;
+    let outbound = gateway.connect(addr, &nick).await?;
+    if let Err(err) = client.relay(&gateway, outbound).await {
+        warn!("Evicting member: {}", err);
+    }
+    gateway.disconnect(addr, &nick).await;
+    Ok(())
+}
+
+/// A single IRC client, and what it believes about the channel it's in.
+///
+/// Members are always in exactly one room, so joining an IRC channel moves the member
+/// out of whichever channel they were in. Nothing changes until the chat rooms list who's
+/// in the room the member entered, which might never happen if they turn the move down.
+#[derive(Debug)]
+struct IrcClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+    addr: MemberID,
+    /// The nick we claimed for the client, once it asked for a good one.
+    nick: Option<String>,
+    /// What the client gave with `PASS`, for registered nicks.
+    password: Option<String>,
+    /// The channel the member is in, once the chat rooms told us so.
+    channel: Option<String>,
+    /// Everybody else in the channel.
+    members: BTreeSet<String>,
+}
+
+impl IrcClient {
+    fn nick(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    async fn send(&mut self, message: IrcMessage) -> crate::Result<()> {
+        trace!(%message, "Writing to IRC client.");
+        // One write per line, so Nagle doesn't hold back the line ending.
+        self.write_half
+            .write_all(format!("{}\r\n", message).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// Send a numeric reply from the server, addressed to the client.
+    async fn reply(&mut self, numeric: &str, params: &[&str]) -> crate::Result<()> {
+        let nick = self.nick().to_string();
+        let params = std::iter::once(nick.as_str()).chain(params.iter().copied());
+        self.send(IrcMessage::new(numeric, params).with_prefix(SERVER_NAME))
+            .await
+    }
+
+    async fn notice(&mut self, target: &str, text: &str) -> crate::Result<()> {
+        self.send(IrcMessage::new("NOTICE", [target, text]).with_prefix(SERVER_NAME))
+            .await
+    }
+
+    /// Tell the client why we're hanging up on it.
+    async fn error(&mut self, reason: &str) -> crate::Result<()> {
+        self.send(IrcMessage::new("ERROR", [reason])).await
+    }
+
+    /// Go through `NICK` and `USER` registration, which is IRC's take on staging:
+    /// every nick goes through the client's [Stage] like any other name, and a bad one is either
+    /// turned down so the client can pick another, or, if the [NamePolicy](crate::names::NamePolicy)
+    /// doesn't reprompt, gets the client disconnected. Registered nicks are only handed out to
+    /// clients that gave their password with `PASS`, and they only get one go at it.
+    /// Returns whether the client made it, in which case its nick is the stage's to admit.
+    async fn register(&mut self, stage: &mut Stage<'_>) -> crate::Result<bool> {
+        let mut has_user = false;
+        loop {
+            if has_user && stage.name().is_some() {
+                if !stage.needs_password() {
+                    return Ok(true);
+                }
+                let password = self.password.take().unwrap_or_default();
+                return match stage.answer(&password).await {
+                    Step::Admit => Ok(true),
+                    step => {
+                        warn!("IRC client gave the wrong password for a registered nick.");
+                        self.nick = None;
+                        self.reply("464", &["Password incorrect"]).await?;
+                        if let Step::Retry(err) | Step::Reject { err, .. } = step {
+                            self.error(&err.to_string()).await?;
+                        }
+                        Ok(false)
+                    }
+                };
+            }
+            let line = match stage.wait_for(self.lines.next_line()).await {
+                Ok(line) => line?,
+                Err(err) => {
+                    self.error(&err.to_string()).await?;
+                    return Ok(false);
+                }
+            };
+            let Some(line) = line else {
+                warn!("IRC client went away before registering.");
+                return Ok(false);
+            };
+            let Some(message) = IrcMessage::parse(&line) else {
+                continue;
+            };
+            trace!(%message, "Read from unregistered IRC client.");
+            match message.command.as_str() {
+                "NICK" => {
+                    let Some(raw) = message.param(0) else {
+                        self.reply("431", &["No nickname given"]).await?;
+                        continue;
+                    };
+                    match stage.claim(raw) {
+                        Step::Admit | Step::Password => {
+                            self.nick = stage.name().map(str::to_string);
+                        }
+                        Step::Retry(err) => {
+                            let numeric = match err {
+                                ClientInitializationError::NameTaken(_) => "433",
+                                _ => "432",
+                            };
+                            self.reply(numeric, &[raw, &err.to_string()]).await?;
+                        }
+                        Step::Reject { err, .. } => {
+                            self.error(&err.to_string()).await?;
+                            return Ok(false);
+                        }
+                    }
+                }
+                "USER" => has_user = true,
+                "PING" => self.pong(&message).await?,
+                "QUIT" => {
+                    self.error("Closing link").await?;
+                    return Ok(false);
+                }
+                "CAP" => self.cap(&message).await?,
+                "PASS" => self.password = message.param(0).map(str::to_string),
+                "PONG" => {}
+                _ => self.reply("451", &["You have not registered"]).await?,
+            }
+        }
+    }
+
+    async fn welcome(&mut self) -> crate::Result<()> {
+        let nick = self.nick().to_string();
+        self.reply("001", &[&format!("Welcome to budgetchat, {}", nick)])
+            .await?;
+        self.reply("002", &[&format!("Your host is {}", SERVER_NAME)])
+            .await?;
+        self.reply("003", &["This server was created just now"])
+            .await?;
+        self.reply("004", &[SERVER_NAME, env!("CARGO_PKG_VERSION"), "o", "o"])
+            .await?;
+        self.reply("422", &["MOTD File is missing"]).await
+    }
+
+    async fn pong(&mut self, ping: &IrcMessage) -> crate::Result<()> {
+        let token = ping.param(0).unwrap_or(SERVER_NAME).to_string();
+        self.send(IrcMessage::new("PONG", [SERVER_NAME, &token]).with_prefix(SERVER_NAME))
+            .await
+    }
+
+    /// We don't support any capabilities, but clients that ask should hear as much.
+    async fn cap(&mut self, cap: &IrcMessage) -> crate::Result<()> {
+        let nick = self.nick().to_string();
+        match cap.param(0).map(str::to_ascii_uppercase).as_deref() {
+            Some("LS") | Some("LIST") => {
+                let subcommand = cap.param(0).unwrap_or_default().to_ascii_uppercase();
+                self.send(IrcMessage::new("CAP", [&nick, &subcommand, ""]).with_prefix(SERVER_NAME))
+                    .await
+            }
+            Some("REQ") => {
+                let requested = cap.param(1).unwrap_or_default().to_string();
+                self.send(
+                    IrcMessage::new("CAP", [&nick, "NAK", &requested]).with_prefix(SERVER_NAME),
+                )
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Relay between the client and the chat rooms, until either side hangs up.
+    async fn relay(
+        &mut self,
+        gateway: &Gateway,
+        mut outbound: OutboundReceiver,
+    ) -> crate::Result<()> {
+        loop {
+            select! {
+                message = outbound.recv() => match message {
+                    Some(message) => {
+                        select! {
+                            result = self.deliver(&message) => result?,
+                            // The client isn't reading what we write, and we've given up on them.
+                            _ = outbound.aborted() => {
+                                warn!("Gave up on IRC client while writing to them. Hanging up.");
+                                return Ok(());
+                            }
+                        }
+                    }
+                    None => {
+                        trace!("Nobody can send messages to this IRC client anymore. Hanging up.");
+                        return self.error("Closing link").await;
+                    }
+                },
+                line = self.lines.next_line() => match line? {
+                    Some(line) => {
+                        let Some(message) = IrcMessage::parse(&line) else {
+                            continue;
+                        };
+                        trace!(%message, "Read from IRC client.");
+                        if !self.handle(gateway, message).await? {
+                            return self.error("Closing link").await;
+                        }
+                    }
+                    None => {
+                        trace!("No more lines to read from IRC client.");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Act on a line from a registered client. Returns whether the client wants to stay.
+    async fn handle(&mut self, gateway: &Gateway, message: IrcMessage) -> crate::Result<bool> {
+        match message.command.as_str() {
+            "PRIVMSG" => {
+                let (Some(target), Some(text)) = (message.param(0), message.param(1)) else {
+                    self.reply("412", &["No text to send"]).await?;
+                    return Ok(true);
+                };
+                if !target.starts_with('#') {
+                    gateway
+                        .forward(self.addr, format!("/msg {} {}", target, text))
+                        .await?;
+                } else if self.channel.as_deref() == Some(target) {
+                    // IRC clients handle their own commands, so whatever makes it
+                    // to a channel is chat.
+                    gateway.forward(self.addr, Line::escape(text)).await?;
+                } else {
+                    self.reply("404", &[target, "Cannot send to channel"])
+                        .await?;
+                }
+            }
+            "JOIN" => {
+                let Some(channels) = message.param(0) else {
+                    self.reply("461", &["JOIN", "Not enough parameters"])
+                        .await?;
+                    return Ok(true);
+                };
+                // Members are only ever in one room, so there's no point joining more than one.
+                let channel = channels.split(',').next().unwrap_or_default();
+                let room = match channel {
+                    "0" => RoomRegistry::LOBBY,
+                    channel => match room_for(channel) {
+                        Some(room) => room,
+                        None => {
+                            self.reply("403", &[channel, "No such channel"]).await?;
+                            return Ok(true);
+                        }
+                    },
+                };
+                self.join(gateway, room).await?;
+            }
+            "PART" => {
+                let channel = message.param(0).unwrap_or_default();
+                let channel = channel.split(',').next().unwrap_or_default();
+                if self.channel.as_deref() != Some(channel) {
+                    self.reply("442", &[channel, "You're not on that channel"])
+                        .await?;
+                } else if room_for(channel) == Some(RoomRegistry::LOBBY) {
+                    self.notice(
+                        channel,
+                        "Everybody is always somewhere, so the lobby can't be left. JOIN another channel instead",
+                    )
+                    .await?;
+                } else {
+                    self.join(gateway, RoomRegistry::LOBBY).await?;
+                }
+            }
+            "NAMES" => {
+                if let Some(channel) = self.channel.clone() {
+                    self.names(&channel).await?;
+                }
+            }
+            "MODE" => match message.param(0) {
+                Some(target) if target.starts_with('#') => {
+                    self.reply("324", &[target, "+"]).await?
+                }
+                _ => self.reply("221", &["+"]).await?,
+            },
+            "WHO" => {
+                let mask = message.param(0).unwrap_or("*").to_string();
+                self.reply("315", &[&mask, "End of WHO list"]).await?;
+            }
+            "NICK" => {
+                self.reply("447", &["Nicknames can't be changed here"])
+                    .await?
+            }
+            "USER" | "PASS" => self.reply("462", &["You may not reregister"]).await?,
+            "PING" => self.pong(&message).await?,
+            "CAP" => self.cap(&message).await?,
+            "QUIT" => return Ok(false),
+            "PONG" | "NOTICE" => {}
+            command => self.reply("421", &[command, "Unknown command"]).await?,
+        }
+        Ok(true)
+    }
+
+    /// Ask the chat rooms to move the member into another room, unless they're in it already.
+    async fn join(&mut self, gateway: &Gateway, room: &str) -> crate::Result<()> {
+        if self.channel.as_deref().and_then(room_for) == Some(room) {
+            return Ok(());
+        }
+        let command = match room {
+            RoomRegistry::LOBBY => "/leave".to_string(),
+            room => format!("/join {}", room),
+        };
+        gateway.forward(self.addr, command).await
+    }
+
+    async fn names(&mut self, channel: &str) -> crate::Result<()> {
+        let names = std::iter::once(self.nick().to_string())
+            .chain(self.members.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.reply("353", &["=", channel, &names]).await?;
+        self.reply("366", &[channel, "End of /NAMES list"]).await
+    }
+
+    /// Tell the client about something the chat rooms sent the member.
+    async fn deliver(&mut self, message: &Outbound) -> crate::Result<()> {
+        match message {
+            Outbound::Listing(listing) => self.entered(listing).await,
+            Outbound::Line(line) => self.hear(line).await,
+        }
+    }
+
+    /// The member made it into a room, so move the client into its channel.
+    async fn entered(&mut self, listing: &RoomListing) -> crate::Result<()> {
+        let nick = self.nick().to_string();
+        let channel = channel_for(&listing.room);
+        if let Some(previous) = self.channel.replace(channel.clone()) {
+            self.send(IrcMessage::new("PART", [previous]).with_prefix(user_prefix(&nick)))
+                .await?;
+        }
+        self.members = listing
+            .members
+            .iter()
+            .chain(&listing.bots)
+            .cloned()
+            .collect();
+        self.send(IrcMessage::new("JOIN", [&channel]).with_prefix(user_prefix(&nick)))
+            .await?;
+        self.names(&channel).await
+    }
+
+    async fn hear(&mut self, line: &str) -> crate::Result<()> {
+        let nick = self.nick().to_string();
+        match Heard::parse(line) {
+            Heard::Entered(name) => {
+                self.members.insert(name.to_string());
+                let channel = self.current_channel();
+                self.send(IrcMessage::new("JOIN", [channel]).with_prefix(user_prefix(name)))
+                    .await
+            }
+            Heard::Left(name) => {
+                self.members.remove(name);
+                let channel = self.current_channel();
+                self.send(IrcMessage::new("PART", [channel]).with_prefix(user_prefix(name)))
+                    .await
+            }
+            Heard::Chat { from, text } => {
+                let channel = self.current_channel();
+                self.send(
+                    IrcMessage::new("PRIVMSG", [channel.as_str(), text])
+                        .with_prefix(user_prefix(from)),
+                )
+                .await
+            }
+            Heard::Direct { from, text } => {
+                self.send(
+                    IrcMessage::new("PRIVMSG", [nick.as_str(), text])
+                        .with_prefix(user_prefix(from)),
+                )
+                .await
+            }
+            Heard::History(message) => {
+                let channel = self.current_channel();
+                self.notice(&channel, &format!("[history] {}", message))
+                    .await
+            }
+            Heard::Notice(text) => self.notice(&nick, text).await,
+        }
+    }
+
+    fn current_channel(&self) -> String {
+        self.channel
+            .clone()
+            .unwrap_or_else(|| channel_for(RoomRegistry::LOBBY))
+    }
+}
@@ -1,9 +1,39 @@
-pub mod connection;
+pub mod accounts;
+pub mod bot;
+pub mod chat_log;
+pub mod client;
+pub mod command;
 mod errors;
+pub mod federation;
+pub mod flood;
+pub mod history;
+pub mod irc;
+pub mod moderation;
+pub mod names;
+pub mod outbox;
+pub mod registry;
 pub mod room;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod tui;
+pub mod websocket;
 
 pub use errors::*;
 
 pub type MemberID = std::net::SocketAddr;
 
+/// How many messages can wait in any channel between the moving parts of the chat,
+/// before whoever is sending has to wait.
+pub const CHANNEL_CAPACITY: usize = 1024;
+
 pub type Shared<T> = std::sync::Arc<std::sync::Mutex<T>>;
+
+/// Write a file out to a temporary one next to it first, so a crash mid-write never leaves
+/// us with half of it.
+pub fn write_atomically(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
@@ -1,262 +1,324 @@
 use budget_chat::{
-    connection::Connection,
-    room::{Message, Room},
-    MemberID,
+    accounts::Accounts,
+    bot::BotConfig,
+    chat_log::ChatLog,
+    federation::FederationConfig,
+    flood::FloodPolicy,
+    history::HistoryConfig,
+    moderation::{Bans, Operators},
+    names::NamePolicy,
+    outbox::{OutboxConfig, OverflowPolicy},
+    server::{BudgetChat, ServerConfig},
+    shutdown::{ShutdownConfig, ShutdownHandle},
 };
 use clap::Parser;
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
 };
-use tokio::sync::mpsc;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    select,
-    sync::mpsc::UnboundedSender,
-};
-use tracing::{debug, error, info, trace, warn};
+use tokio::net::TcpListener;
+use tracing::{error, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
 pub struct Args {
     #[clap(short, long)]
     port: u16,
-}
 
-#[tokio::main]
-async fn main() -> budget_chat::Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "budget_chat=info,tokio=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    /// Also let IRC clients in on this port, into the same rooms.
+    #[clap(long)]
+    irc_port: Option<u16>,
 
-    let args = Args::parse();
-    run_server(args.port).await.unwrap();
-    Ok(())
-}
+    /// Also let browsers in on this port over websockets, and serve them a web client there.
+    #[clap(long)]
+    websocket_port: Option<u16>,
 
-///
-pub async fn run_server(port: u16) -> budget_chat::Result<()> {
-    let (message_received_from_member_tx, message_received_from_member_rx) =
-        tokio::sync::mpsc::unbounded_channel();
-    let (send_to_member_tx, mut send_to_member_rx) = tokio::sync::mpsc::unbounded_channel();
-    let (client_disconnected_tx, client_disconnected_rx) = tokio::sync::mpsc::unbounded_channel();
-    let (client_connected_with_name_tx, client_connected_with_name_rx) =
-        tokio::sync::mpsc::unbounded_channel();
-
-    let outbound_peer_map: Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>> =
-        Default::default();
-
-    let outbound_peer_map_cp = outbound_peer_map.clone();
-    // Any outbound messages from the chat room need to be sent to the corresponding
-    // transport handle, so let's do that in background.
-    tokio::task::spawn(async move {
-        loop {
-            match send_to_member_rx.recv().await {
-                Some((peer, message)) => {
-                    let guard = outbound_peer_map_cp.lock().unwrap();
-                    let sender = guard.get(&peer).unwrap();
-                    sender.send(message).unwrap();
-                }
-                None => {
-                    debug!("Got None in send_to_member_rx. Dropping sender handle.");
-                    break;
-                }
-            }
-        }
-    });
-
-    // Setup our Chat Room with the appropriate channels.
-    let mut room = Room::new(
-        message_received_from_member_rx,
-        send_to_member_tx,
-        client_disconnected_rx,
-        client_connected_with_name_rx,
-    );
-    // Drive our chat room in the background, so it only needs to wait for the members
-    // and messages to be thrown around in the channels.
-    let room_handle = tokio::task::spawn(async move {
-        room.run().await;
-    });
-
-    let addr: SocketAddr = ([0; 8], port).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening for connections on {}", listener.local_addr()?);
-
-    // Wait until the room as well as the main client loop is complete.
-    let (room_result, client_loop_result) = tokio::join!(
-        room_handle,
-        client_loop(
-            listener,
-            message_received_from_member_tx.clone(),
-            client_connected_with_name_tx,
-            client_disconnected_tx,
-            outbound_peer_map.clone()
-        )
-    );
+    /// What this server is called to the servers it's linked with.
+    #[clap(long, default_value = "budget-chat")]
+    server_name: String,
 
-    room_result.unwrap();
-    client_loop_result.unwrap();
+    /// The secret every linked server shares. Linking is off without one.
+    #[clap(long)]
+    federation_secret: Option<String>,
 
-    Ok(())
-}
+    /// Let other servers link to us on this port.
+    #[clap(long, requires = "federation_secret")]
+    federation_port: Option<u16>,
 
-/// Start listening for connections, and spawn individual
-/// client handlers.
-pub async fn client_loop(
-    listener: TcpListener,
-    message_recvd_from_member_tx: mpsc::UnboundedSender<(MemberID, Message)>,
-    client_connected_with_name_tx: mpsc::UnboundedSender<(MemberID, String)>,
-    client_disconnected_tx: mpsc::UnboundedSender<MemberID>,
-    outbound_peer_map: Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>,
-) -> budget_chat::Result<()> {
-    debug!("Entering client_loop...");
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        debug!(peer = %addr, "Accepted connection");
-
-        let message_recvd_from_member_tx = message_recvd_from_member_tx.clone();
-        let client_disconnected_tx = client_disconnected_tx.clone();
-        let client_connected_with_name_tx = client_connected_with_name_tx.clone();
-        let outbound_peer_map = outbound_peer_map.clone();
-
-        tokio::task::spawn(async move {
-            if let Err(err) = handle_client(
-                socket,
-                addr,
-                message_recvd_from_member_tx,
-                client_connected_with_name_tx,
-                client_disconnected_tx,
-                outbound_peer_map,
-            )
-            .await
-            {
-                error!("Handling client failed: {}", err);
-            }
-        });
-    }
-}
+    /// Link to the server listening at this address. Can be given more than once.
+    #[clap(long = "link", requires = "federation_secret")]
+    links: Vec<SocketAddr>,
 
-/// Try to complete the name-giving ceremony (aka "staging")
-/// for a TCP-connected peer and if everything goes okay, return the stream
-/// back as is, along with the name the peer chose.
-#[tracing::instrument(fields(kind = "staging"))]
-pub async fn stage_client(
-    socket: TcpStream,
-    addr: MemberID,
-) -> budget_chat::Result<(String, TcpStream)> {
-    let (read_half, mut write_half) = socket.into_split();
-    let reader = BufReader::new(read_half);
-    let mut lines = reader.lines();
-
-    // Try writing. If we fail, drop the client.
-    trace!("Requesting client for a name.");
-    write_half
-        .write_all(b"Welcome to budgetchat! What shall I call you?\n")
-        .await?;
-
-    let Some(ref name) = lines.next_line().await? else {
-        warn!("Could not read line from client when we were expecting a name. Disconnecting.");
-        return Err(budget_chat::ClientInitializationError::ConnectionResetByClient.into());
-    };
+    /// How many recent messages per room to replay to members as they enter it.
+    /// Off by default, since plain budget chat clients don't expect them.
+    #[clap(long, default_value_t = 0)]
+    history_size: usize,
 
-    trace!("Just read line: {}", name);
-    let Some(cleaned) = is_name_valid(name) else {
-        warn!(raw = %name, "Got invalid name. Disconnecting.");
-        return Err(budget_chat::ClientInitializationError::InvalidName(name.to_string()).into());
-    };
+    /// Never replay messages older than this many seconds.
+    #[clap(long)]
+    history_max_age_secs: Option<u64>,
+
+    /// Append every join, leave and chat message to this file, and rebuild room history from it on startup.
+    #[clap(long)]
+    chat_log: Option<PathBuf>,
+
+    /// Rotate the chat log once it grows past this many bytes.
+    #[clap(long, default_value_t = ChatLog::DEFAULT_MAX_BYTES)]
+    chat_log_max_bytes: u64,
+
+    /// How many rotated chat log files to keep around.
+    #[clap(long, default_value_t = ChatLog::DEFAULT_MAX_FILES)]
+    chat_log_max_files: usize,
+
+    /// The longest name a member can pick.
+    #[clap(long, default_value_t = NamePolicy::DEFAULT_MAX_LENGTH)]
+    max_name_length: usize,
+
+    /// A name nobody can pick. Can be given more than once.
+    #[clap(long = "reserved-name")]
+    reserved_names: Vec<String>,
+
+    /// Ask clients who pick a bad or taken name for another one, instead of disconnecting them.
+    #[clap(long)]
+    reprompt_names: bool,
+
+    /// Warn, mute and then disconnect members who say too much too fast.
+    /// Off by default, since plain budget chat clients don't expect to be slowed down.
+    #[clap(long)]
+    flood_protection: bool,
+
+    /// How many lines a member can send back to back before being slowed down.
+    #[clap(long, default_value_t = FloodPolicy::default().burst, value_parser = clap::value_parser!(u32).range(1..))]
+    flood_burst: u32,
+
+    /// How many lines per second a member can keep sending after their burst.
+    #[clap(long, default_value_t = FloodPolicy::default().rate, value_parser = parse_flood_rate)]
+    flood_rate: f64,
+
+    /// How many warnings a flooding member gets before being muted.
+    #[clap(long, default_value_t = FloodPolicy::default().mute_after, value_parser = clap::value_parser!(u32).range(1..))]
+    flood_mute_after: u32,
+
+    /// How many seconds a flooding member stays muted.
+    #[clap(long, default_value_t = FloodPolicy::default().mute_for.as_secs())]
+    flood_mute_secs: u64,
+
+    /// How many mutes before a flooding member gets disconnected.
+    #[clap(long, default_value_t = FloodPolicy::default().disconnect_after, value_parser = clap::value_parser!(u32).range(1..))]
+    flood_disconnect_after: u32,
+
+    /// The most messages we'll hold on to for a member who isn't reading them.
+    #[clap(long, default_value_t = OutboxConfig::DEFAULT_LIMIT)]
+    outbound_limit: usize,
+
+    /// What to do when a member has too many messages waiting for them.
+    #[clap(long, value_enum, default_value_t = OverflowPolicy::default())]
+    overflow_policy: OverflowPolicy,
+
+    /// An operator, as `name:password`. They become one by saying `/op <password>`.
+    /// Can be given more than once.
+    #[clap(long = "operator", value_parser = parse_operator)]
+    operators: Vec<(String, String)>,
+
+    /// Anyone connecting from this address is an operator. Can be given more than once.
+    #[clap(long = "operator-address")]
+    operator_addresses: Vec<IpAddr>,
+
+    /// Keep bans here, so they survive restarts.
+    #[clap(long)]
+    bans_file: Option<PathBuf>,
 
-    debug!(name = %cleaned, "Client name is valid.");
+    /// Let members register their names with `/register <password>`, keeping
+    /// the accounts in this file.
+    #[clap(long)]
+    accounts_file: Option<PathBuf>,
 
-    let read_half = lines.into_inner().into_inner();
-    let socket = read_half.reunite(write_half)?;
+    /// Only let in members with registered names.
+    #[clap(long, requires = "accounts_file")]
+    no_guests: bool,
 
-    Ok((cleaned.to_string(), socket))
+    /// How many seconds someone who picked a registered name has to give its password.
+    #[clap(long, requires = "accounts_file", default_value_t = Accounts::DEFAULT_PASSWORD_TIMEOUT.as_secs())]
+    password_timeout_secs: u64,
+
+    /// How many wrong passwords a connection can give before it's hung up on.
+    #[clap(long, requires = "accounts_file", default_value_t = Accounts::DEFAULT_MAX_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    password_attempts: u32,
+
+    /// What every member is told when the server shuts down.
+    #[clap(long, default_value = ShutdownConfig::DEFAULT_MESSAGE)]
+    shutdown_message: String,
+
+    /// How many seconds to wait for members to be told we're shutting down, before hanging up anyway.
+    #[clap(long, default_value_t = ShutdownConfig::DEFAULT_FLUSH_DEADLINE.as_secs())]
+    shutdown_flush_secs: u64,
+
+    /// Treat every line from raw TCP members as chat, even ones starting with `/`.
+    #[clap(long)]
+    strict_protocol: bool,
+
+    /// Sit a bot in a room, e.g. `dice`, `reminders@games` or `builds=/var/run/build-status@ci`.
+    /// Can be given more than once.
+    #[clap(long = "bot")]
+    bots: Vec<BotConfig>,
 }
 
-/// Check if the name is a non-empty ascii alphanumeric string.
-pub fn is_name_valid(name: &str) -> Option<&str> {
-    let trimmed = name.trim();
-    if trimmed.is_empty() {
-        return None;
+fn parse_operator(operator: &str) -> Result<(String, String), String> {
+    match operator.split_once(':') {
+        Some((name, password)) if !name.is_empty() && !password.is_empty() => {
+            Ok((name.to_string(), password.to_string()))
+        }
+        _ => Err("operators look like name:password".to_string()),
     }
-    match trimmed.chars().all(|char| char.is_ascii_alphanumeric()) {
-        true => Some(trimmed),
-        false => None,
+}
+
+/// A rate of zero, or one that isn't a number, would never let a member's bucket refill.
+fn parse_flood_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err("the flood rate has to be a number of lines per second above 0".to_string()),
     }
 }
 
-/// Given a raw socket, try to finish the name-giving of the peer,
-/// and if it goes fine, make a long running [Connection] out of it.
-#[tracing::instrument(skip_all, fields(addr, peer = %addr, name))]
-pub async fn handle_client(
-    socket: TcpStream,
-    addr: MemberID,
-    message_recvd_from_member_tx: mpsc::UnboundedSender<(MemberID, Message)>,
-    client_connected_with_name_tx: mpsc::UnboundedSender<(MemberID, String)>,
-    client_disconnected_tx: mpsc::UnboundedSender<MemberID>,
-    outbound_peer_map: Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>,
-) -> budget_chat::Result<()> {
-    let Ok((name, socket)) = stage_client(socket, addr).await else {
-        error!("Staging failed for peer");
-        return Ok(());
+/// Wait for SIGINT (or Ctrl-C) or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Wait for Ctrl-C.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Shut the server down gracefully on the first signal to stop.
+async fn shut_down_on_signal(shutdown: ShutdownHandle) {
+    match shutdown_signal().await {
+        Ok(()) => {
+            info!("Got a signal to stop.");
+            shutdown.shutdown();
+        }
+        Err(err) => error!("Can't listen for signals to stop: {}", err),
+    }
+}
+
+#[tokio::main]
+async fn main() -> budget_chat::Result<()> {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "budget_chat=info,tokio=debug".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let args = Args::parse();
+    let mut history = HistoryConfig::new(args.history_size);
+    if let Some(max_age) = args.history_max_age_secs {
+        history = history.with_max_age(Duration::from_secs(max_age));
+    }
+    let chat_log = match args.chat_log {
+        Some(path) => Some(
+            ChatLog::open(path)?
+                .with_max_bytes(args.chat_log_max_bytes)
+                .with_max_files(args.chat_log_max_files),
+        ),
+        None => None,
+    };
+    let names = NamePolicy::default()
+        .with_max_length(args.max_name_length)
+        .with_reserved(&args.reserved_names)
+        .with_reprompt(args.reprompt_names);
+    let flood_policy = match args.flood_protection {
+        false => None,
+        true => Some(
+            FloodPolicy::default()
+                .with_burst(args.flood_burst)
+                .with_rate(args.flood_rate)
+                .with_mute_after(args.flood_mute_after)
+                .with_mute_for(Duration::from_secs(args.flood_mute_secs))
+                .with_disconnect_after(args.flood_disconnect_after),
+        ),
     };
+    let outbox = OutboxConfig::default()
+        .with_limit(args.outbound_limit)
+        .with_overflow(args.overflow_policy);
 
-    tracing::Span::current().record("name", &name);
+    let operators = args
+        .operators
+        .iter()
+        .fold(Operators::default(), |operators, (name, password)| {
+            operators.with_password(name, password)
+        });
+    let operators = args
+        .operator_addresses
+        .into_iter()
+        .fold(operators, Operators::with_address);
+    let bans = match args.bans_file {
+        Some(path) => Bans::load(path)?,
+        None => Bans::default(),
+    };
 
-    info!("Staging complete... Connecting member with Room.");
+    let accounts = match args.accounts_file {
+        Some(path) => Some(
+            Accounts::load(path)?
+                .with_guests(!args.no_guests)
+                .with_password_timeout(Duration::from_secs(args.password_timeout_secs))
+                .with_max_attempts(args.password_attempts),
+        ),
+        None => None,
+    };
 
-    // We'll only call it a Connection after its been through staging.
-    let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
-    let connection = Connection::new(socket, outbound_rx);
+    let shutdown = ShutdownConfig::default()
+        .with_message(&args.shutdown_message)
+        .with_flush_deadline(Duration::from_secs(args.shutdown_flush_secs));
 
-    let (mut inbound_message_for_room_rx, on_disconnect_rx) = connection.subscribe();
-    let connection_handle = tokio::task::spawn(connection.run());
+    let config = ServerConfig {
+        history,
+        chat_log,
+        names,
+        flood_policy,
+        outbox,
+        operators,
+        bans,
+        accounts,
+        shutdown,
+        strict_protocol: args.strict_protocol,
+        bots: args.bots,
+    };
 
-    // Once a named connection to the room is established,
-    // register this peer into a shared state.
-    {
-        let mut guard = outbound_peer_map.lock().unwrap();
-        guard.insert(addr, outbound_tx);
-        debug!("peer map: {:#?}", guard.keys());
-        drop(guard);
+    let addr: SocketAddr = ([0; 8], args.port).into();
+    let listener = TcpListener::bind(addr).await?;
+    let mut chat = BudgetChat::new(listener, config);
+    if let Some(irc_port) = args.irc_port {
+        let irc_addr: SocketAddr = ([0; 8], irc_port).into();
+        chat = chat.with_irc(TcpListener::bind(irc_addr).await?);
     }
-    client_connected_with_name_tx.send((addr, name)).unwrap();
-
-    let listen_for_messages = tokio::task::spawn(async move {
-        // Propagate the individual messages received into the sink keyed by the
-        // peer addr.
-        let send_messages = async move {
-            loop {
-                if let Some(message) = inbound_message_for_room_rx.recv().await {
-                    trace!(message = %message, "Message from peer that will be forwarded to Room.");
-                    message_recvd_from_member_tx.send((addr, message)).unwrap();
-                }
+    if let Some(websocket_port) = args.websocket_port {
+        let websocket_addr: SocketAddr = ([0; 8], websocket_port).into();
+        chat = chat.with_websocket(TcpListener::bind(websocket_addr).await?);
+    }
+    if let Some(secret) = args.federation_secret {
+        let federation = args.links.into_iter().fold(
+            FederationConfig::new(&args.server_name, &secret),
+            FederationConfig::with_peer,
+        );
+        let listener = match args.federation_port {
+            Some(federation_port) => {
+                let federation_addr: SocketAddr = ([0; 8], federation_port).into();
+                Some(TcpListener::bind(federation_addr).await?)
             }
+            None => None,
         };
-        select! {
-            _ = send_messages => {
-                trace!("No more messages can be received from client.");
-            },
-            // The connection just terminated for whatever reason.
-            _ = on_disconnect_rx => {
-                trace!("Client disconnected while we were waiting on messages.");
-                {
-                    let mut guard = outbound_peer_map.lock().unwrap();
-                    guard.remove(&addr);
-                }
-                // Tell the chat room about it.
-                client_disconnected_tx.send(addr).unwrap();
-            }
-        }
-    });
-
-    _ = tokio::join!(listen_for_messages, connection_handle);
-    Ok(())
+        chat = chat.with_federation(federation, listener);
+    }
+    tokio::spawn(shut_down_on_signal(chat.shutdown_handle()));
+    chat.run().await
 }
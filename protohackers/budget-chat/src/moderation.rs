@@ -0,0 +1,209 @@
+use crate::{write_atomically, Shared};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info};
+
+/// Who gets to moderate: members who know the password for their name,
+/// and anyone connecting from a trusted address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Operators {
+    /// Operator names, and the password each has to give with `/op` to become one.
+    pub passwords: HashMap<String, String>,
+    /// Members connecting from any of these are operators as soon as they join.
+    pub addresses: HashSet<IpAddr>,
+}
+
+impl Operators {
+    pub fn with_password(mut self, name: &str, password: &str) -> Self {
+        self.passwords
+            .insert(name.to_string(), password.to_string());
+        self
+    }
+
+    pub fn with_address(mut self, address: IpAddr) -> Self {
+        self.addresses.insert(address);
+        self
+    }
+
+    /// Check if the given password makes the member with the given name an operator,
+    /// without leaking how close it got.
+    pub fn check_password(&self, name: &str, password: &str) -> bool {
+        let Some(expected) = self.passwords.get(name) else {
+            return false;
+        };
+        // Comparing digests rather than the passwords themselves hides their length too.
+        let given = digest(name, password).finalize().into_bytes();
+        digest(name, expected).verify_slice(&given).is_ok()
+    }
+
+    pub fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.addresses.contains(address)
+    }
+}
+
+fn digest(name: &str, password: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(password.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(name.as_bytes());
+    mac
+}
+
+/// Who a ban keeps out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    /// Anything that looks like an IP address is one, and everything else is a name.
+    pub fn parse(target: &str) -> Self {
+        match target.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Name(target.to_string()),
+        }
+    }
+
+    /// Whether a ban on this target covers the other one. Names only differing in case
+    /// are the same name, as far as claiming them goes, so they're the same target too.
+    pub fn covers(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Name(name), Self::Name(other)) => name.eq_ignore_ascii_case(other),
+            (target, other) => target == other,
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// A single ban, either forever or until some time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Seconds since the unix epoch when the ban lifts, if ever.
+    pub until_secs: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(target: BanTarget, duration: Option<Duration>) -> Self {
+        let until_secs = duration.map(|duration| {
+            (SystemTime::now() + duration)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        Self { target, until_secs }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.until_secs.is_some_and(|until_secs| {
+            SystemTime::now() >= UNIX_EPOCH + Duration::from_secs(until_secs)
+        })
+    }
+}
+
+/// Every ban in effect, shared between the rooms (which hand them out)
+/// and staging (which enforces them), and written to disk on every change if we have a path.
+///
+/// Saving happens on [spawn_blocking](tokio::task::spawn_blocking), so handing out a ban
+/// never holds up a room, and so needs a tokio runtime once there's a path.
+#[derive(Debug, Clone, Default)]
+pub struct Bans {
+    bans: Shared<Vec<Ban>>,
+    path: Option<PathBuf>,
+    /// Held while saving, so two saves never race to rename their files into place.
+    saving: Shared<()>,
+}
+
+impl Bans {
+    /// Load whatever bans were saved at the given path, and keep saving them there.
+    /// A missing file means nobody's banned yet.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let bans: Vec<Ban> = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        info!(path = %path.display(), bans = bans.len(), "Loaded bans.");
+        Ok(Self {
+            bans: Arc::new(Mutex::new(bans)),
+            path: Some(path),
+            saving: Default::default(),
+        })
+    }
+
+    /// Put a ban in place, replacing any earlier ban on the same target.
+    pub fn ban(&self, ban: Ban) {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|existing| !existing.target.covers(&ban.target) && !existing.is_expired());
+        debug!(target = %ban.target, until_secs = ?ban.until_secs, "Banning.");
+        bans.push(ban);
+        drop(bans);
+        self.save();
+    }
+
+    /// Lift the ban on a target. Returns whether there was one.
+    pub fn unban(&self, target: &BanTarget) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let found = bans
+            .iter()
+            .any(|existing| existing.target.covers(target) && !existing.is_expired());
+        bans.retain(|existing| !existing.target.covers(target) && !existing.is_expired());
+        drop(bans);
+        if found {
+            self.save();
+        }
+        found
+    }
+
+    /// The ban in effect on a target, if any.
+    pub fn find(&self, target: &BanTarget) -> Option<Ban> {
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|ban| ban.target.covers(target) && !ban.is_expired())
+            .cloned()
+    }
+
+    pub fn is_banned(&self, target: &BanTarget) -> bool {
+        self.find(target).is_some()
+    }
+
+    /// Write the bans out in the background. Whichever save runs last writes the latest bans,
+    /// however the saves were scheduled.
+    fn save(&self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let bans = self.bans.clone();
+        let saving = self.saving.clone();
+        tokio::task::spawn_blocking(move || {
+            let _saving = saving.lock().unwrap();
+            let saved = serde_json::to_vec_pretty(&*bans.lock().unwrap())
+                .map_err(io::Error::from)
+                .and_then(|contents| write_atomically(&path, &contents));
+            if let Err(err) = saved {
+                error!(path = %path.display(), "Failed to save bans: {}", err);
+            }
+        });
+    }
+}
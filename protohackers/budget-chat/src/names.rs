@@ -0,0 +1,160 @@
+use crate::{ClientInitializationError, Shared};
+use std::collections::HashSet;
+use tracing::debug;
+
+/// What we accept as a member's name during staging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePolicy {
+    /// The longest name we'll accept.
+    pub max_length: usize,
+    /// Names nobody can take, compared case-insensitively.
+    pub reserved: HashSet<String>,
+    /// Ask for another name instead of disconnecting when a client picks a bad one.
+    pub reprompt: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self {
+            max_length: Self::DEFAULT_MAX_LENGTH,
+            reserved: Default::default(),
+            reprompt: false,
+        }
+    }
+}
+
+impl NamePolicy {
+    /// The spec requires us to allow names of at least 16 characters.
+    pub const DEFAULT_MAX_LENGTH: usize = 16;
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    pub fn with_reserved<I, S>(mut self, reserved: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.reserved = reserved
+            .into_iter()
+            .map(|name| name.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn with_reprompt(mut self, reprompt: bool) -> Self {
+        self.reprompt = reprompt;
+        self
+    }
+
+    /// Check everything about a raw name except whether somebody else already has it,
+    /// and give back the cleaned up name if it's fine.
+    pub fn check<'a>(&self, raw: &'a str) -> Result<&'a str, ClientInitializationError> {
+        let Some(name) = is_name_valid(raw) else {
+            return Err(ClientInitializationError::InvalidName(raw.to_string()));
+        };
+        if name.len() > self.max_length {
+            return Err(ClientInitializationError::NameTooLong {
+                name: name.to_string(),
+                max: self.max_length,
+            });
+        }
+        if self.reserved.contains(&name.to_ascii_lowercase()) {
+            return Err(ClientInitializationError::NameReserved(name.to_string()));
+        }
+        Ok(name)
+    }
+}
+
+/// What a member on a linked server is called here when their own name is already taken,
+/// e.g. `alice@elsewhere`. No name a member here picks can ever look like this.
+pub fn qualified(name: &str, server: &str) -> String {
+    format!("{}@{}", name, server)
+}
+
+/// Whether something is a name as members see it: one that made it through a [NamePolicy],
+/// a bot's, or one [qualified] with a linked server. There's never a space in any of them,
+/// which tells them apart from anything else the server says.
+pub fn is_display_name(name: &str) -> bool {
+    let (name, server) = match name.split_once('@') {
+        Some((name, server)) => (name, Some(server)),
+        None => (name, None),
+    };
+    is_name_valid(name) == Some(name)
+        && server.is_none_or(|server| !server.is_empty() && !server.contains(char::is_whitespace))
+}
+
+/// Check if the name is a non-empty ascii alphanumeric string.
+pub fn is_name_valid(name: &str) -> Option<&str> {
+    let trimmed = name.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    match trimmed.chars().all(|char| char.is_ascii_alphanumeric()) {
+        true => Some(trimmed),
+        false => None,
+    }
+}
+
+/// The names of every member who made it through staging and hasn't left yet,
+/// shared between all the clients being staged so that no two of them get the same name.
+/// Names are compared case-insensitively, same as reserved names, so `Alice` and `alice`
+/// can't both be in.
+///
+/// This runs ahead of the members the rooms know about, since a name is claimed
+/// the moment staging succeeds, before the [RoomRegistry](crate::registry::RoomRegistry) hears of it.
+#[derive(Debug, Clone, Default)]
+pub struct ClaimedNames {
+    names: Shared<HashSet<String>>,
+}
+
+impl ClaimedNames {
+    /// Take a name if nobody has it yet. Checking and taking happen under the same lock,
+    /// so of two clients racing for a name exactly one wins.
+    pub fn claim(&self, name: &str) -> Result<(), ClientInitializationError> {
+        let mut guard = self.names.lock().unwrap();
+        match guard.insert(name.to_ascii_lowercase()) {
+            true => {
+                debug!(name, "Claimed name.");
+                Ok(())
+            }
+            false => Err(ClientInitializationError::NameTaken(name.to_string())),
+        }
+    }
+
+    /// Give a name back once its member has left.
+    pub fn release(&self, name: &str) {
+        let mut guard = self.names.lock().unwrap();
+        guard.remove(&name.to_ascii_lowercase());
+        debug!(name, "Released name.");
+    }
+}
+
+/// Everything staging needs to decide whether a client gets the name they asked for.
+#[derive(Debug, Clone, Default)]
+pub struct Names {
+    pub policy: NamePolicy,
+    pub claimed: ClaimedNames,
+}
+
+impl Names {
+    pub fn new(policy: NamePolicy) -> Self {
+        Self {
+            policy,
+            claimed: Default::default(),
+        }
+    }
+
+    /// Check a raw name against the policy and, if it passes, claim it.
+    pub fn claim(&self, raw: &str) -> Result<String, ClientInitializationError> {
+        let name = self.policy.check(raw)?;
+        self.claimed.claim(name)?;
+        Ok(name.to_string())
+    }
+
+    pub fn release(&self, name: &str) {
+        self.claimed.release(name)
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    room::{Message, RoomListing},
+    MemberID,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::Notify,
+    time::{sleep, Instant},
+};
+use tracing::{debug, trace, warn};
+
+/// What to do when a member isn't reading fast enough and their outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Forget the oldest message still waiting, to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Forget the new message.
+    DropNewest,
+    /// Hang up on the member.
+    Disconnect,
+}
+
+/// How much we're willing to hold on to for any one member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxConfig {
+    /// The most messages waiting to be written to any one member.
+    pub limit: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            limit: Self::DEFAULT_LIMIT,
+            overflow: Default::default(),
+        }
+    }
+}
+
+impl OutboxConfig {
+    pub const DEFAULT_LIMIT: usize = 256;
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+}
+
+/// Something queued for a member. Nearly everything is a line of the protocol already,
+/// but room listings keep what went into them, for transports that say who's in a room
+/// in their own way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outbound {
+    Line(Message),
+    Listing(RoomListing),
+}
+
+impl From<Message> for Outbound {
+    fn from(line: Message) -> Self {
+        Self::Line(line)
+    }
+}
+
+impl From<RoomListing> for Outbound {
+    fn from(listing: RoomListing) -> Self {
+        Self::Listing(listing)
+    }
+}
+
+/// Shows it as the line raw TCP members get.
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line(line) => write!(f, "{}", line),
+            Self::Listing(listing) => write!(f, "{}", listing),
+        }
+    }
+}
+
+/// How many messages are waiting to be written, across every member. Every queue keeps it
+/// up to date, so it can be watched from anywhere without asking the chat rooms.
+#[derive(Debug, Clone, Default)]
+pub struct Backlog {
+    queued: Arc<AtomicUsize>,
+}
+
+impl Backlog {
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, messages: usize) {
+        self.queued.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    fn remove(&self, messages: usize) {
+        self.queued.fetch_sub(messages, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    messages: VecDeque<Outbound>,
+    /// Nothing more is coming, but whatever's queued should still be written.
+    closed: bool,
+    /// Nothing more is coming, and the connection should hang up right away.
+    aborted: bool,
+}
+
+impl QueueState {
+    /// Forget everything still queued.
+    fn clear(&mut self, backlog: &Backlog) {
+        backlog.remove(self.messages.len());
+        self.messages.clear();
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    config: OutboxConfig,
+    backlog: Backlog,
+}
+
+/// How the chat rooms reach a single member's [Session](crate::session::Session), or whatever
+/// else is writing to them: the sending half of the bounded outbound queue the session owns.
+/// The registry keeps one in its [Outbox], and every room the member enters gets a copy.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    queue: Arc<Queue>,
+}
+
+impl SessionHandle {
+    /// Make a queue for a member who just made it through staging. The handle goes to the
+    /// chat rooms, and the receiver stays with whatever writes to the member.
+    pub fn new(config: OutboxConfig, backlog: Backlog) -> (Self, OutboundReceiver) {
+        let queue = Arc::new(Queue {
+            state: Default::default(),
+            notify: Notify::new(),
+            config,
+            backlog,
+        });
+        let receiver = OutboundReceiver {
+            queue: queue.clone(),
+        };
+        (Self { queue }, receiver)
+    }
+
+    /// Queue a message for the member, applying the [OverflowPolicy] if they're too far behind.
+    /// Messages for members we've hung up on are dropped.
+    pub fn send(&self, message: impl Into<Outbound>) {
+        let Queue {
+            state,
+            notify,
+            config,
+            backlog,
+        } = &*self.queue;
+        let mut state = state.lock().unwrap();
+        if state.closed || state.aborted {
+            trace!("Dropping message for a member that's already gone.");
+            return;
+        }
+        if state.messages.len() >= config.limit {
+            match config.overflow {
+                OverflowPolicy::DropOldest => {
+                    trace!("Outbound queue is full. Dropping the oldest message.");
+                    if state.messages.pop_front().is_some() {
+                        backlog.remove(1);
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    trace!("Outbound queue is full. Dropping the newest message.");
+                    return;
+                }
+                OverflowPolicy::Disconnect => {
+                    warn!("Outbound queue is full. Hanging up on slow member.");
+                    state.clear(backlog);
+                    state.aborted = true;
+                    drop(state);
+                    notify.notify_one();
+                    return;
+                }
+            }
+        }
+        state.messages.push_back(message.into());
+        backlog.add(1);
+        drop(state);
+        notify.notify_one();
+    }
+
+    /// Stop accepting messages for the member. Their connection hangs up once it has
+    /// written out whatever was queued before this.
+    pub fn hang_up(&self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.notify.notify_one();
+    }
+
+    fn len(&self) -> usize {
+        self.queue.state.lock().unwrap().messages.len()
+    }
+}
+
+/// The receiving half of a single member's bounded outbound queue, read by their connection.
+/// Dropping it forgets whatever was still queued, and drops anything sent after.
+#[derive(Debug)]
+pub struct OutboundReceiver {
+    queue: Arc<Queue>,
+}
+
+impl OutboundReceiver {
+    /// Wait for the next message to write to the member. Returns `None` once we've hung
+    /// up on the member and there's nothing left to write.
+    pub async fn recv(&mut self) -> Option<Outbound> {
+        loop {
+            {
+                let mut state = self.queue.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    self.queue.backlog.remove(1);
+                    return Some(message);
+                }
+                if state.closed || state.aborted {
+                    return None;
+                }
+            }
+            // There's only ever one of us waiting, so a permit stored by `notify_one`
+            // while we weren't looking can't get lost.
+            self.queue.notify.notified().await;
+        }
+    }
+
+    /// Resolves once we've given up on the member altogether, e.g. for being too slow to
+    /// read what we send them, so their connection can hang up even in the middle of a write.
+    pub async fn aborted(&self) {
+        loop {
+            if self.queue.state.lock().unwrap().aborted {
+                return;
+            }
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.clear(&self.queue.backlog);
+        state.closed = true;
+    }
+}
+
+/// Every member's [SessionHandle], keyed by member. This is how the chat rooms reach
+/// members they can't find in a room, and hang up on them. It belongs to the
+/// [RoomRegistry](crate::registry::RoomRegistry) alone, and nothing here ever blocks.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    sessions: HashMap<MemberID, SessionHandle>,
+    backlog: Backlog,
+}
+
+impl Outbox {
+    /// Keep track of sessions whose queues all count towards the given backlog.
+    pub fn new(backlog: Backlog) -> Self {
+        Self {
+            sessions: Default::default(),
+            backlog,
+        }
+    }
+
+    /// Keep the handle of a member who just made it through staging.
+    pub fn register(&mut self, member_id: MemberID, session: SessionHandle) {
+        self.sessions.insert(member_id, session);
+    }
+
+    /// The handle of a member we haven't hung up on, to give to the room they enter.
+    pub fn session(&self, member_id: &MemberID) -> Option<SessionHandle> {
+        self.sessions.get(member_id).cloned()
+    }
+
+    /// Queue a message for a member. Messages for members who are already gone are dropped.
+    pub fn send(&self, member_id: MemberID, message: impl Into<Outbound>) {
+        match self.sessions.get(&member_id) {
+            Some(session) => session.send(message),
+            None => {
+                debug!(member = %member_id, "Dropping message for a member that's already gone.")
+            }
+        }
+    }
+
+    /// Stop accepting messages for a member. Their connection hangs up once it has
+    /// written out whatever was queued before this.
+    pub fn hang_up(&mut self, member_id: MemberID) {
+        if let Some(session) = self.sessions.remove(&member_id) {
+            debug!(member = %member_id, "Hanging up on member.");
+            session.hang_up();
+        }
+    }
+
+    /// Hang up on every member, e.g. because we're shutting down.
+    pub fn hang_up_all(&mut self) {
+        debug!(members = self.sessions.len(), "Hanging up on every member.");
+        for (_, session) in self.sessions.drain() {
+            session.hang_up();
+        }
+    }
+
+    /// Wait until every member's connection has picked up everything queued for them, but
+    /// no longer than the given deadline. Returns whether everything was picked up in time.
+    pub async fn flush(&self, deadline: Duration) -> bool {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+        let give_up_at = Instant::now() + deadline;
+        loop {
+            if self.queued() == 0 {
+                return true;
+            }
+            if Instant::now() >= give_up_at {
+                return false;
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// How many messages are waiting to be written, across every member.
+    pub fn queued(&self) -> usize {
+        self.backlog.queued()
+    }
+
+    /// How many messages are waiting to be written to the given member.
+    pub fn queued_for(&self, member_id: &MemberID) -> usize {
+        self.sessions.get(member_id).map_or(0, SessionHandle::len)
+    }
+}
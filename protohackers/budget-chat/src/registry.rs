@@ -0,0 +1,1363 @@
+use crate::{
+    accounts::Accounts,
+    bot::{self, BotEvent, Bots, Post},
+    chat_log::{ChatEvent, ChatEventKind, ChatLog, ChatLogWriter},
+    command::{Arguments, CommandSpec, Commands, Invocation, Line},
+    federation::{EventKind, Federation, LinkEvent, LinkID, RemoteMember},
+    flood::{FloodGuard, FloodPolicy, Verdict},
+    history::HistoryConfig,
+    moderation::{Ban, BanTarget, Bans, Operators},
+    outbox::{Outbox, SessionHandle},
+    room::{Message, Presence, Room},
+    shutdown::{ShutdownConfig, ShutdownHandle},
+    CommandError, MemberID,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// An actor that keeps track of every [Room], which room each member is in, and routes
+/// everything members say or do to the right room.
+///
+/// Every member starts out in the [lobby](RoomRegistry::LOBBY), so clients that don't
+/// know about rooms see a single chat room, same as always.
+#[derive(Debug)]
+pub struct RoomRegistry {
+    /// Every room that has at least one member, by name. The lobby is always here.
+    rooms: HashMap<String, Room>,
+
+    /// The name of the room each named member is currently in.
+    member_rooms: HashMap<MemberID, String>,
+
+    /// Someone will let us know when we receive a message from a member.
+    message_received_from_member: mpsc::Receiver<(MemberID, Message)>,
+
+    /// Where we queue up messages for members, and hang up on them.
+    outbox: Outbox,
+
+    /// Someone will let us know when a client disconnects.
+    client_disconnected_rx: mpsc::Receiver<MemberID>,
+
+    /// Someone will let us know when a client connects with a given name, and how to reach them.
+    client_connected_with_name_rx: mpsc::Receiver<(MemberID, String, SessionHandle)>,
+
+    /// How much any one member is allowed to say, if we're limiting that at all.
+    flood_policy: Option<FloodPolicy>,
+
+    /// How much each member has been saying lately.
+    flood_guards: HashMap<MemberID, FloodGuard>,
+
+    /// Members we've hung up on, whose lines may still trickle in until transport notices.
+    disconnecting: HashSet<MemberID>,
+
+    /// Who's allowed to become an operator, and how.
+    operators: Operators,
+
+    /// Members who are currently operators.
+    operator_members: HashSet<MemberID>,
+
+    /// The lowercased names of members an operator muted, and until when, if not until
+    /// they're unmuted. Kept by name, so leaving and coming back doesn't lift a mute.
+    mutes: HashMap<String, Option<Instant>>,
+
+    /// Who's kept out, shared with staging.
+    bans: Bans,
+
+    /// How much history every room keeps around.
+    history_config: HistoryConfig,
+
+    /// Where every join, leave and chat message gets written to, if anywhere.
+    chat_log: Option<ChatLogWriter>,
+
+    /// Our links to other servers, and everyone we know of on them, if we're linked at all.
+    federation: Option<Federation>,
+
+    /// Registered names, shared with staging, if accounts are enabled at all.
+    accounts: Option<Accounts>,
+
+    /// Every command members can use.
+    commands: Commands<Self>,
+
+    /// The bots sitting in rooms, and whatever they're saying.
+    bots: Bots,
+
+    /// Lets us know when it's time to say goodbye to everyone.
+    shutdown: ShutdownHandle,
+
+    /// How we say goodbye.
+    shutdown_config: ShutdownConfig,
+}
+
+impl RoomRegistry {
+    /// The room every member enters after staging, and goes back to when they `/leave`.
+    pub const LOBBY: &'static str = "lobby";
+
+    /// The longest room name we'll let anyone create.
+    pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+    #[tracing::instrument(skip_all)]
+    pub fn new(
+        message_received_from_member: mpsc::Receiver<(MemberID, Message)>,
+        outbox: Outbox,
+        client_disconnected_rx: mpsc::Receiver<MemberID>,
+        client_connected_with_name_rx: mpsc::Receiver<(MemberID, String, SessionHandle)>,
+    ) -> Self {
+        let lobby = Room::new(Self::LOBBY);
+        Self {
+            rooms: HashMap::from([(Self::LOBBY.to_string(), lobby)]),
+            member_rooms: Default::default(),
+            message_received_from_member,
+            outbox,
+            client_disconnected_rx,
+            client_connected_with_name_rx,
+            flood_policy: None,
+            flood_guards: Default::default(),
+            disconnecting: Default::default(),
+            operators: Default::default(),
+            operator_members: Default::default(),
+            mutes: Default::default(),
+            bans: Default::default(),
+            history_config: Default::default(),
+            chat_log: None,
+            federation: None,
+            accounts: None,
+            commands: Self::commands(),
+            bots: Default::default(),
+            shutdown: Default::default(),
+            shutdown_config: Default::default(),
+        }
+    }
+
+    /// Have every room replay recent messages to members who enter it.
+    pub fn with_history(mut self, history_config: HistoryConfig) -> Self {
+        self.history_config = history_config;
+        self.rooms = self
+            .rooms
+            .into_iter()
+            .map(|(name, room)| (name, room.with_history(history_config)))
+            .collect();
+        self
+    }
+
+    /// Limit how much any one member can say with the given policy. Nobody is limited by default,
+    /// since the protocol never asks plain budget chat clients to slow down.
+    pub fn with_flood_policy(mut self, flood_policy: Option<FloodPolicy>) -> Self {
+        self.flood_policy = flood_policy;
+        self
+    }
+
+    /// Let the given operators moderate, handing out bans that staging then enforces.
+    pub fn with_moderation(mut self, operators: Operators, bans: Bans) -> Self {
+        self.operators = operators;
+        self.bans = bans;
+        self
+    }
+
+    /// Write every join, leave and chat message in any room to the given log,
+    /// from a task of its own. Needs a tokio runtime.
+    pub fn with_chat_log(mut self, chat_log: ChatLog) -> Self {
+        self.chat_log = Some(ChatLogWriter::spawn(chat_log));
+        self
+    }
+
+    /// Share every room with linked servers.
+    pub fn with_federation(mut self, federation: Federation) -> Self {
+        self.federation = Some(federation);
+        self
+    }
+
+    /// Let members register their names with the given accounts, which staging then
+    /// asks passwords for.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = Some(accounts);
+        self
+    }
+
+    /// Sit the given bots, already started, in their rooms as soon as the rooms open.
+    pub fn with_bots(mut self, bots: Bots) -> Self {
+        self.bots = bots;
+        self
+    }
+
+    /// Say goodbye to everyone and close the rooms once the given handle is used.
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle, config: ShutdownConfig) -> Self {
+        self.shutdown = shutdown;
+        self.shutdown_config = config;
+        self
+    }
+
+    /// Refill the history of every room from events read back from a [ChatLog], so a restart
+    /// doesn't wipe out what members see when they enter. Rooms that end up with any history
+    /// are kept around even without members, so they can replay it later.
+    pub fn rebuild_history(&mut self, events: &[ChatEvent]) {
+        for event in events {
+            let ChatEventKind::Chat { text } = &event.kind else {
+                continue;
+            };
+            let history_config = self.history_config;
+            self.rooms
+                .entry(event.room.clone())
+                .or_insert_with(|| Room::new(&event.room).with_history(history_config))
+                .history_mut()
+                .record_at(event.at(), &Room::format_message(&event.member, text));
+        }
+        self.rooms
+            .retain(|name, room| name == Self::LOBBY || !room.history().is_empty());
+        info!(
+            events = events.len(),
+            rooms = self.rooms.len(),
+            "Rebuilt room history from the chat log."
+        );
+    }
+
+    /// Drive the chat rooms by listening for any inbound/outbound messages
+    /// to/from members as well as any new members connecting and old ones leaving,
+    /// until we're asked to shut down.
+    #[tracing::instrument(skip(self))]
+    pub async fn run(&mut self) {
+        info!("Starting our budget chat rooms...");
+        self.seat_bots();
+        loop {
+            tokio::select! {
+                _ = self.shutdown.requested() => {
+                    self.shut_down().await;
+                    return;
+                },
+                Some((new_member, new_member_name, session)) = self.client_connected_with_name_rx.recv() => {
+                    debug!("Client {} connected with name: {}", new_member, new_member_name);
+                    self.outbox.register(new_member, session);
+                    self.enter(new_member, &new_member_name, Self::LOBBY);
+                    if self.operators.is_trusted(&new_member.ip()) {
+                        self.operator_members.insert(new_member);
+                        self.reply(new_member, "You are an operator");
+                    }
+                },
+                Some(disconnected_member) = self.client_disconnected_rx.recv() => {
+                    debug!("Recvd client_disconnected");
+                    self.flood_guards.remove(&disconnected_member);
+                    self.disconnecting.remove(&disconnected_member);
+                    self.operator_members.remove(&disconnected_member);
+                    if let Some(name) = self.leave(disconnected_member) {
+                        self.publish(&name, EventKind::Quit);
+                    }
+                },
+                Some(link_event) = next_link_event(&mut self.federation) => {
+                    self.handle_link_event(link_event);
+                },
+                Some(post) = self.bots.next() => {
+                    self.bot_post(post);
+                },
+                Some((sender, msg)) = self.message_received_from_member.recv() => {
+                    if !self.admit(sender) {
+                        continue;
+                    }
+                    self.handle_line(sender, &msg);
+                }
+                else => {
+                    info!("Nobody can reach the chat rooms anymore. Closing them.");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tell every member we're shutting down, give their connections until the deadline
+    /// to write out whatever's queued for them, and hang up on everyone.
+    ///
+    /// Everyone still here leaves the chat log and our linked servers on the way out,
+    /// so neither is left thinking they're still around.
+    async fn shut_down(&mut self) {
+        info!(
+            members = self.member_rooms.len(),
+            "Shutting down the chat rooms..."
+        );
+        for room in self.rooms.values() {
+            room.announce(&self.shutdown_config.message);
+        }
+        let leaving = self
+            .member_rooms
+            .iter()
+            .filter_map(|(member_id, room_name)| {
+                let member_name = self.rooms.get(room_name)?.get_name(member_id)?;
+                Some((room_name.clone(), member_name))
+            })
+            .chain(
+                self.bots
+                    .iter()
+                    .map(|(bot_name, room_name)| (room_name.to_string(), bot_name.to_string())),
+            )
+            .collect::<Vec<_>>();
+        for (room_name, member_name) in leaving {
+            self.write_to_chat_log(&room_name, &member_name, ChatEventKind::Leave);
+            self.publish(&member_name, EventKind::Quit);
+        }
+        if let Some(chat_log) = self.chat_log.take() {
+            chat_log.close().await;
+        }
+
+        let deadline = self.shutdown_config.flush_deadline;
+        if !self.outbox.flush(deadline).await {
+            warn!(
+                queued = self.outbox.queued(),
+                ?deadline,
+                "Gave up waiting for members to be written to."
+            );
+        }
+        self.outbox.hang_up_all();
+        info!("Chat rooms are closed.");
+    }
+
+    /// Let a named member into the given room, creating the room if needed.
+    #[tracing::instrument(skip(self))]
+    pub fn enter(&mut self, member_id: MemberID, member_name: &str, room_name: &str) {
+        let Some(session) = self.outbox.session(&member_id) else {
+            warn!("Member to let in was already hung up on.");
+            return;
+        };
+        self.room_mut(room_name)
+            .enter(member_id, member_name, session);
+        self.member_rooms.insert(member_id, room_name.to_string());
+        self.write_to_chat_log(room_name, member_name, ChatEventKind::Join);
+        self.bots.tell(
+            room_name,
+            BotEvent::Join {
+                member: member_name.to_string(),
+            },
+            None,
+        );
+        self.publish(
+            member_name,
+            EventKind::Join {
+                room: room_name.to_string(),
+            },
+        );
+    }
+
+    /// The room with the given name, created if nobody's been in it yet.
+    fn room_mut(&mut self, room_name: &str) -> &mut Room {
+        self.rooms.entry(room_name.to_string()).or_insert_with(|| {
+            info!(room = %room_name, "Creating room.");
+            Room::new(room_name).with_history(self.history_config)
+        })
+    }
+
+    /// Drop a room once it's left empty with nothing to replay, unless it is the lobby.
+    fn drop_room_if_unused(&mut self, room_name: &str) {
+        let unused = self
+            .rooms
+            .get(room_name)
+            .is_some_and(|room| room.is_empty() && room.history().is_empty());
+        if unused && room_name != Self::LOBBY {
+            info!(room = %room_name, "Dropping empty room.");
+            self.rooms.remove(room_name);
+        }
+    }
+
+    /// Let a member out of whichever room they're in, dropping the room if it's
+    /// left empty with nothing to replay (unless it is the lobby). Returns the name of the member,
+    /// if we knew them.
+    #[tracing::instrument(skip(self))]
+    pub fn leave(&mut self, member_id: MemberID) -> Option<String> {
+        let room_name = self.member_rooms.remove(&member_id)?;
+        let room = self.rooms.get_mut(&room_name)?;
+        let member_name = room.leave(member_id);
+        self.drop_room_if_unused(&room_name);
+        if let Some(member_name) = &member_name {
+            self.write_to_chat_log(&room_name, member_name, ChatEventKind::Leave);
+            self.bots.tell(
+                &room_name,
+                BotEvent::Leave {
+                    member: member_name.to_string(),
+                },
+                None,
+            );
+        }
+        member_name
+    }
+
+    /// Append an event to the chat log, if we have one. A log we can't write to
+    /// shouldn't take the chat down with it, so failures are only logged.
+    fn write_to_chat_log(&self, room_name: &str, member_name: &str, kind: ChatEventKind) {
+        if let Some(chat_log) = self.chat_log.as_ref() {
+            chat_log.write(ChatEvent::new(room_name, member_name, kind));
+        }
+    }
+
+    /// The room a member is currently in, if they made it through staging.
+    pub fn room_of(&self, member_id: &MemberID) -> Option<&Room> {
+        self.member_rooms
+            .get(member_id)
+            .and_then(|room_name| self.rooms.get(room_name))
+    }
+
+    /// Check whether a member is saying too much before acting on anything they say,
+    /// and deal with them if they are.
+    fn admit(&mut self, member_id: MemberID) -> bool {
+        if self.disconnecting.contains(&member_id) {
+            return false;
+        }
+        let Some(flood_policy) = self.flood_policy else {
+            return true;
+        };
+        let verdict = self
+            .flood_guards
+            .entry(member_id)
+            .or_insert_with(|| FloodGuard::new(flood_policy))
+            .check();
+
+        match verdict {
+            Verdict::Allow => return true,
+            Verdict::Muted => {}
+            Verdict::Warn => {
+                debug!(member = %member_id, "Member is flooding.");
+                self.reply(member_id, "Slow down! You are sending messages too fast");
+            }
+            Verdict::Mute(duration) => {
+                warn!(member = %member_id, ?duration, "Muting member for flooding.");
+                self.reply(
+                    member_id,
+                    &format!(
+                        "You are muted for {} seconds for flooding",
+                        duration.as_secs()
+                    ),
+                );
+            }
+            Verdict::Disconnect => {
+                self.disconnect(member_id, "You are being disconnected for flooding");
+            }
+        }
+        false
+    }
+
+    /// Say goodbye to a member, and hang up on them. They'll leave their room
+    /// once transport lets us know they're gone, same as if they'd left on their own.
+    #[tracing::instrument(skip(self))]
+    pub fn disconnect(&mut self, member_id: MemberID, reason: &str) {
+        if !self.disconnecting.insert(member_id) {
+            return;
+        }
+        warn!("Disconnecting member.");
+        self.reply(member_id, reason);
+        self.outbox.hang_up(member_id);
+    }
+
+    /// Send a chat message from a member to everyone else in their room.
+    #[tracing::instrument(skip(self))]
+    fn broadcast(&mut self, sender: MemberID, message: &str) {
+        let Some(room) = self
+            .member_rooms
+            .get(&sender)
+            .and_then(|room_name| self.rooms.get_mut(room_name))
+        else {
+            warn!("Received a message from a member who isn't in any room. Dropping it.");
+            return;
+        };
+        info!(room = %room.name(), "[{:?}] {}", room.get_name(&sender), message);
+        if let Some(presence) = room.presence_mut(&sender) {
+            presence.touch();
+        }
+        debug!("Received message from sender that will be broadcasted to others.");
+        room.broadcast_message_to_other_members_except(&sender, message);
+
+        if let Some(member_name) = room.get_name(&sender) {
+            let room_name = room.name().to_string();
+            let text = message.trim().to_string();
+            self.write_to_chat_log(
+                &room_name,
+                &member_name,
+                ChatEventKind::Chat { text: text.clone() },
+            );
+            self.bots.tell(
+                &room_name,
+                BotEvent::Message {
+                    member: member_name.clone(),
+                    text: text.clone(),
+                },
+                None,
+            );
+            self.publish(
+                &member_name,
+                EventKind::Chat {
+                    room: room_name,
+                    text,
+                },
+            );
+        }
+    }
+
+    /// Every command members can use, and what handles it.
+    fn commands() -> Commands<Self> {
+        Commands::default()
+            .with(CommandSpec::new(
+                "help",
+                "/help [command]",
+                "list every command, or explain one",
+                Self::help,
+            ))
+            .with(CommandSpec::new(
+                "join",
+                "/join <room>",
+                "leave this room for another, creating it if nobody's there",
+                |registry, invocation| {
+                    let room = invocation.arguments.one()?;
+                    registry.move_member(invocation.sender, invocation.room, room)
+                },
+            ))
+            .with(CommandSpec::new(
+                "leave",
+                "/leave",
+                "go back to the lobby",
+                |registry, invocation| {
+                    invocation.arguments.none()?;
+                    registry.move_member(invocation.sender, invocation.room, Self::LOBBY)
+                },
+            ))
+            .with(CommandSpec::new(
+                "rooms",
+                "/rooms",
+                "list every room, and how many are in each",
+                |registry, invocation| {
+                    invocation.arguments.none()?;
+                    registry.reply(invocation.sender, &registry.list_rooms());
+                    Ok(())
+                },
+            ))
+            .with(CommandSpec::new(
+                "msg",
+                "/msg <name> <text>",
+                "say something to a single member, wherever they are",
+                |registry, invocation| {
+                    let (to, text) = invocation.arguments.one_and_text()?;
+                    registry.check_not_muted(invocation.sender)?;
+                    registry.direct_message(invocation.sender, to, text)
+                },
+            ))
+            .with(CommandSpec::new(
+                "who",
+                "/who",
+                "list everyone in this room, and how long they've been idle",
+                |registry, invocation| {
+                    invocation.arguments.none()?;
+                    registry.reply(invocation.sender, &registry.who(invocation.room));
+                    Ok(())
+                },
+            ))
+            .with(CommandSpec::new(
+                "whois",
+                "/whois <name>",
+                "when a member got here, when they last said anything, and where they are",
+                |registry, invocation| {
+                    let whois = registry.whois(invocation.arguments.one()?)?;
+                    registry.reply(invocation.sender, &whois);
+                    Ok(())
+                },
+            ))
+            .with(CommandSpec::new(
+                "away",
+                "/away [reason]",
+                "let anyone who messages you know you're away",
+                |registry, invocation| {
+                    let reason = invocation.arguments.rest().unwrap_or("away");
+                    registry.set_away(invocation.sender, Some(reason.to_string()));
+                    registry.reply(invocation.sender, &format!("You are now away: {}", reason));
+                    Ok(())
+                },
+            ))
+            .with(CommandSpec::new(
+                "back",
+                "/back",
+                "stop being away",
+                |registry, invocation| {
+                    invocation.arguments.none()?;
+                    registry
+                        .set_away(invocation.sender, None)
+                        .ok_or(CommandError::NotAway)?;
+                    registry.reply(invocation.sender, "You are back");
+                    Ok(())
+                },
+            ))
+            .with(CommandSpec::new(
+                "register",
+                "/register <password>",
+                "keep your name, so only those who know the password can take it",
+                |registry, invocation| {
+                    let password = invocation.arguments.one()?.to_string();
+                    registry.with_account(invocation.sender, move |accounts, name| {
+                        accounts
+                            .register(name, &password)
+                            .map(|_| format!("Registered {}", name))
+                    })
+                },
+            ))
+            .with(CommandSpec::new(
+                "passwd",
+                "/passwd <old password> <new password>",
+                "change the password for your name",
+                |registry, invocation| {
+                    let (old, new) = invocation.arguments.two()?;
+                    let (old, new) = (old.to_string(), new.to_string());
+                    registry.with_account(invocation.sender, move |accounts, name| {
+                        accounts
+                            .change_password(name, &old, &new)
+                            .map(|_| format!("Changed the password for {}", name))
+                    })
+                },
+            ))
+            .with(CommandSpec::new(
+                "op",
+                "/op <password>",
+                "become an operator, if the password for your name checks out",
+                |registry, invocation| registry.op(invocation.sender, invocation.arguments.one()?),
+            ))
+            .with(
+                CommandSpec::<Self>::new(
+                    "kick",
+                    "/kick <name>",
+                    "disconnect a member",
+                    |registry, invocation| {
+                        registry.kick(invocation.sender, invocation.arguments.one()?)
+                    },
+                )
+                .operators_only(),
+            )
+            .with(
+                CommandSpec::<Self>::new(
+                    "ban",
+                    "/ban <name|ip> [duration]",
+                    "disconnect a member, or everyone from an address, and keep them out",
+                    |registry, invocation| {
+                        let (target, duration) = invocation.arguments.one_and_duration()?;
+                        registry.ban(invocation.sender, target, duration)
+                    },
+                )
+                .operators_only(),
+            )
+            .with(
+                CommandSpec::<Self>::new(
+                    "mute",
+                    "/mute <name> [duration]",
+                    "stop a member from saying anything",
+                    |registry, invocation| {
+                        let (name, duration) = invocation.arguments.one_and_duration()?;
+                        registry.mute(invocation.sender, name, duration)
+                    },
+                )
+                .operators_only(),
+            )
+            .with(
+                CommandSpec::<Self>::new(
+                    "unmute",
+                    "/unmute <name>",
+                    "let a muted member speak again",
+                    |registry, invocation| {
+                        registry.unmute(invocation.sender, invocation.arguments.one()?)
+                    },
+                )
+                .operators_only(),
+            )
+            .with(
+                CommandSpec::<Self>::new(
+                    "unban",
+                    "/unban <name|ip>",
+                    "lift a ban",
+                    |registry, invocation| {
+                        registry.unban(invocation.sender, invocation.arguments.one()?)
+                    },
+                )
+                .operators_only(),
+            )
+    }
+
+    /// Act on a line a member sent, be it a command or something to say to their room.
+    fn handle_line(&mut self, sender: MemberID, line: &str) {
+        match Line::parse(line) {
+            Line::Chat(text) => {
+                if let Err(err) = self.check_not_muted(sender) {
+                    self.reply(sender, &err.to_string());
+                    return;
+                }
+                self.broadcast(sender, text);
+            }
+            Line::Command { name, arguments } => self.handle_command(sender, name, arguments),
+        }
+    }
+
+    /// Run a command a member used, if there is such a command and they're allowed to use it,
+    /// and tell them what went wrong otherwise.
+    #[tracing::instrument(skip(self))]
+    fn handle_command(&mut self, sender: MemberID, name: &str, arguments: &str) {
+        let Some(current_room) = self.room_of(&sender).map(|room| room.name().to_string()) else {
+            warn!("Received a command from a member who isn't in any room. Dropping it.");
+            return;
+        };
+        let result = match self.commands.get(name).copied() {
+            Some(spec) if !self.can_use(sender, &spec) => Err(CommandError::NotOperator),
+            Some(spec) => (spec.handler)(
+                self,
+                Invocation {
+                    sender,
+                    room: &current_room,
+                    arguments: Arguments::new(arguments, spec.usage),
+                },
+            ),
+            None => Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        if let Err(err) = result {
+            self.reply(sender, &err.to_string());
+        }
+    }
+
+    fn can_use(&self, member_id: MemberID, spec: &CommandSpec<Self>) -> bool {
+        !spec.operators_only || self.operator_members.contains(&member_id)
+    }
+
+    /// List every command a member can use, or explain the one they asked about.
+    fn help(&mut self, invocation: Invocation<'_>) -> Result<(), CommandError> {
+        let sender = invocation.sender;
+        if let Some(name) = invocation.arguments.rest() {
+            let name = name.trim_start_matches('/');
+            let spec = self
+                .commands
+                .get(name)
+                .filter(|spec| self.can_use(sender, spec))
+                .ok_or_else(|| CommandError::UnknownCommand(name.to_string()))?;
+            self.reply(sender, &spec.help());
+            return Ok(());
+        }
+        self.reply(
+            sender,
+            "Commands (start a line with // to say something that starts with /):",
+        );
+        for spec in self.commands.iter() {
+            if self.can_use(sender, spec) {
+                self.reply(sender, &spec.help());
+            }
+        }
+        Ok(())
+    }
+
+    /// Do something with the accounts for a member's name, and tell them how it went.
+    /// Passwords are slow to hash on purpose, so this happens off to the side, with the
+    /// member hearing back whenever it's done.
+    fn with_account<F>(&self, member_id: MemberID, f: F) -> Result<(), CommandError>
+    where
+        F: FnOnce(&Accounts, &str) -> Result<String, CommandError> + Send + 'static,
+    {
+        let accounts = self
+            .accounts
+            .clone()
+            .ok_or(CommandError::AccountsDisabled)?;
+        let name = self.name_of(&member_id).unwrap_or_default();
+        let session = self.outbox.session(&member_id);
+        tokio::task::spawn_blocking(move || {
+            let reply = f(&accounts, &name).unwrap_or_else(|err| err.to_string());
+            if let Some(session) = session {
+                session.send(format!("* {}", reply));
+            }
+        });
+        Ok(())
+    }
+
+    /// The name of a member, if they made it through staging.
+    fn name_of(&self, member_id: &MemberID) -> Option<String> {
+        self.room_of(member_id)
+            .and_then(|room| room.get_name(member_id))
+    }
+
+    /// Tell everyone in a member's room something about them.
+    fn announce_around(&self, member_id: &MemberID, message: &str) {
+        if let Some(room) = self.room_of(member_id) {
+            room.announce(&format!("* {}", message));
+        }
+    }
+
+    fn check_operator(&self, member_id: MemberID) -> Result<String, CommandError> {
+        match self.operator_members.contains(&member_id) {
+            true => Ok(self.name_of(&member_id).unwrap_or_default()),
+            false => Err(CommandError::NotOperator),
+        }
+    }
+
+    /// Make sure a member isn't muted before letting them say anything, lifting their
+    /// mute if it's run out.
+    fn check_not_muted(&mut self, member_id: MemberID) -> Result<(), CommandError> {
+        let Some(name) = self.name_of(&member_id) else {
+            return Ok(());
+        };
+        let name = name.to_ascii_lowercase();
+        match self.mutes.get(&name) {
+            None => Ok(()),
+            Some(Some(until)) if Instant::now() >= *until => {
+                self.mutes.remove(&name);
+                Ok(())
+            }
+            Some(_) => Err(CommandError::Muted),
+        }
+    }
+
+    fn op(&mut self, member_id: MemberID, password: &str) -> Result<(), CommandError> {
+        let name = self.name_of(&member_id).unwrap_or_default();
+        if !self.operators.check_password(&name, password) {
+            warn!(name, "Wrong operator password.");
+            return Err(CommandError::WrongPassword(name));
+        }
+        info!(name, "Member is now an operator.");
+        self.operator_members.insert(member_id);
+        self.reply(member_id, "You are now an operator");
+        Ok(())
+    }
+
+    fn kick(&mut self, operator: MemberID, name: &str) -> Result<(), CommandError> {
+        let operator_name = self.check_operator(operator)?;
+        let (member_id, name) = self.find_named(name)?;
+        info!(operator = operator_name, name, "Kicking member.");
+        self.announce_around(
+            &member_id,
+            &format!("{} was kicked by {}", name, operator_name),
+        );
+        self.disconnect(member_id, &format!("You were kicked by {}", operator_name));
+        Ok(())
+    }
+
+    fn ban(
+        &mut self,
+        operator: MemberID,
+        target: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), CommandError> {
+        let operator_name = self.check_operator(operator)?;
+        let target = BanTarget::parse(target);
+        let how_long = describe_duration(duration);
+        info!(operator = operator_name, %target, how_long, "Banning.");
+        self.bans.ban(Ban::new(target.clone(), duration));
+
+        let banned: Vec<_> = match &target {
+            BanTarget::Name(name) => self.find_member(name).into_iter().collect(),
+            BanTarget::Ip(ip) => self
+                .member_rooms
+                .keys()
+                .filter(|member_id| member_id.ip() == *ip)
+                .cloned()
+                .collect(),
+        };
+        for member_id in banned {
+            let name = self.name_of(&member_id).unwrap_or_default();
+            self.announce_around(
+                &member_id,
+                &format!("{} was banned by {}{}", name, operator_name, how_long),
+            );
+            self.disconnect(
+                member_id,
+                &format!("You were banned by {}{}", operator_name, how_long),
+            );
+        }
+        self.reply(operator, &format!("Banned {}{}", target, how_long));
+        Ok(())
+    }
+
+    fn mute(
+        &mut self,
+        operator: MemberID,
+        name: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), CommandError> {
+        let operator_name = self.check_operator(operator)?;
+        let (member_id, name) = self.find_named(name)?;
+        let how_long = describe_duration(duration);
+        info!(operator = operator_name, name, how_long, "Muting member.");
+        // Nothing else clears out mutes that ran out on members who never came back.
+        let now = Instant::now();
+        self.mutes
+            .retain(|_, until| until.is_none_or(|until| now < until));
+        self.mutes.insert(
+            name.to_ascii_lowercase(),
+            duration.map(|duration| now + duration),
+        );
+        self.announce_around(
+            &member_id,
+            &format!("{} was muted by {}{}", name, operator_name, how_long),
+        );
+        Ok(())
+    }
+
+    fn unmute(&mut self, operator: MemberID, name: &str) -> Result<(), CommandError> {
+        let operator_name = self.check_operator(operator)?;
+        if self.mutes.remove(&name.to_ascii_lowercase()).is_none() {
+            return Err(CommandError::NotMuted(name.to_string()));
+        }
+        info!(operator = operator_name, name, "Unmuted.");
+        match self.find_named(name) {
+            Ok((member_id, name)) => self.announce_around(
+                &member_id,
+                &format!("{} was unmuted by {}", name, operator_name),
+            ),
+            Err(_) => self.reply(operator, &format!("Unmuted {}", name)),
+        }
+        Ok(())
+    }
+
+    fn unban(&mut self, operator: MemberID, target: &str) -> Result<(), CommandError> {
+        let operator_name = self.check_operator(operator)?;
+        let target = BanTarget::parse(target);
+        if !self.bans.unban(&target) {
+            return Err(CommandError::NotBanned(target.to_string()));
+        }
+        info!(operator = operator_name, %target, "Unbanned.");
+        self.reply(operator, &format!("Unbanned {}", target));
+        Ok(())
+    }
+
+    /// Take a member out of their current room and into another one.
+    fn move_member(
+        &mut self,
+        member_id: MemberID,
+        from: &str,
+        to: &str,
+    ) -> Result<(), CommandError> {
+        if !is_room_name_valid(to) {
+            return Err(CommandError::InvalidRoomName {
+                name: to.to_string(),
+                max: Self::MAX_ROOM_NAME_LENGTH,
+            });
+        }
+        if from == to {
+            return Err(CommandError::AlreadyInRoom(to.to_string()));
+        }
+        let presence = self.presence_of(&member_id).cloned();
+        let Some(member_name) = self.leave(member_id) else {
+            warn!("Member to move had no name.");
+            return Ok(());
+        };
+        self.enter(member_id, &member_name, to);
+        if let Some(presence) = presence {
+            self.room_mut(to).set_presence(member_id, presence);
+        }
+        Ok(())
+    }
+
+    /// Deliver a message from a member to only the named member, in whichever room they are,
+    /// letting the sender know if they're away.
+    fn direct_message(
+        &mut self,
+        sender: MemberID,
+        to: &str,
+        text: &str,
+    ) -> Result<(), CommandError> {
+        let (recipient, to) = self.find_named(to)?;
+        let Some(sender_name) = self
+            .room_of(&sender)
+            .and_then(|room| room.get_name(&sender))
+        else {
+            warn!("Member sending a direct message had no name.");
+            return Ok(());
+        };
+        info!("[{} -> {}] {}", sender_name, to, text);
+        self.outbox.send(
+            recipient,
+            Room::create_direct_message(&sender_name, &to, text),
+        );
+        if let Some(presence) = self.presence_of_mut(&sender) {
+            presence.touch();
+        }
+        if let Some(reason) = self
+            .presence_of(&recipient)
+            .and_then(|presence| presence.away.clone())
+        {
+            self.reply(sender, &format!("{} is away: {}", to, reason));
+        }
+        Ok(())
+    }
+
+    /// Look up a named member across every room.
+    pub fn find_member(&self, member_name: &str) -> Option<MemberID> {
+        self.rooms
+            .values()
+            .find_map(|room| room.find_member(member_name))
+    }
+
+    /// Look up a named member across every room, along with their name as they picked it,
+    /// which might not be in the case it was asked for.
+    fn find_named(&self, member_name: &str) -> Result<(MemberID, String), CommandError> {
+        self.find_member(member_name)
+            .and_then(|member_id| Some((member_id, self.name_of(&member_id)?)))
+            .ok_or_else(|| CommandError::NoSuchMember(member_name.to_string()))
+    }
+
+    fn presence_of(&self, member_id: &MemberID) -> Option<&Presence> {
+        self.room_of(member_id)?.presence(member_id)
+    }
+
+    fn presence_of_mut(&mut self, member_id: &MemberID) -> Option<&mut Presence> {
+        let room_name = self.member_rooms.get(member_id)?;
+        self.rooms.get_mut(room_name)?.presence_mut(member_id)
+    }
+
+    /// Mark a member as away for the given reason, or as back. Returns whether they were away.
+    fn set_away(&mut self, member_id: MemberID, reason: Option<String>) -> Option<String> {
+        let presence = self.presence_of_mut(&member_id)?;
+        std::mem::replace(&mut presence.away, reason)
+    }
+
+    /// Everyone in a room and how long they've been idle, e.g.
+    ///
+    /// `In lobby: alice (idle 5s), bob (away: lunch, idle 10m), zed (linked server)`
+    fn who(&self, room_name: &str) -> Message {
+        let Some(room) = self.rooms.get(room_name) else {
+            return format!("In {}: nobody", room_name);
+        };
+        let mut members: Vec<_> = room
+            .members_with_presence()
+            .into_iter()
+            .map(|(name, presence)| {
+                let idle = describe_idle(presence.idle());
+                match &presence.away {
+                    Some(reason) => format!("{} (away: {}, idle {})", name, reason, idle),
+                    None => format!("{} (idle {})", name, idle),
+                }
+            })
+            .collect();
+        members.extend(
+            room.remote_member_names()
+                .map(|name| format!("{} (linked server)", name)),
+        );
+        members.extend(room.bot_names().map(bot::marked));
+        format!("In {}: {}", room.name(), members.join(", "))
+    }
+
+    /// Everything we know about how present a member is, e.g.
+    ///
+    /// `alice is in lobby, joined 2023-08-05T12:00:00Z, last message 2023-08-05T12:03:10Z, idle 5s`
+    fn whois(&self, name: &str) -> Result<Message, CommandError> {
+        if let Some(room_name) = self.bots.room_of(name) {
+            return Ok(format!("{} is a bot, in {}", name, room_name));
+        }
+        let Some(member_id) = self.find_member(name) else {
+            return self
+                .rooms
+                .values()
+                .find(|room| {
+                    room.remote_member_names()
+                        .any(|remote| remote.eq_ignore_ascii_case(name))
+                })
+                .map(|room| format!("{} is on a linked server, in {}", name, room.name()))
+                .ok_or_else(|| CommandError::NoSuchMember(name.to_string()));
+        };
+        let (Some(room), Some(presence), Some(name)) = (
+            self.room_of(&member_id),
+            self.presence_of(&member_id),
+            self.name_of(&member_id),
+        ) else {
+            return Err(CommandError::NoSuchMember(name.to_string()));
+        };
+        let last_message = presence
+            .last_message_at
+            .map(|at| humantime::format_rfc3339_seconds(at).to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let mut whois = format!(
+            "{} is in {}, joined {}, last message {}, idle {}",
+            name,
+            room.name(),
+            humantime::format_rfc3339_seconds(presence.joined_at),
+            last_message,
+            describe_idle(presence.idle()),
+        );
+        if let Some(reason) = &presence.away {
+            whois.push_str(&format!(", away: {}", reason));
+        }
+        Ok(whois)
+    }
+
+    /// Every room and how many members are in it, e.g.
+    ///
+    /// `Rooms: games (2), lobby (5)`
+    fn list_rooms(&self) -> Message {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .values()
+            .map(|room| format!("{} ({})", room.name(), room.len()))
+            .collect();
+        rooms.sort();
+        format!("Rooms: {}", rooms.join(", "))
+    }
+
+    /// Tell every linked server about something a member here did, if we're linked at all.
+    fn publish(&mut self, member_name: &str, kind: EventKind) {
+        if let Some(federation) = self.federation.as_mut() {
+            federation.publish(member_name, kind);
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn handle_link_event(&mut self, link_event: LinkEvent) {
+        let Some(federation) = self.federation.as_mut() else {
+            return;
+        };
+        match link_event {
+            LinkEvent::Up {
+                link,
+                server,
+                events,
+            } => {
+                if federation.link_up(link, &server, events) {
+                    self.catch_up(link);
+                }
+            }
+            LinkEvent::Received { link, event } => {
+                if !federation.is_new(&event) {
+                    return;
+                }
+                federation.forward(Some(link), &event);
+                // Our own members are ours to keep track of, whatever anyone else says.
+                if event.server == federation.server_name() {
+                    return;
+                }
+                let member = RemoteMember::from(&event);
+                match event.kind {
+                    EventKind::Join { room } => self.remote_join(member, link, &room),
+                    EventKind::Quit => self.remote_quit(&member),
+                    EventKind::Chat { room, text } => self.remote_chat(&member, &room, &text),
+                }
+            }
+            LinkEvent::Down { link } => self.netsplit(link),
+        }
+    }
+
+    /// Tell a server we just linked with about every member we know of, here and on
+    /// any other server we're linked with.
+    fn catch_up(&mut self, link: LinkID) {
+        let local: Vec<_> = self
+            .member_rooms
+            .iter()
+            .filter_map(|(member_id, room)| Some((self.name_of(member_id)?, room.clone())))
+            .chain(
+                self.bots
+                    .iter()
+                    .map(|(name, room)| (name.to_string(), room.to_string())),
+            )
+            .collect();
+        let Some(federation) = self.federation.as_mut() else {
+            return;
+        };
+        let server = federation.server_name().to_string();
+        let remote = federation
+            .members_not_via(link)
+            .into_iter()
+            .map(|(member, room)| (member.server, member.name, room));
+        let everyone: Vec<_> = local
+            .into_iter()
+            .map(|(name, room)| (server.clone(), name, room))
+            .chain(remote)
+            .collect();
+        for (server, name, room) in everyone {
+            let event = federation.event(&server, &name, EventKind::Join { room });
+            federation.send_to(link, event);
+        }
+    }
+
+    /// A member on a linked server is now in the given room.
+    fn remote_join(&mut self, member: RemoteMember, via: LinkID, room_name: &str) {
+        if !is_room_name_valid(room_name) {
+            warn!(room = %room_name, "Linked server sent a room name we'd never allow. Ignoring it.");
+            return;
+        }
+        let Some(federation) = self.federation.as_mut() else {
+            return;
+        };
+        let (member_name, previous_room) = federation.join(member, via, room_name);
+        if previous_room.as_deref() == Some(room_name) {
+            return;
+        }
+        if let Some(previous_room) = previous_room {
+            self.remote_leave(&member_name, &previous_room);
+        }
+        self.room_mut(room_name).enter_remote(&member_name);
+        self.write_to_chat_log(room_name, &member_name, ChatEventKind::Join);
+        self.bots.tell(
+            room_name,
+            BotEvent::Join {
+                member: member_name,
+            },
+            None,
+        );
+    }
+
+    /// A member on a linked server left their server, or we can't reach it anymore.
+    fn remote_quit(&mut self, member: &RemoteMember) {
+        let Some((member_name, room_name)) = self
+            .federation
+            .as_mut()
+            .and_then(|federation| federation.quit(member))
+        else {
+            return;
+        };
+        self.remote_leave(&member_name, &room_name);
+    }
+
+    fn remote_leave(&mut self, member_name: &str, room_name: &str) {
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+        if room.leave_remote(member_name) {
+            self.write_to_chat_log(room_name, member_name, ChatEventKind::Leave);
+            self.bots.tell(
+                room_name,
+                BotEvent::Leave {
+                    member: member_name.to_string(),
+                },
+                None,
+            );
+        }
+        self.drop_room_if_unused(room_name);
+    }
+
+    /// A member on a linked server said something in the given room.
+    fn remote_chat(&mut self, member: &RemoteMember, room_name: &str, text: &str) {
+        let Some((member_name, _)) = self
+            .federation
+            .as_ref()
+            .and_then(|federation| federation.find(member))
+        else {
+            debug!(
+                ?member,
+                "Chat from a remote member we don't know of. Dropping it."
+            );
+            return;
+        };
+        let member_name = member_name.to_string();
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            debug!(room = %room_name, "Chat for a room nobody's in. Dropping it.");
+            return;
+        };
+        info!(room = %room_name, "[{}] {}", member_name, text);
+        room.broadcast_remote_message(&member_name, text);
+        self.write_to_chat_log(
+            room_name,
+            &member_name,
+            ChatEventKind::Chat {
+                text: text.to_string(),
+            },
+        );
+        self.bots.tell(
+            room_name,
+            BotEvent::Message {
+                member: member_name,
+                text: text.to_string(),
+            },
+            None,
+        );
+    }
+
+    /// A link dropped, so everyone we heard of through it is gone as far as we can tell.
+    /// Tell the members in the rooms they were in, and the rest of our linked servers.
+    #[tracing::instrument(skip(self))]
+    fn netsplit(&mut self, link: LinkID) {
+        let Some(federation) = self.federation.as_mut() else {
+            return;
+        };
+        let Some(server) = federation.link_down(link) else {
+            return;
+        };
+        let lost = federation.members_via(link);
+        let rooms: HashSet<_> = lost
+            .iter()
+            .filter_map(|member| federation.find(member).map(|(_, room)| room.to_string()))
+            .collect();
+        for room_name in rooms {
+            if let Some(room) = self.rooms.get(&room_name) {
+                room.announce(&format!("* Netsplit: lost the link to {}", server));
+            }
+        }
+        for member in lost {
+            self.remote_quit(&member);
+            if let Some(federation) = self.federation.as_mut() {
+                let event = federation.event(&member.server, &member.name, EventKind::Quit);
+                federation.forward(None, &event);
+            }
+        }
+    }
+
+    /// Sit every bot in its room, as if it just joined.
+    fn seat_bots(&mut self) {
+        let bots: Vec<_> = self
+            .bots
+            .iter()
+            .map(|(name, room)| (name.to_string(), room.to_string()))
+            .collect();
+        for (bot_name, room_name) in bots {
+            self.room_mut(&room_name).enter_bot(&bot_name);
+            self.write_to_chat_log(&room_name, &bot_name, ChatEventKind::Join);
+            self.publish(&bot_name, EventKind::Join { room: room_name });
+        }
+    }
+
+    /// Pass something a bot said to everyone else in its room, bots included.
+    #[tracing::instrument(skip(self))]
+    fn bot_post(&mut self, post: Post) {
+        let Some(room_name) = self.bots.room_of(&post.bot).map(str::to_string) else {
+            warn!("Received a post from a bot we don't know of. Dropping it.");
+            return;
+        };
+        let text = post.text.trim().to_string();
+        info!(room = %room_name, "[{}] {}", post.bot, text);
+        self.room_mut(&room_name)
+            .broadcast_bot_message(&post.bot, &text);
+        self.write_to_chat_log(
+            &room_name,
+            &post.bot,
+            ChatEventKind::Chat { text: text.clone() },
+        );
+        self.bots.tell(
+            &room_name,
+            BotEvent::Message {
+                member: post.bot.clone(),
+                text: text.clone(),
+            },
+            Some(&post.bot),
+        );
+        self.publish(
+            &post.bot,
+            EventKind::Chat {
+                room: room_name,
+                text,
+            },
+        );
+    }
+
+    /// Tell a single member something, as the server.
+    fn reply(&self, member_id: MemberID, message: &str) {
+        self.outbox.send(member_id, format!("* {}", message));
+    }
+}
+
+/// How long something lasts, as part of a sentence, e.g. ` for 10m`.
+fn describe_duration(duration: Option<Duration>) -> String {
+    duration
+        .map(|duration| format!(" for {}", humantime::format_duration(duration)))
+        .unwrap_or_default()
+}
+
+/// How long someone's been idle, to the second, e.g. `1m 5s`.
+fn describe_idle(idle: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(idle.as_secs())).to_string()
+}
+
+/// Check if a room name is a non-empty string of ascii alphanumerics, dashes and underscores,
+/// and isn't too long.
+pub fn is_room_name_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= RoomRegistry::MAX_ROOM_NAME_LENGTH
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+}
+
+/// Wait for the next thing to happen on any link, or forever if we aren't linked at all.
+async fn next_link_event(federation: &mut Option<Federation>) -> Option<LinkEvent> {
+    match federation {
+        Some(federation) => federation.next().await,
+        None => std::future::pending().await,
+    }
+}
@@ -1,54 +1,238 @@
-use crate::MemberID;
-use std::collections::HashMap;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
+use crate::{
+    bot,
+    history::{History, HistoryConfig},
+    outbox::{Outbound, SessionHandle},
+    MemberID,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, error, info};
 
 pub type Message = String;
 
-/// An actor for the ChatRoom that handles all the business logic of the messages to broadcast
-/// inside of our budget chatroom.
+/// Who else is in a room, as told to a member who just entered it:
+///
+/// `* The room contains: {name}, {bot} (bot)`
+///
+/// Transports that have their own way of saying so get the room and names as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomListing {
+    /// The room the member just entered.
+    pub room: String,
+    /// Everybody else in the room, wherever they're connected.
+    pub members: Vec<String>,
+    /// Bots in the room, without the marker they're listed with.
+    pub bots: Vec<String>,
+}
+
+impl fmt::Display for RoomListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self
+            .members
+            .iter()
+            .cloned()
+            .chain(self.bots.iter().map(|name| bot::marked(name)))
+            .collect::<Vec<_>>();
+        write!(f, "* The room contains: {}", names.join(", "))
+    }
+}
+
+/// How present a member is: when they got here, when they last said anything, and whether
+/// they said they're away. This follows members from room to room.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    /// When the member made it through staging.
+    pub joined_at: SystemTime,
+    /// When they last said anything, to a room or to anyone directly.
+    pub last_message_at: Option<SystemTime>,
+    /// When they last did anything at all, for telling how long they've been idle.
+    last_active: Instant,
+    /// Why they're away, if they said they are.
+    pub away: Option<String>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self {
+            joined_at: SystemTime::now(),
+            last_message_at: None,
+            last_active: Instant::now(),
+            away: None,
+        }
+    }
+
+    /// How long it's been since the member said anything, or got here if they haven't yet.
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Remember the member just said something.
+    pub fn touch(&mut self) {
+        self.last_message_at = Some(SystemTime::now());
+        self.last_active = Instant::now();
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A single chat room that handles all the business logic of the messages to broadcast
+/// between its members. Which member is in which room is up to the [RoomRegistry](crate::registry::RoomRegistry).
 #[derive(Debug)]
 pub struct Room {
-    /// A source of truth for currently active members and their names.
+    /// What members call this room.
+    name: String,
+
+    /// A source of truth for currently active members of this room and their names.
     ///
-    /// *Note*: This could lag arbitrarily behind the actual connection map (SocketAddr => UnboundedSender<Message>)
+    /// *Note*: This could lag arbitrarily behind the members' sessions,
     /// since the Room only ever contains members who were successfully named and made it through the staging area.
     ///
     members: HashMap<MemberID, String>,
 
-    /// Someone will let us know when we receive a message from a member.
-    message_received_from_member: mpsc::UnboundedReceiver<(MemberID, Message)>,
+    /// How to reach each member of this room.
+    sessions: HashMap<MemberID, SessionHandle>,
+
+    /// How present each member of this room is.
+    presence: HashMap<MemberID, Presence>,
 
-    /// We'll tell transport what message to send and to which member.
-    send_to_member: mpsc::UnboundedSender<(MemberID, Message)>,
+    /// Names of the members of this room who are on linked servers, as they're called here.
+    remote_members: BTreeSet<String>,
 
-    /// Someone will let us know when a client disconnects.
-    client_disconnected_rx: mpsc::UnboundedReceiver<MemberID>,
+    /// Names of the [bots](crate::bot::Bot) sitting in this room.
+    bots: BTreeSet<String>,
 
-    /// Someone will let us know when a client connects with a given name.
-    client_connected_with_name_rx: mpsc::UnboundedReceiver<(MemberID, String)>,
+    /// The most recent chat messages, replayed to members as they enter.
+    history: History,
 }
 
 impl Room {
-    #[tracing::instrument(skip_all)]
-    pub fn new(
-        message_received_from_member: mpsc::UnboundedReceiver<(MemberID, Message)>,
-        send_to_member: mpsc::UnboundedSender<(MemberID, Message)>,
-        client_disconnected_rx: mpsc::UnboundedReceiver<MemberID>,
-        client_connected_with_name_rx: mpsc::UnboundedReceiver<(MemberID, String)>,
-    ) -> Self {
+    #[tracing::instrument]
+    pub fn new(name: &str) -> Self {
         Self {
-            message_received_from_member,
-            send_to_member,
-            client_disconnected_rx,
-            client_connected_with_name_rx,
+            name: name.to_string(),
             members: Default::default(),
+            sessions: Default::default(),
+            presence: Default::default(),
+            remote_members: Default::default(),
+            bots: Default::default(),
+            history: Default::default(),
+        }
+    }
+
+    /// Keep a bounded history of what's said in this room, for members who join later.
+    pub fn with_history(mut self, config: HistoryConfig) -> Self {
+        self.history = History::new(config);
+        self
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of members in this room, wherever they're connected, bots included.
+    pub fn len(&self) -> usize {
+        self.members.len() + self.remote_members.len() + self.bots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.remote_members.is_empty() && self.bots.is_empty()
+    }
+
+    /// Let a named member into the room, tell everyone else about them,
+    /// and tell them about everyone else.
+    #[tracing::instrument(skip(self, session), fields(room = %self.name))]
+    pub fn enter(&mut self, member_id: MemberID, member_name: &str, session: SessionHandle) {
+        info!("* {} has entered the room.", member_name);
+        self.add_member(member_id, member_name, session);
+        info!(
+            "* The room contains: {}, {}",
+            member_name,
+            self.member_names_except(&member_id).join(", ")
+        );
+        self.notify_others_of_new_member(member_id);
+        self.notify_member_of_other_members(member_id);
+        self.replay_history_to(member_id);
+        debug!("After adding member and notifying: {:#?}", self.members);
+    }
+
+    /// Let a member out of the room, and tell everyone else about it.
+    /// Returns the name of the member, if they were in the room.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn leave(&mut self, member_id: MemberID) -> Option<String> {
+        self.notify_others_of_disconnection(member_id);
+        let name = self.remove_member(member_id);
+        info!("* {:?} has left the room", name);
+        name
+    }
+
+    /// Let a member on a linked server into the room, and tell everyone here about them.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn enter_remote(&mut self, member_name: &str) {
+        info!(
+            "* {} has entered the room from a linked server.",
+            member_name
+        );
+        self.announce(&format!("* {} has entered the room", member_name));
+        self.remote_members.insert(member_name.to_string());
+    }
+
+    /// Let a member on a linked server out of the room, and tell everyone here about it.
+    /// Returns whether they were in the room.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn leave_remote(&mut self, member_name: &str) -> bool {
+        if !self.remote_members.remove(member_name) {
+            return false;
         }
+        info!("* {} has left the room from a linked server.", member_name);
+        self.announce(&format!("* {} has left the room", member_name));
+        true
+    }
+
+    /// Pass a chat message from a member on a linked server to everyone here,
+    /// and remember it for anyone who joins later.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn broadcast_remote_message(&mut self, member_name: &str, message: &str) {
+        self.broadcast_from(member_name, message);
+    }
+
+    /// Sit a bot in the room, and tell everyone here about it.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn enter_bot(&mut self, bot_name: &str) {
+        info!("* {} has entered the room as a bot.", bot_name);
+        self.announce(&format!("* {} has entered the room", bot_name));
+        self.bots.insert(bot_name.to_string());
+    }
+
+    /// Pass something a bot said to everyone here, and remember it for anyone who joins later.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn broadcast_bot_message(&mut self, bot_name: &str, message: &str) {
+        self.broadcast_from(bot_name, message);
+    }
+
+    fn broadcast_from(&mut self, member_name: &str, message: &str) {
+        let message = Self::format_message(member_name, message);
+        self.announce(&message);
+        self.history.record(&message);
     }
 
     /// Given the member_id just disconnected, send messages to others about it.
     #[tracing::instrument(skip(self))]
-    pub async fn notify_others_of_disconnection(&self, disconnected_member: MemberID) {
+    pub fn notify_others_of_disconnection(&self, disconnected_member: MemberID) {
         let Some(disconnected_member_name) = self.get_name(&disconnected_member) else {
             error!("Don't know the name of the member who just disconnected");
             return;
@@ -58,12 +242,10 @@ impl Room {
             .keys()
             .filter(|&member_id| *member_id != disconnected_member)
             .for_each(|member_id| {
-                self.send_to_member
-                    .send((
-                        *member_id,
-                        format!("* {} has left the room", disconnected_member_name),
-                    ))
-                    .unwrap();
+                self.send(
+                    *member_id,
+                    format!("* {} has left the room", disconnected_member_name),
+                );
             });
     }
 
@@ -90,12 +272,10 @@ impl Room {
         );
 
         others.into_iter().for_each(|member_id| {
-            self.send_to_member
-                .send((
-                    member_id,
-                    format!("* {} has entered the room", connected_member_name),
-                ))
-                .unwrap();
+            self.send(
+                member_id,
+                format!("* {} has entered the room", connected_member_name),
+            );
         });
     }
 
@@ -103,75 +283,53 @@ impl Room {
     /// in the room.
     #[tracing::instrument(skip(self))]
     pub fn notify_member_of_other_members(&self, newly_connected_member: MemberID) {
-        let existing_member_names: Vec<_> = self
-            .members
-            .keys()
-            .filter(|&member_id| *member_id != newly_connected_member)
-            .filter_map(|member_id| self.get_name(member_id))
-            .collect();
-        let message = format!("* The room contains: {}", existing_member_names.join(", "));
+        let listing = RoomListing {
+            room: self.name.clone(),
+            members: self
+                .members
+                .keys()
+                .filter(|&member_id| *member_id != newly_connected_member)
+                .filter_map(|member_id| self.get_name(member_id))
+                .chain(self.remote_members.iter().cloned())
+                .collect(),
+            bots: self.bots.iter().cloned().collect(),
+        };
 
         debug!(
-            existing_member_names = ?existing_member_names,
+            listing = ?listing,
             newly_connected_member = %newly_connected_member,
             newly_connected_member_name = ?(self.get_name(&newly_connected_member)),
-            message = %message,
             "Notifying connected member of the existing members",
         );
-        self.send_to_member
-            .send((newly_connected_member, message))
-            .unwrap();
+        self.send(newly_connected_member, listing);
     }
 
-    /// Drive the chat room by listening for any inbound/outbound messages
-    /// to/from our chat room as well as any new members connecting and old ones leaving.
+    /// Given a member just entered the room, catch them up on what was said recently.
+    /// Every replayed line is marked, so nobody mistakes it for something said just now:
     ///
-    #[tracing::instrument(skip(self), fields(self.members = ?self.members))]
-    pub async fn run(&mut self) {
-        info!("Starting our budget chat room...");
-        loop {
-            tokio::select! {
-                Some((new_member, new_member_name)) = self.client_connected_with_name_rx.recv() => {
-                    debug!("Client {} connected with name: {}", new_member, new_member_name);
-                    info!("* {} has entered the room.", new_member_name);
-                    self.add_member(new_member, &new_member_name);
-                    info!(
-                        "* The room contains: {}, {}",
-                        new_member_name,
-                        self.member_names_except(&new_member).join(", ")
-                    );
-                    self.notify_others_of_new_member(new_member);
-                    self.notify_member_of_other_members(new_member);
-                    debug!("After adding member and notifying: {:#?}", self.members);
-                },
-                Some(disconnected_member) = self.client_disconnected_rx.recv() => {
-                    self.notify_others_of_disconnection(disconnected_member).await;
-                    self.remove_member(disconnected_member);
-                    debug!("Recvd client_disconnected");
-                    info!(
-                        "* {:?} has left the room",
-                        self.get_name(&disconnected_member)
-                    );
-                },
-                Some((sender, msg)) = self.message_received_from_member.recv() => {
-                    info!(
-                        "[{:?}] {}",
-                        self.get_name(&sender),
-                        msg
-                    );
-                    debug!("Received message from sender that will be broadcasted to others.");
-                    self.broadcast_message_to_other_members_except(&sender, &msg);
-                }
-            }
-        }
+    /// `* [history] [{user}] {message}`
+    #[tracing::instrument(skip(self))]
+    pub fn replay_history_to(&self, member_id: MemberID) {
+        self.history.recent().for_each(|entry| {
+            self.send(member_id, format!("* [history] {}", entry.message));
+        });
+    }
+
+    /// Tell every member of the room something, as the server.
+    #[tracing::instrument(skip(self), fields(room = %self.name))]
+    pub fn announce(&self, message: &str) {
+        self.members.keys().for_each(|member_id| {
+            self.send(*member_id, message.to_string());
+        });
     }
 
-    /// Broadcast a given message to all active members of the room except the provided member.
+    /// Broadcast a given message to all active members of the room except the provided member,
+    /// and remember it for anyone who joins later.
     #[tracing::instrument(skip(self))]
     pub fn broadcast_message_to_other_members_except(
-        &self,
+        &mut self,
         except_member_id: &MemberID,
-        message: &Message,
+        message: &str,
     ) {
         let others: Vec<_> = self
             .members
@@ -191,36 +349,83 @@ impl Room {
             if let Some(message_prefixed) =
                 self.create_message_from_member(except_member_id, message)
             {
-                self.send_to_member
-                    .send((member_id, message_prefixed))
-                    .unwrap();
+                self.send(member_id, message_prefixed);
             }
         });
+        if let Some(message_prefixed) = self.create_message_from_member(except_member_id, message) {
+            self.history.record(&message_prefixed);
+        }
     }
 
-    /// Format a raw message as the following:
-    ///
-    /// `[{user}] {message}`
+    /// Format a raw message from a member of this room, as in [Room::format_message].
     #[tracing::instrument(skip(self))]
     pub fn create_message_from_member(
         &self,
         member_id: &MemberID,
-        message: &Message,
+        message: &str,
     ) -> Option<Message> {
         self.get_name(member_id)
-            .map(|member_name| format!("[{}] {}", member_name, message.as_str().trim()))
+            .map(|member_name| Self::format_message(&member_name, message))
     }
 
-    /// Add a member to the chat room.
-    #[tracing::instrument(skip(self))]
-    pub fn add_member(&mut self, member_id: MemberID, member_name: &str) {
+    /// Format a raw message from the member with the given name as the following:
+    ///
+    /// `[{user}] {message}`
+    pub fn format_message(member_name: &str, message: &str) -> Message {
+        format!("[{}] {}", member_name, message.trim())
+    }
+
+    /// Add a member to the chat room, along with how to reach them.
+    #[tracing::instrument(skip(self, session))]
+    pub fn add_member(&mut self, member_id: MemberID, member_name: &str, session: SessionHandle) {
         self.members.insert(member_id, member_name.to_string());
+        self.sessions.insert(member_id, session);
+        self.presence.entry(member_id).or_default();
     }
 
-    /// Remove a member from the chat room.
+    /// Remove a member from the chat room, and get their name back.
     #[tracing::instrument(skip(self))]
-    pub fn remove_member(&mut self, member_id: MemberID) {
-        self.members.remove(&member_id);
+    pub fn remove_member(&mut self, member_id: MemberID) -> Option<String> {
+        self.presence.remove(&member_id);
+        self.sessions.remove(&member_id);
+        self.members.remove(&member_id)
+    }
+
+    /// How present a member of the room is.
+    pub fn presence(&self, member_id: &MemberID) -> Option<&Presence> {
+        self.presence.get(member_id)
+    }
+
+    pub fn presence_mut(&mut self, member_id: &MemberID) -> Option<&mut Presence> {
+        self.presence.get_mut(member_id)
+    }
+
+    /// Carry over how present a member was from the room they just left.
+    pub fn set_presence(&mut self, member_id: MemberID, presence: Presence) {
+        if self.members.contains_key(&member_id) {
+            self.presence.insert(member_id, presence);
+        }
+    }
+
+    /// Every member here, along with how present they are, sorted by name.
+    pub fn members_with_presence(&self) -> Vec<(&str, &Presence)> {
+        let mut members: Vec<_> = self
+            .members
+            .iter()
+            .filter_map(|(member_id, name)| Some((name.as_str(), self.presence.get(member_id)?)))
+            .collect();
+        members.sort_by_key(|(name, _)| *name);
+        members
+    }
+
+    /// Names of the members of this room on linked servers, sorted.
+    pub fn remote_member_names(&self) -> impl Iterator<Item = &str> {
+        self.remote_members.iter().map(String::as_str)
+    }
+
+    /// Names of the bots sitting in this room, sorted.
+    pub fn bot_names(&self) -> impl Iterator<Item = &str> {
+        self.bots.iter().map(String::as_str)
     }
 
     /// Get the name of a member in the room, if possible.
@@ -229,6 +434,34 @@ impl Room {
         self.members.get(member_id).cloned()
     }
 
+    /// Look up a member in the room by their name. Names that only differ in case are
+    /// the same name, same as when they're claimed.
+    #[tracing::instrument(skip(self))]
+    pub fn find_member(&self, member_name: &str) -> Option<MemberID> {
+        self.members
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(member_name))
+            .map(|(member_id, _)| *member_id)
+    }
+
+    /// Format a raw message sent privately from one member to another as the following:
+    ///
+    /// `[{sender} -> {recipient}] {message}`
+    pub fn create_direct_message(
+        sender_name: &str,
+        recipient_name: &str,
+        message: &str,
+    ) -> Message {
+        format!("[{} -> {}] {}", sender_name, recipient_name, message.trim())
+    }
+
+    /// Queue a message for a member of this room.
+    fn send(&self, member_id: MemberID, message: impl Into<Outbound>) {
+        if let Some(session) = self.sessions.get(&member_id) {
+            session.send(message);
+        }
+    }
+
     /// Get all the members except for the given one.
     fn members_except(&self, member_id: &MemberID) -> Vec<(MemberID, String)> {
         self.members
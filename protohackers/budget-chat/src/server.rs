@@ -0,0 +1,696 @@
+use crate::{
+    accounts::Accounts,
+    bot::{Bot, BotConfig, Bots},
+    chat_log::ChatLog,
+    command::Line,
+    federation::{self, Federation, FederationConfig, LinkIDs},
+    flood::FloodPolicy,
+    history::HistoryConfig,
+    irc,
+    moderation::{BanTarget, Bans, Operators},
+    names::{is_name_valid, NamePolicy, Names},
+    outbox::{Backlog, OutboundReceiver, Outbox, OutboxConfig, SessionHandle},
+    registry::RoomRegistry,
+    room::Message,
+    session::Session,
+    shutdown::{ShutdownConfig, ShutdownHandle},
+    websocket, BudgetChatError, ClientInitializationError, MemberID, CHANNEL_CAPACITY,
+};
+use std::{future::Future, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc,
+    task::JoinSet,
+};
+use tracing::{debug, error, info, trace, warn};
+
+/// Everything about how the chat behaves that isn't baked into the protocol.
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+    pub history: HistoryConfig,
+    pub chat_log: Option<ChatLog>,
+    pub names: NamePolicy,
+    pub flood_policy: Option<FloodPolicy>,
+    pub outbox: OutboxConfig,
+    pub operators: Operators,
+    pub bans: Bans,
+    pub accounts: Option<Accounts>,
+    pub shutdown: ShutdownConfig,
+    /// Treat every line raw TCP members send as chat, as the protocol says,
+    /// so none of them are ever taken for commands.
+    pub strict_protocol: bool,
+    /// Bots that come with the server, to sit in rooms from the moment it starts.
+    pub bots: Vec<BotConfig>,
+}
+
+/// Everything staging needs to decide who gets in, and under what name.
+#[derive(Debug, Clone, Default)]
+pub struct Staging {
+    pub names: Names,
+    pub bans: Bans,
+    pub accounts: Option<Accounts>,
+}
+
+impl Staging {
+    /// What every peer is greeted with, before they've said anything.
+    pub const WELCOME: &'static str = "Welcome to budgetchat! What shall I call you?";
+
+    /// What a peer who picked a registered name is asked next.
+    pub const PASSWORD_PROMPT: &'static str = "That name is registered. What's the password?";
+
+    /// What a peer who picked a bad name is told, if the [NamePolicy] lets them pick another.
+    pub fn reprompt(err: &ClientInitializationError) -> String {
+        format!("{}. What shall I call you?", err)
+    }
+
+    /// Start staging a peer, unless their address is banned, in which case they should be
+    /// told so before they're hung up on.
+    pub fn start(&self, addr: &MemberID) -> Result<Stage<'_>, ClientInitializationError> {
+        let ip = BanTarget::Ip(addr.ip());
+        if self.bans.is_banned(&ip) {
+            warn!("Peer's address is banned. Disconnecting.");
+            return Err(ClientInitializationError::Banned(ip.to_string()));
+        }
+        Ok(Stage {
+            staging: self,
+            name: None,
+            needs_password: false,
+            wrong_passwords: 0,
+        })
+    }
+
+    /// Claim the name a peer asked for, as long as the [NamePolicy] allows it,
+    /// nobody banned it, and it's registered if guests aren't welcome.
+    fn claim(&self, raw: &str) -> Result<String, ClientInitializationError> {
+        let name = self.names.claim(raw)?;
+        let banned_name = BanTarget::Name(name.clone());
+        if self.bans.is_banned(&banned_name) {
+            self.names.release(&name);
+            return Err(ClientInitializationError::Banned(banned_name.to_string()));
+        }
+        if let Some(accounts) = &self.accounts {
+            if !accounts.allows_guests() && !accounts.is_registered(&name) {
+                self.names.release(&name);
+                return Err(ClientInitializationError::GuestsNotAllowed(name));
+            }
+        }
+        Ok(name)
+    }
+
+    /// Whether a peer has to give the password for a name they claimed before they get it.
+    fn needs_password(&self, name: &str) -> bool {
+        self.accounts
+            .as_ref()
+            .is_some_and(|accounts| accounts.is_registered(name))
+    }
+
+    /// Check the password for a registered name a peer claimed, letting go of the name
+    /// if it's wrong.
+    async fn log_in(
+        &self,
+        name: String,
+        password: String,
+    ) -> Result<String, ClientInitializationError> {
+        let accounts = self.accounts.clone().unwrap_or_default();
+        let checked = {
+            let name = name.clone();
+            tokio::task::spawn_blocking(move || accounts.check_password(&name, &password))
+        };
+        match checked.await {
+            Ok(true) => Ok(name),
+            _ => {
+                self.release(&name);
+                Err(ClientInitializationError::WrongPassword(name))
+            }
+        }
+    }
+
+    /// Let someone else have a name once its member has left.
+    pub fn release(&self, name: &str) {
+        self.names.release(name)
+    }
+}
+
+/// What a transport should do next with a peer in staging, as their [Stage] decided.
+#[derive(Debug)]
+pub enum Step {
+    /// The name they claimed is theirs, as far as staging goes. [Stage::admit] hands it over.
+    Admit,
+    /// They claimed a registered name, so ask for its password.
+    Password,
+    /// Tell them what was wrong, and ask for another name.
+    Retry(ClientInitializationError),
+    /// Hang up on them, telling them why first if `tell` says so.
+    Reject {
+        err: ClientInitializationError,
+        tell: bool,
+    },
+}
+
+/// A single peer's way through staging. It decides what comes of everything the peer says,
+/// so transports only have to say and hear things. Any name it's holding on to is let go of
+/// if the peer never makes it in.
+#[derive(Debug)]
+pub struct Stage<'a> {
+    staging: &'a Staging,
+    /// The name the peer claimed, if any.
+    name: Option<String>,
+    /// Whether the name is registered, and the peer still has to give its password.
+    needs_password: bool,
+    wrong_passwords: u32,
+}
+
+impl Stage<'_> {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Whether the next thing the peer says should be a password.
+    pub fn needs_password(&self) -> bool {
+        self.needs_password
+    }
+
+    /// Take the next thing the peer said: the password if they owe us one, and a name otherwise.
+    pub async fn answer(&mut self, line: &str) -> Step {
+        match (self.name.clone(), self.needs_password) {
+            (Some(name), true) => self.log_in(name, line.to_string()).await,
+            _ => self.claim(line),
+        }
+    }
+
+    /// Claim the name the peer asked for, letting go of any they claimed before once the new one
+    /// is theirs. Asking for the name they already have changes nothing.
+    pub fn claim(&mut self, raw: &str) -> Step {
+        if self.name.as_deref() != Some(raw) {
+            trace!(raw, "Claiming name.");
+            match self.staging.claim(raw) {
+                Ok(name) => {
+                    self.needs_password = self.staging.needs_password(&name);
+                    if let Some(previous) = self.name.replace(name) {
+                        self.staging.release(&previous);
+                    }
+                }
+                Err(err) => return self.turn_down(raw, err),
+            }
+        }
+        match self.needs_password {
+            true => {
+                trace!(raw, "Name is registered. Asking for the password.");
+                Step::Password
+            }
+            false => Step::Admit,
+        }
+    }
+
+    /// Check the password for the registered name the peer claimed. A wrong one costs them
+    /// the name, and counts as a bad name, until they've given too many of them.
+    async fn log_in(&mut self, name: String, password: String) -> Step {
+        match self.staging.log_in(name.clone(), password).await {
+            Ok(_) => {
+                self.needs_password = false;
+                Step::Admit
+            }
+            Err(err) => {
+                // Logging in lets go of the name when the password's wrong.
+                self.name = None;
+                self.needs_password = false;
+                self.wrong_passwords += 1;
+                let max_attempts = self
+                    .staging
+                    .accounts
+                    .as_ref()
+                    .map_or(Accounts::DEFAULT_MAX_ATTEMPTS, Accounts::max_attempts);
+                if self.wrong_passwords >= max_attempts {
+                    warn!(name, "Peer gave too many wrong passwords. Disconnecting.");
+                    return Step::Reject {
+                        err: ClientInitializationError::TooManyWrongPasswords,
+                        tell: true,
+                    };
+                }
+                self.turn_down(&name, err)
+            }
+        }
+    }
+
+    /// Turn down a bad name: banned ones get the peer disconnected after being told why,
+    /// and the rest get them asked for another or disconnected, as the [NamePolicy] says.
+    fn turn_down(&self, raw: &str, err: ClientInitializationError) -> Step {
+        match err {
+            ClientInitializationError::Banned(_) => {
+                warn!(raw, "Peer's name is banned. Disconnecting.");
+                Step::Reject { err, tell: true }
+            }
+            err if self.staging.names.policy.reprompt => {
+                debug!(raw, "Got bad name. Asking for another: {}", err);
+                Step::Retry(err)
+            }
+            err => {
+                warn!(raw, "Got bad name. Disconnecting: {}", err);
+                Step::Reject { err, tell: false }
+            }
+        }
+    }
+
+    /// Wait for the peer to say something with the given read. Peers who owe us a password
+    /// only get so long to give it, so they can't keep a registered name from its owner.
+    pub async fn wait_for<F: Future>(
+        &self,
+        read: F,
+    ) -> Result<F::Output, ClientInitializationError> {
+        let (Some(name), true, Some(accounts)) =
+            (&self.name, self.needs_password, &self.staging.accounts)
+        else {
+            return Ok(read.await);
+        };
+        tokio::time::timeout(accounts.password_timeout(), read)
+            .await
+            .map_err(|_| {
+                warn!(
+                    name,
+                    "Peer took too long to give the password. Disconnecting."
+                );
+                ClientInitializationError::PasswordTimeout(name.clone())
+            })
+    }
+
+    /// Hand over the name, once a [Step::Admit] said the peer gets it. It's theirs until they
+    /// leave, when [Gateway::disconnect] lets go of it.
+    pub fn admit(mut self) -> String {
+        self.name
+            .take()
+            .expect("Peers are only admitted under a name they claimed")
+    }
+}
+
+impl Drop for Stage<'_> {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            self.staging.release(&name);
+        }
+    }
+}
+
+/// The way into the chat rooms for any transport: hand members over once they're named,
+/// pass along what they say, and let the rooms know once they're gone.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    message_recvd_from_member_tx: mpsc::Sender<(MemberID, Message)>,
+    client_connected_with_name_tx: mpsc::Sender<(MemberID, String, SessionHandle)>,
+    client_disconnected_tx: mpsc::Sender<MemberID>,
+    outbox: OutboxConfig,
+    backlog: Backlog,
+    pub staging: Staging,
+    /// Every line members say is chat, even the ones that look like commands.
+    strict_protocol: bool,
+}
+
+impl Gateway {
+    /// Give a member who made it through staging a queue of their own, hand the chat rooms
+    /// a way to reach it, and let them into the lobby. Returns the end of the queue to write
+    /// to the member from.
+    ///
+    /// Fails only if the chat rooms are gone, in which case the member never got in,
+    /// and their name is free again.
+    pub async fn connect(&self, addr: MemberID, name: &str) -> crate::Result<OutboundReceiver> {
+        let (session, outbound) = SessionHandle::new(self.outbox, self.backlog.clone());
+        if self
+            .client_connected_with_name_tx
+            .send((addr, name.to_string(), session))
+            .await
+            .is_err()
+        {
+            self.staging.release(name);
+            return Err(BudgetChatError::RoomsGone);
+        }
+        Ok(outbound)
+    }
+
+    /// Pass a line a member said along to the rooms. Fails only if the chat rooms are gone.
+    pub async fn forward(&self, addr: MemberID, message: Message) -> crate::Result<()> {
+        let message = match self.strict_protocol {
+            true => Line::escape(&message),
+            false => message,
+        };
+        self.message_recvd_from_member_tx
+            .send((addr, message))
+            .await
+            .map_err(|_| BudgetChatError::RoomsGone)
+    }
+
+    /// Tell the chat rooms a member went away, so they stop writing to them,
+    /// and let someone else have the name.
+    pub async fn disconnect(&self, addr: MemberID, name: &str) {
+        if self.client_disconnected_tx.send(addr).await.is_err() {
+            debug!(peer = %addr, "The chat rooms are gone, so they can't be told the member left.");
+        }
+        self.staging.release(name);
+    }
+}
+
+/// A budget chat server, ready to serve members on a listener.
+#[derive(Debug)]
+pub struct BudgetChat {
+    listener: TcpListener,
+    irc_listener: Option<TcpListener>,
+    websocket_listener: Option<TcpListener>,
+    federation: Option<(FederationConfig, Option<TcpListener>)>,
+    config: ServerConfig,
+    backlog: Backlog,
+    staging: Staging,
+    shutdown: ShutdownHandle,
+    /// Every bot to start along with the server: its name, its room, and the bot itself.
+    bots: Vec<(String, String, Box<dyn Bot>)>,
+}
+
+impl BudgetChat {
+    pub fn new(listener: TcpListener, config: ServerConfig) -> Self {
+        let staging = Staging {
+            names: Names::new(config.names.clone()),
+            bans: config.bans.clone(),
+            accounts: config.accounts.clone(),
+        };
+        let bots = config
+            .bots
+            .iter()
+            .map(|bot| (bot.name.clone(), bot.room.clone(), bot.build()))
+            .collect();
+        Self {
+            listener,
+            irc_listener: None,
+            websocket_listener: None,
+            federation: None,
+            config,
+            backlog: Default::default(),
+            staging,
+            shutdown: Default::default(),
+            bots,
+        }
+    }
+
+    /// Sit a bot in the given room under the given name, from the moment the server starts.
+    pub fn with_bot(mut self, name: &str, room: &str, bot: impl Bot) -> Self {
+        self.bots
+            .push((name.to_string(), room.to_string(), Box::new(bot)));
+        self
+    }
+
+    /// Also let IRC clients in on another listener, into the same rooms.
+    pub fn with_irc(mut self, listener: TcpListener) -> Self {
+        self.irc_listener = Some(listener);
+        self
+    }
+
+    /// Also let browsers in on another listener, into the same rooms, and serve them a web client there.
+    pub fn with_websocket(mut self, listener: TcpListener) -> Self {
+        self.websocket_listener = Some(listener);
+        self
+    }
+
+    /// Share every room with linked servers, linking to the configured peers and, given a listener,
+    /// letting other servers link to us there.
+    pub fn with_federation(
+        mut self,
+        config: FederationConfig,
+        listener: Option<TcpListener>,
+    ) -> Self {
+        self.federation = Some((config, listener));
+        self
+    }
+
+    /// How much is queued up for members, to keep an eye on how much we're holding on to.
+    pub fn backlog(&self) -> &Backlog {
+        &self.backlog
+    }
+
+    /// A way to shut the server down once it's running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Wire up the chat rooms with the client loop, and serve members until we're asked to
+    /// shut down or the listener fails. Either way, every member is told we're shutting down
+    /// before we hang up on them.
+    pub async fn run(self) -> crate::Result<()> {
+        let (message_received_from_member_tx, message_received_from_member_rx) =
+            mpsc::channel(CHANNEL_CAPACITY);
+        let (client_disconnected_tx, client_disconnected_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (client_connected_with_name_tx, client_connected_with_name_rx) =
+            mpsc::channel(CHANNEL_CAPACITY);
+
+        // Setup our Chat Rooms with the appropriate channels.
+        let mut registry = RoomRegistry::new(
+            message_received_from_member_rx,
+            Outbox::new(self.backlog.clone()),
+            client_disconnected_rx,
+            client_connected_with_name_rx,
+        )
+        .with_history(self.config.history)
+        .with_flood_policy(self.config.flood_policy)
+        .with_moderation(self.config.operators, self.config.bans)
+        .with_shutdown(self.shutdown.clone(), self.config.shutdown);
+        if let Some(accounts) = self.config.accounts {
+            registry = registry.with_accounts(accounts);
+        }
+        if let Some(chat_log) = self.config.chat_log {
+            let events = ChatLog::read_all(chat_log.path())?;
+            registry = registry.with_chat_log(chat_log);
+            registry.rebuild_history(&events);
+        }
+        // Bots get their names before anyone else can ask for them.
+        let mut bots = Bots::default();
+        for (name, room, bot) in self.bots {
+            let Some(name) = is_name_valid(&name) else {
+                return Err(ClientInitializationError::InvalidName(name).into());
+            };
+            self.staging.names.claimed.claim(name)?;
+            bots.spawn(name, &room, bot);
+        }
+        registry = registry.with_bots(bots);
+        // Everything that lets anyone in, so it can all be stopped once we're shutting down.
+        let mut listeners = JoinSet::new();
+        if let Some((federation_config, federation_listener)) = self.federation {
+            let (link_events_tx, link_events_rx) = mpsc::channel(CHANNEL_CAPACITY);
+            registry = registry.with_federation(Federation::new(
+                &federation_config.server_name,
+                link_events_rx,
+                self.staging.names.claimed.clone(),
+            ));
+            let federation_config = Arc::new(federation_config);
+            let link_ids = LinkIDs::default();
+            if let Some(listener) = federation_listener {
+                let (config, link_events, link_ids) = (
+                    federation_config.clone(),
+                    link_events_tx.clone(),
+                    link_ids.clone(),
+                );
+                listeners.spawn(async move {
+                    if let Err(err) =
+                        federation::listen(listener, config, link_events, link_ids).await
+                    {
+                        error!("Listening for linked servers failed: {}", err);
+                    }
+                });
+            }
+            for peer in federation_config.peers.iter().cloned() {
+                listeners.spawn(federation::connect(
+                    peer,
+                    federation_config.clone(),
+                    link_events_tx.clone(),
+                    link_ids.clone(),
+                ));
+            }
+        }
+        // Drive our chat rooms in the background, so they only need to wait for the members
+        // and messages to be thrown around in the channels.
+        let room_handle = tokio::task::spawn(async move {
+            registry.run().await;
+        });
+
+        let gateway = Gateway {
+            message_recvd_from_member_tx: message_received_from_member_tx,
+            client_connected_with_name_tx,
+            client_disconnected_tx,
+            outbox: self.config.outbox,
+            backlog: self.backlog,
+            staging: self.staging,
+            strict_protocol: false,
+        };
+
+        if let Some(irc_listener) = self.irc_listener {
+            info!(
+                "Listening for IRC clients on {}",
+                irc_listener.local_addr()?
+            );
+            let gateway = gateway.clone();
+            listeners.spawn(async move {
+                if let Err(err) = irc::client_loop(irc_listener, gateway).await {
+                    error!("IRC client loop failed: {}", err);
+                }
+            });
+        }
+
+        if let Some(websocket_listener) = self.websocket_listener {
+            info!(
+                "Listening for websockets on {}",
+                websocket_listener.local_addr()?
+            );
+            let gateway = gateway.clone();
+            listeners.spawn(async move {
+                if let Err(err) = websocket::client_loop(websocket_listener, gateway).await {
+                    error!("Websocket client loop failed: {}", err);
+                }
+            });
+        }
+
+        info!(
+            "Listening for connections on {}",
+            self.listener.local_addr()?
+        );
+
+        let client_loop_result = select! {
+            // Only raw TCP members speak the protocol as written. Everyone else has
+            // a client that knows about commands.
+            result = client_loop(self.listener, Gateway {
+                strict_protocol: self.config.strict_protocol,
+                ..gateway
+            }) => result,
+            _ = self.shutdown.requested() => Ok(()),
+        };
+        info!("No longer letting anyone in. Shutting down...");
+        listeners.shutdown().await;
+        // The chat rooms say goodbye to everyone on their way out, even if
+        // it's the client loop failing that got us here.
+        self.shutdown.shutdown();
+        room_handle.await?;
+        info!("Shut down.");
+        client_loop_result
+    }
+}
+
+/// Start listening for connections, and spawn individual
+/// client handlers.
+pub async fn client_loop(listener: TcpListener, gateway: Gateway) -> crate::Result<()> {
+    debug!("Entering client_loop...");
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        debug!(peer = %addr, "Accepted connection");
+        // Chat lines are small, and should go out as soon as they're written.
+        if let Err(err) = socket.set_nodelay(true) {
+            warn!(peer = %addr, "Failed to disable Nagle's algorithm: {}", err);
+        }
+
+        let gateway = gateway.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_client(socket, addr, gateway).await {
+                error!("Handling client failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Try to complete the name-giving ceremony (aka "staging")
+/// for a TCP-connected peer and if everything goes okay, return the stream
+/// back as is, along with the name the peer chose, which is now theirs until they leave.
+///
+/// What comes of each name or password the peer gives is up to their [Stage];
+/// this only says and hears things on the socket.
+#[tracing::instrument(skip(staging), fields(kind = "staging"))]
+pub async fn stage_client(
+    mut socket: TcpStream,
+    addr: MemberID,
+    staging: &Staging,
+) -> crate::Result<(String, TcpStream)> {
+    let mut stage = match staging.start(&addr) {
+        Ok(stage) => stage,
+        Err(err) => {
+            socket.write_all(format!("{}\n", err).as_bytes()).await?;
+            return Err(err.into());
+        }
+    };
+
+    let (read_half, mut write_half) = socket.into_split();
+    let reader = BufReader::new(read_half);
+    let mut lines = reader.lines();
+
+    // Try writing. If we fail, drop the client.
+    trace!("Requesting client for a name.");
+    write_half
+        .write_all(format!("{}\n", Staging::WELCOME).as_bytes())
+        .await?;
+
+    let name = loop {
+        let line = match stage.wait_for(lines.next_line()).await {
+            Ok(line) => line?,
+            Err(err) => {
+                write_half
+                    .write_all(format!("{}\n", err).as_bytes())
+                    .await?;
+                return Err(err.into());
+            }
+        };
+        let Some(line) = line else {
+            warn!("Could not read line from client when we were expecting a name. Disconnecting.");
+            return Err(ClientInitializationError::ConnectionResetByClient.into());
+        };
+
+        trace!("Just read line: {}", line);
+        let reply = match stage.answer(&line).await {
+            Step::Admit => break stage.admit(),
+            Step::Password => Staging::PASSWORD_PROMPT.to_string(),
+            Step::Retry(err) => Staging::reprompt(&err),
+            Step::Reject { err, tell } => {
+                if tell {
+                    write_half
+                        .write_all(format!("{}\n", err).as_bytes())
+                        .await?;
+                }
+                return Err(err.into());
+            }
+        };
+        write_half
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await?;
+    };
+
+    debug!(name = %name, "Client name is valid.");
+
+    let read_half = lines.into_inner().into_inner();
+    let socket = match read_half.reunite(write_half) {
+        Ok(socket) => socket,
+        Err(err) => {
+            staging.release(&name);
+            return Err(err.into());
+        }
+    };
+
+    Ok((name, socket))
+}
+
+/// Given a raw socket, try to finish the name-giving of the peer,
+/// and if it goes fine, run a [Session] for them until they leave.
+#[tracing::instrument(skip_all, fields(addr, peer = %addr, name))]
+pub async fn handle_client(
+    socket: TcpStream,
+    addr: MemberID,
+    gateway: Gateway,
+) -> crate::Result<()> {
+    let Ok((name, socket)) = stage_client(socket, addr, &gateway.staging).await else {
+        error!("Staging failed for peer");
+        return Ok(());
+    };
+
+    tracing::Span::current().record("name", &name);
+
+    info!("Staging complete... Connecting member with Room.");
+
+    // We'll only call it a Session after its been through staging,
+    // at which point it gets a queue of its own.
+    Session::start(socket, addr, name, gateway)
+        .await?
+        .run()
+        .await;
+    Ok(())
+}
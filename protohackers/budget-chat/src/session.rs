@@ -0,0 +1,94 @@
+use crate::{outbox::OutboundReceiver, server::Gateway, MemberID};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    select,
+};
+use tracing::{info, trace, warn};
+
+/// An actor for a single member, from the moment they make it through staging until either
+/// side hangs up.
+///
+/// The session owns both halves of the member's socket, and its own bounded outbound queue.
+/// It passes every line the member sends straight on to the chat rooms, and writes out every
+/// message the rooms queue up for the member through their
+/// [SessionHandle](crate::outbox::SessionHandle), which is the only way the rooms ever reach it.
+#[derive(Debug)]
+pub struct Session {
+    member_id: MemberID,
+    name: String,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+    outbound: OutboundReceiver,
+    gateway: Gateway,
+}
+
+impl Session {
+    /// Hand a member who made it through staging over to the chat rooms.
+    pub async fn start(
+        socket: TcpStream,
+        member_id: MemberID,
+        name: String,
+        gateway: Gateway,
+    ) -> crate::Result<Self> {
+        let outbound = gateway.connect(member_id, &name).await?;
+        let (read_half, write_half) = socket.into_split();
+        Ok(Self {
+            member_id,
+            name,
+            lines: BufReader::new(read_half).lines(),
+            write_half,
+            outbound,
+            gateway,
+        })
+    }
+
+    /// Drive the session until either side hangs up, then let the chat rooms know
+    /// the member is gone. Anything that goes wrong along the way only ever evicts this member.
+    #[tracing::instrument(skip_all, fields(peer = %self.member_id, name = %self.name))]
+    pub async fn run(mut self) {
+        match self.relay().await {
+            Ok(()) => info!("Session is over."),
+            Err(err) => warn!("Evicting member: {}", err),
+        }
+        self.gateway.disconnect(self.member_id, &self.name).await;
+    }
+
+    async fn relay(&mut self) -> crate::Result<()> {
+        loop {
+            select! {
+                message = self.outbound.recv() => match message {
+                    Some(message) => {
+                        // One write per line, so Nagle doesn't hold back the newline.
+                        let line = format!("{}\n", message);
+                        select! {
+                            result = self.write_half.write_all(line.as_bytes()) => result?,
+                            // The member isn't reading what we write, and we've given up on them.
+                            _ = self.outbound.aborted() => {
+                                warn!("Gave up on member while writing to them. Hanging up.");
+                                return Ok(());
+                            }
+                        }
+                    }
+                    None => {
+                        trace!("Nobody can send messages to this member anymore. Hanging up.");
+                        return Ok(());
+                    }
+                },
+                line = self.lines.next_line() => match line? {
+                    Some(line) => {
+                        trace!(message = %line, "Message from member that will be forwarded to Room.");
+                        self.gateway.forward(self.member_id, line).await?;
+                    }
+                    None => {
+                        trace!("No more lines to read from member.");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+}
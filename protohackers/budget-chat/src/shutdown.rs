@@ -0,0 +1,69 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+/// What members are told, and how long we wait for them to hear it, when the server shuts down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownConfig {
+    /// Said to every member still around, right before we hang up on them.
+    pub message: String,
+    /// The longest we'll wait for everything queued up for members to be written
+    /// before hanging up on them anyway.
+    pub flush_deadline: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            message: Self::DEFAULT_MESSAGE.to_string(),
+            flush_deadline: Self::DEFAULT_FLUSH_DEADLINE,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub const DEFAULT_MESSAGE: &'static str = "* The server is shutting down";
+    pub const DEFAULT_FLUSH_DEADLINE: Duration = Duration::from_secs(5);
+
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = message.to_string();
+        self
+    }
+
+    pub fn with_flush_deadline(mut self, flush_deadline: Duration) -> Self {
+        self.flush_deadline = flush_deadline;
+        self
+    }
+}
+
+/// A way to ask a running [BudgetChat](crate::server::BudgetChat) to shut down, from anywhere.
+/// Every clone shuts down the same server, and asking more than once does nothing more.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self {
+            requested: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop letting anyone in, say goodbye to every member, and hang up on them.
+    pub fn shutdown(&self) {
+        self.requested.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once somebody asked for the server to shut down.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        // We hold on to the sender ourselves, so it can't be dropped while we wait.
+        _ = requested.wait_for(|requested| *requested).await;
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{
+    client::{ConnectionUpdate, ServerEvent},
+    command::Line as ChatLine,
+};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::{
+    layout::{Constraint, Layout, Position, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, Paragraph},
+    Frame,
+};
+use std::collections::{BTreeSet, VecDeque};
+
+/// Something in the scrollback: what someone said, or what the server or client had to say.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Who said it, unless it's a notice.
+    pub author: Option<String>,
+    pub text: String,
+}
+
+impl Entry {
+    pub fn said(author: &str, text: &str) -> Self {
+        Self {
+            author: Some(author.to_string()),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn notice(text: &str) -> Self {
+        Self {
+            author: None,
+            text: text.to_string(),
+        }
+    }
+
+    /// The entry as it's shown, along with how many characters at the start are the author.
+    fn shown(&self) -> (String, usize) {
+        match &self.author {
+            Some(author) => {
+                let prefix = format!("[{}]", author);
+                let prefix_len = prefix.chars().count();
+                (format!("{} {}", prefix, self.text), prefix_len)
+            }
+            None => (self.text.clone(), 0),
+        }
+    }
+}
+
+/// Every author gets a colour of their own, the same one every time.
+pub fn author_colour(name: &str) -> Color {
+    const PALETTE: [Color; 12] = [
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+    ];
+    // FNV-1a, so colours don't change between runs, or builds.
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+/// The line being typed, and every line sent before it.
+#[derive(Debug, Clone, Default)]
+pub struct InputLine {
+    text: String,
+    /// Where the cursor is, in characters.
+    cursor: usize,
+    history: VecDeque<String>,
+    /// Which line in the history is being looked at, if any, counting back from the latest.
+    browsing: Option<usize>,
+    /// What was being typed before looking through the history.
+    draft: String,
+}
+
+impl InputLine {
+    pub const MAX_HISTORY: usize = 100;
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+
+    fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    pub fn insert(&mut self, char: char) {
+        let index = self.byte_index(self.cursor);
+        self.text.insert(index, char);
+        self.cursor += 1;
+    }
+
+    /// Remove the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.cursor -= 1;
+        let index = self.byte_index(self.cursor);
+        self.text.remove(index);
+    }
+
+    /// Remove the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.len() {
+            let index = self.byte_index(self.cursor);
+            self.text.remove(index);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.len();
+    }
+
+    /// Show the line sent before the one showing, keeping whatever was being typed for later.
+    pub fn previous(&mut self) {
+        let browsing = match self.browsing {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                0
+            }
+            Some(browsing) => (browsing + 1).min(self.history.len() - 1),
+        };
+        self.show_history(Some(browsing));
+    }
+
+    /// Show the line sent after the one showing, or whatever was being typed after the latest.
+    pub fn next(&mut self) {
+        match self.browsing {
+            None => {}
+            Some(0) => self.show_history(None),
+            Some(browsing) => self.show_history(Some(browsing - 1)),
+        }
+    }
+
+    fn show_history(&mut self, browsing: Option<usize>) {
+        self.browsing = browsing;
+        self.text = match browsing {
+            Some(browsing) => self.history[self.history.len() - 1 - browsing].clone(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.end();
+    }
+
+    /// Take the line to send, if there's anything on it, and remember it.
+    pub fn submit(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        if line.trim().is_empty() {
+            return None;
+        }
+        if self.history.back() != Some(&line) {
+            self.history.push_back(line.clone());
+            if self.history.len() > Self::MAX_HISTORY {
+                self.history.pop_front();
+            }
+        }
+        Some(line)
+    }
+}
+
+/// What the member wants done, after pressing a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Send(String),
+    Quit,
+}
+
+/// How we're getting on with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Everything a full screen client shows: what was said, who's here, and what's being typed.
+#[derive(Debug)]
+pub struct ChatView {
+    name: String,
+    scrollback: VecDeque<Entry>,
+    /// How many rows up from the latest the scrollback is scrolled.
+    scroll: usize,
+    members: BTreeSet<String>,
+    status: Status,
+    pub input: InputLine,
+}
+
+impl ChatView {
+    pub const MAX_SCROLLBACK: usize = 1000;
+    const MEMBERS_WIDTH: u16 = 24;
+
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            scrollback: Default::default(),
+            scroll: 0,
+            members: Default::default(),
+            status: Status::Connecting,
+            input: Default::default(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status == Status::Connected
+    }
+
+    /// Everyone in the room as far as we know, sorted, us included.
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(String::as_str)
+    }
+
+    /// Everything in the scrollback, oldest first.
+    pub fn scrollback(&self) -> impl Iterator<Item = &Entry> {
+        self.scrollback.iter()
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.scrollback.push_back(entry);
+        if self.scrollback.len() > Self::MAX_SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        // Stay on whatever was being read, if anything.
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    /// Keep up with what's happening on the connection.
+    pub fn apply(&mut self, update: ConnectionUpdate) {
+        match update {
+            ConnectionUpdate::Connected => {
+                self.status = Status::Connected;
+            }
+            ConnectionUpdate::Event(event) => self.apply_event(event),
+            ConnectionUpdate::Disconnected(reason) => {
+                self.status = Status::Disconnected;
+                self.members.clear();
+                self.push(Entry::notice(&format!("* Disconnected: {}", reason)));
+            }
+            ConnectionUpdate::Reconnecting(delay) => {
+                self.push(Entry::notice(&format!(
+                    "* Reconnecting in {}...",
+                    humantime::format_duration(delay)
+                )));
+            }
+        }
+    }
+
+    fn apply_event(&mut self, event: ServerEvent) {
+        match &event {
+            ServerEvent::RoomListing(members) => {
+                self.members = members.iter().cloned().collect();
+                self.members.insert(self.name.clone());
+            }
+            ServerEvent::Join(name) => {
+                self.members.insert(name.clone());
+            }
+            ServerEvent::Leave(name) => {
+                self.members.remove(name);
+            }
+            ServerEvent::Message { from, text } => {
+                self.push(Entry::said(from, text));
+                return;
+            }
+            ServerEvent::Welcome(_) | ServerEvent::Unknown(_) => {}
+        }
+        self.push(Entry::notice(&event.to_string()));
+    }
+
+    /// Something the client itself has to say.
+    pub fn note(&mut self, text: &str) {
+        self.push(Entry::notice(text));
+    }
+
+    /// Show something we said, since the server never sends it back. Commands get
+    /// replies of their own, so only chat shows.
+    pub fn sent(&mut self, line: &str) {
+        if let ChatLine::Chat(text) = ChatLine::parse(line) {
+            let name = self.name.clone();
+            self.push(Entry::said(&name, text));
+        }
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll = (self.scroll + rows).min(self.scrollback.len());
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll = self.scroll.saturating_sub(rows);
+    }
+
+    /// Act on a key the member pressed.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c' | 'd') if control => return Some(Action::Quit),
+            KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Char('a') if control => self.input.home(),
+            KeyCode::Char('e') if control => self.input.end(),
+            KeyCode::Char(char) => self.input.insert(char),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll_up(10),
+            KeyCode::PageDown => self.scroll_down(10),
+            KeyCode::Enter => return self.input.submit().map(Action::Send),
+            _ => {}
+        }
+        None
+    }
+
+    /// Draw the scrollback with the members to its right, and the input line below both.
+    pub fn render(&self, frame: &mut Frame) {
+        let [main, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [scrollback, members] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(Self::MEMBERS_WIDTH)])
+                .areas(main);
+        self.render_scrollback(frame, scrollback);
+        self.render_members(frame, members);
+        self.render_input(frame, input);
+    }
+
+    fn render_scrollback(&self, frame: &mut Frame, area: Rect) {
+        let status = match self.status {
+            Status::Connecting => "connecting",
+            Status::Connected => "connected",
+            Status::Disconnected => "disconnected",
+        };
+        let mut title = format!(" budget-chat: {} ", status);
+        if self.scroll > 0 {
+            title.push_str("[scrolled back] ");
+        }
+        let block = Block::bordered().title(title);
+        let inner = block.inner(area);
+        let rows = self.rows(inner.width as usize);
+        let height = inner.height as usize;
+        let scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let end = rows.len() - scroll;
+        let start = end.saturating_sub(height);
+        let visible = rows[start..end].to_vec();
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+    }
+
+    /// Every entry in the scrollback, broken into rows no wider than the given width.
+    fn rows(&self, width: usize) -> Vec<Line<'static>> {
+        let width = width.max(1);
+        let mut rows = vec![];
+        for entry in &self.scrollback {
+            let (shown, prefix_len) = entry.shown();
+            let chars: Vec<char> = shown.chars().collect();
+            let author_style = match &entry.author {
+                Some(author) => Style::new().fg(author_colour(author)).bold(),
+                None => Style::new().add_modifier(Modifier::DIM),
+            };
+            let text_style = match entry.author {
+                Some(_) => Style::new(),
+                None => author_style,
+            };
+            for (row, chunk) in chars.chunks(width).enumerate() {
+                let start = row * width;
+                let split = prefix_len.saturating_sub(start).min(chunk.len());
+                let (author, text) = chunk.split_at(split);
+                rows.push(Line::from(vec![
+                    Span::styled(author.iter().collect::<String>(), author_style),
+                    Span::styled(text.iter().collect::<String>(), text_style),
+                ]));
+            }
+            if chars.is_empty() {
+                rows.push(Line::default());
+            }
+        }
+        rows
+    }
+
+    fn render_members(&self, frame: &mut Frame, area: Rect) {
+        let members: Vec<_> = self
+            .members
+            .iter()
+            .map(|member| {
+                let name = member.split_whitespace().next().unwrap_or(member);
+                let mut style = Style::new().fg(author_colour(name));
+                if name == self.name {
+                    style = style.bold();
+                }
+                ListItem::new(member.as_str()).style(style)
+            })
+            .collect();
+        let title = format!(" Members ({}) ", members.len());
+        frame.render_widget(
+            List::new(members).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn render_input(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(format!(" {} ", self.name));
+        let inner = block.inner(area);
+        let width = (inner.width as usize).max(1);
+        // Keep the cursor in view on lines too long to show in full.
+        let offset = self.input.cursor().saturating_sub(width - 1);
+        let shown: String = self.input.text().chars().skip(offset).collect();
+        frame.render_widget(Paragraph::new(shown).block(block), area);
+        frame.set_cursor_position(Position::new(
+            inner.x + (self.input.cursor() - offset) as u16,
+            inner.y,
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    outbox::OutboundReceiver,
+    room::Message,
+    server::{Gateway, Staging, Step},
+    MemberID,
+};
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+};
+use tokio_tungstenite::{
+    tungstenite::{self, handshake::derive_accept_key, protocol::Role, Message as Frame},
+    WebSocketStream,
+};
+use tracing::{debug, error, info, trace, warn};
+
+/// The web client, served to anyone who visits the websocket port with a browser.
+const INDEX_HTML: &str = include_str!("../static/index.html");
+
+/// The most we'll read of an HTTP request before giving up on ever seeing the end of its head.
+const MAX_REQUEST_HEAD_BYTES: usize = 8 * 1024;
+
+type WebSocket = WebSocketStream<BufReader<TcpStream>>;
+
+/// The parts of an HTTP request we care about.
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    path: String,
+    /// Every header, by its name in lower case.
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// The key the client upgrading to a websocket wants us to answer with, if that's
+    /// what it's doing.
+    fn websocket_key(&self) -> Option<&str> {
+        self.header("upgrade")
+            .filter(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+            .and(self.header("sec-websocket-key"))
+    }
+}
+
+/// Read the head of an HTTP request, up to and including the blank line that ends it.
+/// Returns `None` if the peer went away or sent something that isn't HTTP.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> crate::Result<Option<Request>> {
+    let mut request = Request::default();
+    let mut read = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        // Never buffer past the limit, even for a line that doesn't end.
+        let remaining = (MAX_REQUEST_HEAD_BYTES - read) as u64;
+        let bytes = (&mut *stream).take(remaining).read_line(&mut line).await?;
+        read += bytes;
+        // Either the peer went away, or the head ran into the limit before the line ended.
+        if !line.ends_with('\n') {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if request.method.is_empty() {
+            let mut parts = line.split_whitespace();
+            let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+                return Ok(None);
+            };
+            request.method = method.to_string();
+            request.path = path.to_string();
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(None);
+        };
+        request
+            .headers
+            .insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    Ok((!request.method.is_empty()).then_some(request))
+}
+
+/// Answer a plain HTTP request, which can only ever be for the web client.
+async fn respond(
+    stream: &mut BufReader<TcpStream>,
+    request: Option<&Request>,
+) -> crate::Result<()> {
+    let (status, content_type, body) = match request {
+        None => ("400 Bad Request", "text/plain", "Bad request\n"),
+        Some(request) if request.method != "GET" => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n",
+        ),
+        Some(request) if request.path == "/" || request.path == "/index.html" => {
+            ("200 OK", "text/html; charset=utf-8", INDEX_HTML)
+        }
+        Some(_) => ("404 Not Found", "text/plain", "Not found\n"),
+    };
+    debug!(status, path = ?request.map(|request| &request.path), "Answering HTTP request.");
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Start listening for browsers, and spawn individual client handlers.
+pub async fn client_loop(listener: TcpListener, gateway: Gateway) -> crate::Result<()> {
+    debug!("Entering websocket client_loop...");
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        debug!(peer = %addr, "Accepted websocket connection");
+        if let Err(err) = socket.set_nodelay(true) {
+            warn!(peer = %addr, "Failed to disable Nagle's algorithm: {}", err);
+        }
+
+        let gateway = gateway.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_client(socket, addr, gateway).await {
+                error!("Handling websocket client failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Serve the web client to plain HTTP requests, and treat websocket upgrades exactly like
+/// a TCP client, only with every line in a text frame of its own.
+#[tracing::instrument(skip_all, fields(peer = %addr, name, kind = "websocket"))]
+pub async fn handle_client(
+    socket: TcpStream,
+    addr: MemberID,
+    gateway: Gateway,
+) -> crate::Result<()> {
+    let mut stream = BufReader::new(socket);
+    let request = read_request(&mut stream).await?;
+    let Some(key) = request.as_ref().and_then(Request::websocket_key) else {
+        return respond(&mut stream, request.as_ref()).await;
+    };
+
+    trace!("Upgrading to a websocket.");
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    stream.write_all(response.as_bytes()).await?;
+    let mut websocket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+    let Some(name) = stage_client(&mut websocket, addr, &gateway.staging).await? else {
+        error!("Staging failed for peer");
+        _ = websocket.close(None).await;
+        return Ok(());
+    };
+    tracing::Span::current().record("name", &name);
+    info!("Staging complete... Connecting member with Room.");
+
+    let outbound = gateway.connect(addr, &name).await?;
+    if let Err(err) = relay(websocket, addr, &gateway, outbound).await {
+        warn!("Evicting member: {}", err);
+    }
+    gateway.disconnect(addr, &name).await;
+    Ok(())
+}
+
+/// Go through the same name-giving ceremony as a TCP client, saying the same things.
+/// Returns the name the peer got, or `None` if they didn't make it.
+async fn stage_client(
+    websocket: &mut WebSocket,
+    addr: MemberID,
+    staging: &Staging,
+) -> crate::Result<Option<String>> {
+    let mut stage = match staging.start(&addr) {
+        Ok(stage) => stage,
+        Err(err) => {
+            websocket.send(Frame::text(err.to_string())).await?;
+            return Ok(None);
+        }
+    };
+
+    websocket.send(Frame::text(Staging::WELCOME)).await?;
+    loop {
+        let line = match stage.wait_for(next_line(websocket)).await {
+            Ok(line) => line?,
+            Err(err) => {
+                websocket.send(Frame::text(err.to_string())).await?;
+                return Ok(None);
+            }
+        };
+        let Some(line) = line else {
+            warn!("Peer went away when we were expecting a name.");
+            return Ok(None);
+        };
+        let reply = match stage.answer(&line).await {
+            Step::Admit => return Ok(Some(stage.admit())),
+            Step::Password => Staging::PASSWORD_PROMPT.to_string(),
+            Step::Retry(err) => Staging::reprompt(&err),
+            Step::Reject { err, tell } => {
+                if tell {
+                    websocket.send(Frame::text(err.to_string())).await?;
+                }
+                return Ok(None);
+            }
+        };
+        websocket.send(Frame::text(reply)).await?;
+    }
+}
+
+/// Wait for the next text frame, as a single chat line. Line breaks inside a frame
+/// would let a browser smuggle extra lines to TCP clients, so they're flattened into spaces.
+/// Returns `None` once the peer closes the websocket.
+async fn next_line<S>(frames: &mut S) -> crate::Result<Option<Message>>
+where
+    S: Stream<Item = Result<Frame, tungstenite::Error>> + Unpin,
+{
+    while let Some(frame) = frames.next().await {
+        match frame? {
+            Frame::Text(text) => {
+                let line = text
+                    .trim_end_matches(['\r', '\n'])
+                    .replace(['\r', '\n'], " ");
+                return Ok(Some(line));
+            }
+            Frame::Close(_) => return Ok(None),
+            frame => trace!(?frame, "Ignoring frame that isn't text."),
+        }
+    }
+    Ok(None)
+}
+
+/// Relay between the websocket and the chat rooms, until either side hangs up.
+async fn relay(
+    websocket: WebSocket,
+    addr: MemberID,
+    gateway: &Gateway,
+    mut outbound: OutboundReceiver,
+) -> crate::Result<()> {
+    let (mut sink, mut frames) = websocket.split();
+    loop {
+        select! {
+            message = outbound.recv() => match message {
+                Some(message) => {
+                    select! {
+                        result = sink.send(Frame::text(message.to_string())) => result?,
+                        // The peer isn't reading what we write, and we've given up on them.
+                        _ = outbound.aborted() => {
+                            warn!("Gave up on peer while writing to them. Hanging up.");
+                            return Ok(());
+                        }
+                    }
+                }
+                None => {
+                    trace!("Nobody can send messages to this peer anymore. Hanging up.");
+                    _ = sink.send(Frame::Close(None)).await;
+                    return Ok(());
+                }
+            },
+            line = next_line(&mut frames) => match line? {
+                Some(line) => {
+                    trace!(message = %line, "Message from peer that will be forwarded to Room.");
+                    gateway.forward(addr, line).await?;
+                }
+                None => {
+                    trace!("Peer closed the websocket.");
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Members can register their names, after which only those who know the password get them.
+
+mod common;
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use budget_chat::{accounts::Accounts, names::NamePolicy, server::ServerConfig};
+use common::Member;
+
+/// Somewhere to keep accounts for a single test, starting out empty.
+fn accounts_file(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "budget-chat-{}-{}-accounts.json",
+        test,
+        std::process::id()
+    ));
+    _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn start(accounts: Accounts) -> SocketAddr {
+    common::start(ServerConfig {
+        names: NamePolicy::default().with_reprompt(true),
+        // Nobody's guessing these, so there's no need to wait on hashing them properly.
+        accounts: Some(accounts.with_rounds(1)),
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn registered_names_need_their_password() {
+    let path = accounts_file("password");
+    let addr = start(Accounts::load(&path).unwrap()).await;
+
+    let mut alice = Member::connect(addr).await;
+    alice.say("alice").await;
+    alice.expect("* The room contains: ").await;
+    alice.say("/register hunter2").await;
+    alice.expect("* Registered alice").await;
+    drop(alice);
+
+    let mut imposter = Member::connect(addr).await;
+    assert_eq!(
+        imposter.ask_for("alice").await,
+        "That name is registered. What's the password?"
+    );
+    imposter.say("password1").await;
+    imposter
+        .expect("That's not the password for alice. What shall I call you?")
+        .await;
+
+    let mut alice = Member::connect(addr).await;
+    assert_eq!(
+        alice.ask_for("alice").await,
+        "That name is registered. What's the password?"
+    );
+    alice.say("hunter2").await;
+    alice.expect("* The room contains: ").await;
+
+    alice.say("/passwd password1 hunter3").await;
+    alice.expect("* That's not the password for alice").await;
+    alice.say("/passwd hunter2 hunter3").await;
+    alice.expect("* Changed the password for alice").await;
+
+    let saved = Accounts::load(&path).unwrap();
+    assert!(saved.check_password("alice", "hunter3"));
+    assert!(!saved.check_password("alice", "hunter2"));
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn registered_names_need_their_password_in_any_case() {
+    let path = accounts_file("case");
+    let accounts = Accounts::load(&path).unwrap().with_rounds(1);
+    accounts.register("alice", "hunter2").unwrap();
+    let addr = start(accounts).await;
+
+    let mut imposter = Member::connect(addr).await;
+    assert_eq!(
+        imposter.ask_for("ALICE").await,
+        "That name is registered. What's the password?"
+    );
+    imposter.say("password1").await;
+    imposter
+        .expect("That's not the password for ALICE. What shall I call you?")
+        .await;
+
+    let mut alice = Member::connect(addr).await;
+    assert_eq!(
+        alice.ask_for("Alice").await,
+        "That name is registered. What's the password?"
+    );
+    alice.say("hunter2").await;
+    alice.expect("* The room contains: ").await;
+    alice.say("/register hunter3").await;
+    alice.expect("* Alice is already registered").await;
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn only_registered_names_get_in_without_guests() {
+    let path = accounts_file("guests");
+    let accounts = Accounts::load(&path).unwrap().with_rounds(1);
+    accounts.register("alice", "hunter2").unwrap();
+    let addr = start(accounts.with_guests(false)).await;
+
+    let mut bob = Member::connect(addr).await;
+    bob.say("bob").await;
+    bob.expect(
+        "Only registered names are allowed here, and nobody registered bob. What shall I call you?",
+    )
+    .await;
+    bob.say("alice").await;
+    bob.expect("That name is registered. What's the password?")
+        .await;
+    bob.say("hunter2").await;
+    bob.expect("* The room contains: ").await;
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn the_password_prompt_does_not_hold_on_to_a_name_forever() {
+    let path = accounts_file("timeout");
+    let accounts = Accounts::load(&path).unwrap().with_rounds(1);
+    accounts.register("alice", "hunter2").unwrap();
+    let addr = start(accounts.with_password_timeout(Duration::from_millis(200))).await;
+
+    let mut imposter = Member::connect(addr).await;
+    imposter.say("alice").await;
+    imposter
+        .expect("That name is registered. What's the password?")
+        .await;
+
+    // Nobody else gets the name while the imposter sits on the prompt, but only for so long.
+    let mut alice = Member::connect(addr).await;
+    assert_eq!(
+        alice.ask_for("alice").await,
+        "That name is registered. What's the password?"
+    );
+    imposter
+        .expect("Took too long to give the password for alice")
+        .await;
+    assert_eq!(imposter.read_line().await, None);
+
+    alice.say("hunter2").await;
+    alice.expect("* The room contains: ").await;
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn too_many_wrong_passwords_get_a_peer_disconnected() {
+    let path = accounts_file("attempts");
+    let accounts = Accounts::load(&path).unwrap().with_rounds(1);
+    accounts.register("alice", "hunter2").unwrap();
+    let addr = start(accounts.with_max_attempts(2)).await;
+
+    let mut imposter = Member::connect(addr).await;
+    imposter.say("alice").await;
+    imposter
+        .expect("That name is registered. What's the password?")
+        .await;
+    imposter.say("password1").await;
+    imposter
+        .expect("That's not the password for alice. What shall I call you?")
+        .await;
+    imposter.say("alice").await;
+    imposter
+        .expect("That name is registered. What's the password?")
+        .await;
+    imposter.say("password2").await;
+    imposter.expect("Too many wrong passwords").await;
+    assert_eq!(imposter.read_line().await, None);
+
+    // The name is free for its owner again.
+    let mut alice = Member::connect(addr).await;
+    assert_eq!(
+        alice.ask_for("alice").await,
+        "That name is registered. What's the password?"
+    );
+    alice.say("hunter2").await;
+    alice.expect("* The room contains: ").await;
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn changes_that_cannot_be_saved_are_undone() {
+    let directory = std::env::temp_dir().join(format!(
+        "budget-chat-unsaved-{}-accounts",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&directory).unwrap();
+    let accounts = Accounts::load(directory.join("accounts.json")).unwrap();
+    let addr = start(accounts.clone()).await;
+
+    let mut alice = Member::join(addr, "alice").await;
+    alice.say("/register hunter2").await;
+    alice.expect("* Registered alice").await;
+
+    // Nowhere to save anything anymore.
+    std::fs::remove_dir_all(&directory).unwrap();
+    alice.say("/passwd hunter2 hunter3").await;
+    let reply = alice.next_line().await;
+    assert!(
+        reply.starts_with("* Couldn't save accounts, so nothing changed: "),
+        "{}",
+        reply
+    );
+    assert!(accounts.check_password("alice", "hunter2"));
+
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+    bob.say("/register hunter2").await;
+    let reply = bob.next_line().await;
+    assert!(
+        reply.starts_with("* Couldn't save accounts, so nothing changed: "),
+        "{}",
+        reply
+    );
+    assert!(!accounts.is_registered("bob"));
+}
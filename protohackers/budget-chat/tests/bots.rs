@@ -0,0 +1,156 @@
+//! Bots sit in rooms like members do, without connecting to anything.
+
+mod common;
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use budget_chat::{
+    bot::{Bot, BuildStatus, Poster, Reminders},
+    names::NamePolicy,
+    server::{BudgetChat, ServerConfig},
+};
+use common::Member;
+
+/// Says hello to everyone who enters its room, and pong to anyone who says ping.
+#[derive(Debug)]
+struct Greeter;
+
+impl Bot for Greeter {
+    fn on_join(&mut self, member: &str, poster: &Poster) {
+        poster.post(format!("Hello, {}!", member));
+    }
+
+    fn on_message(&mut self, _member: &str, text: &str, poster: &Poster) {
+        if text == "ping" {
+            poster.post("pong");
+        }
+    }
+}
+
+/// Start a server with the given config, and whatever bots the test adds to it.
+async fn start(
+    config: ServerConfig,
+    with_bots: impl FnOnce(BudgetChat) -> BudgetChat,
+) -> SocketAddr {
+    common::start_with(
+        ServerConfig {
+            names: NamePolicy::default().with_reprompt(true),
+            ..config
+        },
+        with_bots,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn bots_are_listed_and_hear_whats_said() {
+    let addr = start(
+        ServerConfig {
+            bots: vec!["dice".parse().unwrap()],
+            ..Default::default()
+        },
+        |chat| chat.with_bot("greeter", "lobby", Greeter),
+    )
+    .await;
+
+    let mut alice = Member::connect(addr).await;
+    alice.say("alice").await;
+    assert_eq!(
+        alice.next_line().await,
+        "* The room contains: dice (bot), greeter (bot)"
+    );
+    assert_eq!(alice.next_line().await, "[greeter] Hello, alice!");
+
+    alice.say("ping").await;
+    assert_eq!(alice.next_line().await, "[greeter] pong");
+
+    alice.say("!roll 2d6").await;
+    let roll = alice.next_line().await;
+    assert!(roll.starts_with("[dice] alice rolled 2d6: "), "{}", roll);
+
+    alice.say("/who").await;
+    let who = alice.next_line().await;
+    assert!(who.starts_with("* In lobby: alice (idle "), "{}", who);
+    assert!(who.ends_with(", dice (bot), greeter (bot)"), "{}", who);
+    alice.say("/whois dice").await;
+    assert_eq!(alice.next_line().await, "* dice is a bot, in lobby");
+
+    let mut imposter = Member::connect(addr).await;
+    imposter.say("dice").await;
+    assert_eq!(
+        imposter.next_line().await,
+        "Somebody is already called dice. What shall I call you?"
+    );
+}
+
+#[tokio::test]
+async fn reminders_only_keep_track_of_so_many() {
+    let addr = start(Default::default(), |chat| {
+        chat.with_bot(
+            "reminders",
+            "lobby",
+            Reminders::default()
+                .with_max_per_member(1)
+                .with_max_pending(2),
+        )
+    })
+    .await;
+
+    let mut alice = Member::join(addr, "alice").await;
+    alice.say("!remind 100ms stretch").await;
+    alice
+        .expect("[reminders] alice: I'll remind you in 100ms")
+        .await;
+    alice.say("!remind 1h stretch again").await;
+    alice
+        .expect("[reminders] alice: you can only have 1 reminders waiting at once")
+        .await;
+    // Once a reminder goes off, it no longer counts.
+    alice.expect("[reminders] alice: reminder: stretch").await;
+    alice.say("!remind 1h stretch again").await;
+    alice
+        .expect("[reminders] alice: I'll remind you in 1h")
+        .await;
+
+    let mut bob = Member::join(addr, "bob").await;
+    bob.say("!remind 1h eat").await;
+    bob.expect("[reminders] bob: I'll remind you in 1h").await;
+    let mut carol = Member::join(addr, "carol").await;
+    carol.say("!remind 1h sleep").await;
+    carol
+        .expect("[reminders] carol: I have too many reminders to keep track of, try again later")
+        .await;
+}
+
+#[tokio::test]
+async fn build_status_is_posted_when_it_changes() {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("budget-chat-{}-build-status", std::process::id()));
+    _ = std::fs::remove_file(&path);
+    let addr = start(Default::default(), |chat| {
+        chat.with_bot(
+            "builds",
+            "lobby",
+            BuildStatus::new(&path).with_poll_interval(Duration::from_millis(20)),
+        )
+    })
+    .await;
+
+    let mut alice = Member::connect(addr).await;
+    alice.say("alice").await;
+    assert_eq!(alice.next_line().await, "* The room contains: builds (bot)");
+    alice.say("!build").await;
+    assert_eq!(
+        alice.next_line().await,
+        "[builds] No build has reported its status yet"
+    );
+
+    std::fs::write(&path, "passing\n").unwrap();
+    assert_eq!(alice.next_line().await, "[builds] Build status: passing");
+    std::fs::write(&path, "failing: 3 tests\n").unwrap();
+    assert_eq!(
+        alice.next_line().await,
+        "[builds] Build status: failing: 3 tests"
+    );
+    _ = std::fs::remove_file(&path);
+}
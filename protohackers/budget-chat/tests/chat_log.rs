@@ -0,0 +1,188 @@
+//! The chat log keeps a bounded number of rotated files, and a restarted server
+//! rebuilds room history from it.
+
+mod common;
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use budget_chat::{
+    chat_log::{ChatEvent, ChatEventKind, ChatLog, ChatLogWriter},
+    history::HistoryConfig,
+    server::ServerConfig,
+};
+use common::{start, Member, WAIT};
+
+/// Somewhere to keep a chat log for a single test, starting out with no log at all.
+fn log_file(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "budget-chat-{}-{}-chat.log",
+        test,
+        std::process::id()
+    ));
+    remove_log(&path);
+    path
+}
+
+fn remove_log(path: &Path) {
+    _ = std::fs::remove_file(path);
+    for index in 1..10 {
+        _ = std::fs::remove_file(rotated(path, index));
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), index))
+}
+
+fn said(text: &str) -> ChatEvent {
+    ChatEvent::new(
+        "lobby",
+        "alice",
+        ChatEventKind::Chat {
+            text: text.to_string(),
+        },
+    )
+}
+
+fn texts(events: &[ChatEvent]) -> Vec<&str> {
+    events
+        .iter()
+        .filter_map(|event| match &event.kind {
+            ChatEventKind::Chat { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn full_logs_are_rotated_and_the_oldest_dropped() {
+    let path = log_file("rotation");
+    let mut log = ChatLog::open(&path)
+        .unwrap()
+        .with_max_bytes(1)
+        .with_max_files(2);
+    for text in ["one", "two", "three", "four"] {
+        log.append(&said(text)).unwrap();
+    }
+    assert!(rotated(&path, 1).exists());
+    assert!(rotated(&path, 2).exists());
+    assert!(!rotated(&path, 3).exists());
+    assert_eq!(
+        texts(&ChatLog::read_all(&path).unwrap()),
+        ["two", "three", "four"]
+    );
+
+    // Reopening picks up where we left off, and rotates the full current file first.
+    let mut log = ChatLog::open(&path)
+        .unwrap()
+        .with_max_bytes(1)
+        .with_max_files(2);
+    log.append(&said("five")).unwrap();
+    assert_eq!(
+        texts(&ChatLog::read_all(&path).unwrap()),
+        ["three", "four", "five"]
+    );
+    remove_log(&path);
+}
+
+#[test]
+fn without_rotated_files_the_log_starts_over() {
+    let path = log_file("truncation");
+    let mut log = ChatLog::open(&path)
+        .unwrap()
+        .with_max_bytes(1)
+        .with_max_files(0);
+    for text in ["one", "two"] {
+        log.append(&said(text)).unwrap();
+    }
+    assert!(!rotated(&path, 1).exists());
+    assert_eq!(texts(&ChatLog::read_all(&path).unwrap()), ["two"]);
+    remove_log(&path);
+}
+
+#[test]
+fn unreadable_lines_are_skipped() {
+    let path = log_file("unreadable");
+    let mut log = ChatLog::open(&path).unwrap();
+    log.append(&said("before")).unwrap();
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, b"{\"at_ms\":1,\"ro\n"))
+        .unwrap();
+    let mut log = ChatLog::open(&path).unwrap();
+    log.append(&said("after")).unwrap();
+    log.sync().unwrap();
+    assert_eq!(
+        texts(&ChatLog::read_all(&path).unwrap()),
+        ["before", "after"]
+    );
+    remove_log(&path);
+}
+
+#[tokio::test]
+async fn closing_a_writer_writes_out_everything_it_was_handed() {
+    let path = log_file("writer");
+    let writer = ChatLogWriter::spawn(ChatLog::open(&path).unwrap().with_max_bytes(64));
+    for text in ["one", "two", "three"] {
+        writer.write(said(text));
+    }
+    writer.close().await;
+    assert_eq!(
+        texts(&ChatLog::read_all(&path).unwrap()),
+        ["one", "two", "three"]
+    );
+    remove_log(&path);
+}
+
+#[tokio::test]
+async fn history_survives_a_restart() {
+    let path = log_file("restart");
+    let config = || ServerConfig {
+        history: HistoryConfig::new(10),
+        chat_log: Some(ChatLog::open(&path).unwrap()),
+        ..Default::default()
+    };
+
+    let before = start(config()).await;
+    let mut alice = Member::join(before, "alice").await;
+    let mut bob = Member::join(before, "bob").await;
+    alice.expect("* bob has entered the room").await;
+    alice.say("remember me").await;
+    bob.expect("[alice] remember me").await;
+    bob.say("/join games").await;
+    bob.expect("* The room contains: ").await;
+    bob.say("anyone for chess?").await;
+    bob.say("/rooms").await;
+    bob.expect("* Rooms: games (1), lobby (1)").await;
+
+    // The log is written in the background, so give that a moment to land.
+    let started = Instant::now();
+    let events = loop {
+        let events = ChatLog::read_all(&path).unwrap();
+        if texts(&events).len() == 2 {
+            break events;
+        }
+        assert!(started.elapsed() < WAIT, "Chat was never logged");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(texts(&events), ["remember me", "anyone for chess?"]);
+    assert!(events
+        .iter()
+        .any(|event| event.kind == ChatEventKind::Join && event.member == "bob"));
+
+    // A fresh server reading the same log, as if the first one had been restarted.
+    let after = start(config()).await;
+    let mut carol = Member::named(after, "carol").await;
+    carol.expect("* The room contains: ").await;
+    carol.expect("* [history] [alice] remember me").await;
+    carol.say("/rooms").await;
+    carol.expect("* Rooms: games (0), lobby (1)").await;
+    carol.say("/join games").await;
+    carol.expect("* The room contains: ").await;
+    carol.expect("* [history] [bob] anyone for chess?").await;
+    remove_log(&path);
+}
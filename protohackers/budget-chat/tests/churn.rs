@@ -0,0 +1,67 @@
+//! Members dropping off at the worst possible moments only ever take themselves out of the chat:
+//! everyone else keeps hearing each other, and newcomers still get in.
+
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use budget_chat::server::ServerConfig;
+use common::{start, Member};
+use tokio::{io::AsyncWriteExt, net::TcpStream, task::JoinSet, time::sleep};
+
+const FLAKY_MEMBERS: usize = 200;
+const ROUNDS: usize = 20;
+
+/// Connect, get some way into saying something, and vanish. Some hang up politely and
+/// some reset the connection, at any point from before picking a name to halfway through a line.
+async fn flake(addr: SocketAddr, id: usize) {
+    // The server may well have reset us first, so nothing here is expected to succeed.
+    let Ok(mut stream) = TcpStream::connect(addr).await else {
+        return;
+    };
+    if rand::random() {
+        _ = stream.set_linger(Some(Duration::ZERO));
+    }
+    let name = format!("flaky{}\n", id);
+    let said = format!("{}I was about to say something important\n", name);
+    // Anywhere from nothing at all to everything, usually cutting a line short.
+    let cut = rand::random_range(0..=said.len());
+    for chunk in said.as_bytes()[..cut].chunks(8) {
+        if stream.write_all(chunk).await.is_err() {
+            return;
+        }
+        if rand::random_ratio(1, 4) {
+            sleep(Duration::from_millis(rand::random_range(0..5))).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn members_dropping_mid_message_dont_take_the_room_down() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::named(addr, "alice").await;
+    alice.wait_for("* The room contains: ").await;
+    let mut bob = Member::named(addr, "bob").await;
+    alice.wait_for("* bob has entered the room").await;
+
+    let mut flakes = JoinSet::new();
+    for id in 0..FLAKY_MEMBERS {
+        flakes.spawn(flake(addr, id));
+    }
+
+    for round in 0..ROUNDS {
+        bob.say(&format!("still here {}", round)).await;
+        alice.wait_for(&format!("[bob] still here {}", round)).await;
+        alice.say(&format!("me too {}", round)).await;
+        bob.wait_for(&format!("[alice] me too {}", round)).await;
+    }
+    while let Some(result) = flakes.join_next().await {
+        result.unwrap();
+    }
+
+    let mut carol = Member::named(addr, "carol").await;
+    alice.wait_for("* carol has entered the room").await;
+    carol.say("did I miss anything").await;
+    alice.wait_for("[carol] did I miss anything").await;
+    bob.wait_for("[carol] did I miss anything").await;
+}
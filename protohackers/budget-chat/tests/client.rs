@@ -0,0 +1,118 @@
+//! The client library gets through the name ceremony and makes sense of what the server says.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use budget_chat::{
+    client::{Client, ServerEvent},
+    names::NamePolicy,
+    server::{ServerConfig, Staging},
+    ClientError,
+};
+use common::WAIT;
+use tokio::time::timeout;
+
+async fn start() -> SocketAddr {
+    common::start(ServerConfig {
+        names: NamePolicy::default().with_reprompt(true),
+        ..Default::default()
+    })
+    .await
+}
+
+async fn next_event(client: &mut Client) -> Option<ServerEvent> {
+    timeout(WAIT, client.next_event())
+        .await
+        .expect("Waited too long for an event")
+        .unwrap()
+}
+
+#[test]
+fn server_lines_are_parsed_and_shown_as_they_were() {
+    let lines = [
+        (
+            "* The room contains: alice, dice (bot)",
+            ServerEvent::RoomListing(vec!["alice".into(), "dice (bot)".into()]),
+        ),
+        ("* The room contains: ", ServerEvent::RoomListing(vec![])),
+        (
+            "* bob has entered the room",
+            ServerEvent::Join("bob".into()),
+        ),
+        (
+            "* zed@elsewhere has left the room",
+            ServerEvent::Leave("zed@elsewhere".into()),
+        ),
+        (
+            "[alice] [not a name] hi",
+            ServerEvent::Message {
+                from: "alice".into(),
+                text: "[not a name] hi".into(),
+            },
+        ),
+        (
+            "[alice -> bob] psst",
+            ServerEvent::Unknown("[alice -> bob] psst".into()),
+        ),
+        (
+            "* [history] [alice] hi",
+            ServerEvent::Unknown("* [history] [alice] hi".into()),
+        ),
+        (
+            "* The server is shutting down",
+            ServerEvent::Unknown("* The server is shutting down".into()),
+        ),
+    ];
+    for (line, event) in lines {
+        assert_eq!(ServerEvent::parse(line), event, "{}", line);
+        assert_eq!(event.to_string(), line);
+    }
+}
+
+#[tokio::test]
+async fn clients_see_each_other_come_talk_and_go() {
+    let addr = start().await;
+
+    let mut alice = Client::connect(addr, "alice").await.unwrap();
+    assert_eq!(
+        next_event(&mut alice).await,
+        Some(ServerEvent::Welcome(Staging::WELCOME.into()))
+    );
+    assert_eq!(
+        next_event(&mut alice).await,
+        Some(ServerEvent::RoomListing(vec![]))
+    );
+
+    let (mut bob_events, mut bob) = Client::connect(addr, "bob").await.unwrap().split();
+    assert_eq!(
+        next_event(&mut alice).await,
+        Some(ServerEvent::Join("bob".into()))
+    );
+    bob_events.next_event().await.unwrap();
+    assert_eq!(
+        bob_events.next_event().await.unwrap(),
+        Some(ServerEvent::RoomListing(vec!["alice".into()]))
+    );
+
+    bob.send("hi alice").await.unwrap();
+    assert_eq!(
+        next_event(&mut alice).await,
+        Some(ServerEvent::Message {
+            from: "bob".into(),
+            text: "hi alice".into()
+        })
+    );
+
+    bob.quit().await.unwrap();
+    drop(bob_events);
+    assert_eq!(
+        next_event(&mut alice).await,
+        Some(ServerEvent::Leave("bob".into()))
+    );
+
+    assert!(matches!(
+        Client::connect(addr, "alice").await,
+        Err(ClientError::Rejected(reply)) if reply.starts_with("Somebody is already called alice")
+    ));
+}
@@ -0,0 +1,69 @@
+//! Lines starting with a `/` are commands, unless they start with `//`, or the server
+//! sticks to the protocol as written.
+
+mod common;
+
+use budget_chat::server::ServerConfig;
+use common::{start, Member};
+
+#[tokio::test]
+async fn commands_answer_with_help_and_usage() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    assert_eq!(alice.next_line().await, "* bob has entered the room");
+
+    alice.say("/help").await;
+    assert_eq!(
+        alice.next_line().await,
+        "* Commands (start a line with // to say something that starts with /):"
+    );
+    let mut commands = vec![];
+    loop {
+        let line = alice.next_line().await;
+        let last = line.starts_with("* /whois ");
+        commands.push(line);
+        if last {
+            break;
+        }
+    }
+    assert!(commands
+        .iter()
+        .any(|line| line.starts_with("* /join <room>: ")));
+    assert!(
+        !commands.iter().any(|line| line.starts_with("* /kick ")),
+        "Operator commands are listed for members who can't use them"
+    );
+
+    alice.say("/help join").await;
+    assert!(alice.next_line().await.starts_with("* /join <room>: "));
+
+    alice.say("/join").await;
+    assert_eq!(alice.next_line().await, "* Usage: /join <room>");
+
+    alice.say("/dance").await;
+    assert_eq!(
+        alice.next_line().await,
+        "* There's no /dance command. Try /help"
+    );
+
+    alice.say("//dance is not a command").await;
+    assert_eq!(bob.next_line().await, "[alice] /dance is not a command");
+}
+
+#[tokio::test]
+async fn strict_protocol_treats_every_line_as_chat() {
+    let addr = start(ServerConfig {
+        strict_protocol: true,
+        ..Default::default()
+    })
+    .await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    assert_eq!(alice.next_line().await, "* bob has entered the room");
+
+    alice.say("/join games").await;
+    assert_eq!(bob.next_line().await, "[alice] /join games");
+    alice.say("//help").await;
+    assert_eq!(bob.next_line().await, "[alice] //help");
+}
@@ -0,0 +1,113 @@
+//! What most of the tests need: a server to talk to, and members talking to it a line at a time.
+//! Not every test uses all of it.
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, time::Duration};
+
+use budget_chat::server::{BudgetChat, ServerConfig};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::timeout,
+};
+
+pub const WAIT: Duration = Duration::from_secs(10);
+
+/// Start a server with the given config, and get back where members connect to it.
+pub async fn start(config: ServerConfig) -> SocketAddr {
+    start_with(config, |chat| chat).await
+}
+
+/// Same as [start], but lets the test add bots, gateways or links before the server runs.
+pub async fn start_with(
+    config: ServerConfig,
+    with: impl FnOnce(BudgetChat) -> BudgetChat,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(with(BudgetChat::new(listener, config)).run());
+    addr
+}
+
+/// Someone on a raw TCP connection, speaking the protocol a line at a time.
+pub struct Member {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+}
+
+impl Member {
+    /// Connect, and read the welcome.
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let (read_half, write_half) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut member = Self {
+            lines: BufReader::new(read_half).lines(),
+            write_half,
+        };
+        member.next_line().await;
+        member
+    }
+
+    /// Connect and give a name, leaving whatever the server says back to the test.
+    pub async fn named(addr: SocketAddr, name: &str) -> Self {
+        let mut member = Self::connect(addr).await;
+        member.say(name).await;
+        member
+    }
+
+    /// Connect and give a name, and read up to the listing of who's in the lobby.
+    pub async fn join(addr: SocketAddr, name: &str) -> Self {
+        let mut member = Self::named(addr, name).await;
+        member.next_line().await;
+        member
+    }
+
+    pub async fn say(&mut self, line: &str) {
+        self.write_half
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    /// The next line the server sends, or nothing once it hangs up.
+    pub async fn read_line(&mut self) -> Option<String> {
+        timeout(WAIT, self.lines.next_line())
+            .await
+            .expect("Waited too long for a line")
+            .unwrap()
+    }
+
+    pub async fn next_line(&mut self) -> String {
+        self.read_line().await.expect("Server hung up")
+    }
+
+    pub async fn expect(&mut self, expected: &str) {
+        assert_eq!(self.next_line().await, expected);
+    }
+
+    /// Read lines up to and including the given one, and return everything read before it.
+    pub async fn wait_for(&mut self, expected: &str) -> Vec<String> {
+        let mut before = vec![];
+        loop {
+            let line = self.next_line().await;
+            if line == expected {
+                return before;
+            }
+            before.push(line);
+        }
+    }
+
+    /// Ask for a name that was just let go of, waiting for the server to notice if need be.
+    /// Returns what the server said back, once it isn't that the name is taken.
+    pub async fn ask_for(&mut self, name: &str) -> String {
+        loop {
+            self.say(name).await;
+            let reply = self.next_line().await;
+            if !reply.starts_with("Somebody is already called") {
+                return reply;
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! `/msg` reaches a single member wherever they are, and nobody else.
+
+mod common;
+
+use budget_chat::server::ServerConfig;
+use common::{start, Member};
+
+#[tokio::test]
+async fn direct_messages_reach_only_their_recipient() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+    let mut carol = Member::join(addr, "carol").await;
+    alice.expect("* carol has entered the room").await;
+    bob.expect("* carol has entered the room").await;
+
+    alice.say("/msg bob  psst, over here ").await;
+    bob.expect("[alice -> bob] psst, over here").await;
+
+    // Rooms don't get in the way.
+    carol.say("/join games").await;
+    carol.expect("* The room contains: ").await;
+    bob.say("/msg carol are you coming back?").await;
+    carol.expect("[bob -> carol] are you coming back?").await;
+
+    // Neither alice nor bob heard anything they weren't meant to before this.
+    carol.say("/leave").await;
+    alice.expect("* carol has left the room").await;
+    alice.expect("* carol has entered the room").await;
+    bob.expect("* carol has left the room").await;
+    bob.expect("* carol has entered the room").await;
+}
+
+#[tokio::test]
+async fn direct_messages_to_nobody_are_explained() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+
+    alice.say("/msg zed hello?").await;
+    alice.expect("* Nobody called zed is here").await;
+    alice.say("/msg zed").await;
+    alice.expect("* Usage: /msg <name> <text>").await;
+
+    alice.say("/msg alice note to self").await;
+    alice.expect("[alice -> alice] note to self").await;
+}
+
+#[tokio::test]
+async fn direct_messages_find_their_recipient_in_any_case() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "Bob").await;
+    alice.expect("* Bob has entered the room").await;
+
+    alice.say("/msg bob psst").await;
+    bob.expect("[alice -> Bob] psst").await;
+    bob.say("/whois ALICE").await;
+    let whois = bob.next_line().await;
+    assert!(
+        whois.starts_with("* alice is in lobby, joined "),
+        "{}",
+        whois
+    );
+}
@@ -0,0 +1,263 @@
+//! Linked servers share their rooms: members on any of them see each other come and go
+//! and hear what each other say, and lose sight of each other when a link drops.
+
+mod common;
+
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use budget_chat::{
+    federation::{self, Event, EventKind, FederationConfig, Frame},
+    server::ServerConfig,
+};
+use common::{start_with, Member, WAIT};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::timeout,
+};
+
+const SECRET: &str = "hunter2";
+
+/// A server linked to the given peers, and listening for links of its own.
+/// Returns where members connect, and where servers link.
+async fn start(name: &str, peers: &[SocketAddr]) -> (SocketAddr, SocketAddr) {
+    let federation_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let links = federation_listener.local_addr().unwrap();
+    let config = peers.iter().cloned().fold(
+        FederationConfig::new(name, SECRET).with_reconnect_delay(Duration::from_millis(100)),
+        FederationConfig::with_peer,
+    );
+    let addr = start_with(ServerConfig::default(), |chat| {
+        chat.with_federation(config, Some(federation_listener))
+    })
+    .await;
+    (addr, links)
+}
+
+/// Read lines until every one of the given members has been in the room with us.
+async fn wait_to_meet(member: &mut Member, names: &[&str]) {
+    let mut met = HashSet::new();
+    while !names.iter().all(|name| met.contains(*name)) {
+        let line = member.next_line().await;
+        if let Some(members) = line.strip_prefix("* The room contains: ") {
+            met.extend(members.split(", ").map(str::to_string));
+        }
+        if let Some(name) = line.strip_suffix(" has entered the room") {
+            met.insert(name.trim_start_matches("* ").to_string());
+        }
+    }
+}
+
+/// A link as some other server would make it, for poking at a server directly.
+struct Link {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+}
+
+impl Link {
+    async fn send(&mut self, frame: &Frame) {
+        let line = format!("{}\n", serde_json::to_string(frame).unwrap());
+        self.write_half.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Option<Frame> {
+        let line = timeout(WAIT, self.lines.next_line())
+            .await
+            .expect("Waited too long for a frame")
+            .ok()??;
+        Some(serde_json::from_str(&line).unwrap())
+    }
+
+    /// Link to a server as `server`, claiming to know the given secret.
+    async fn connect(addr: SocketAddr, server: &str, secret: &str) -> Self {
+        let (read_half, write_half) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut link = Self {
+            lines: BufReader::new(read_half).lines(),
+            write_half,
+        };
+        link.send(&Frame::Hello {
+            server: server.to_string(),
+            nonce: "not-very-random".to_string(),
+        })
+        .await;
+        let Some(Frame::Hello { nonce, .. }) = link.recv().await else {
+            panic!("Server didn't say hello");
+        };
+        link.send(&Frame::Auth {
+            proof: federation::prove(secret, &nonce, server),
+        })
+        .await;
+        link
+    }
+
+    /// Link to a server as `elsewhere`, knowing the secret, and wait for it to prove it does too.
+    async fn establish(addr: SocketAddr) -> Self {
+        let mut link = Self::connect(addr, "elsewhere", SECRET).await;
+        let Some(Frame::Auth { .. }) = link.recv().await else {
+            panic!("Server didn't prove it knows the secret");
+        };
+        link
+    }
+
+    async fn send_event(&mut self, seq: u64, name: &str, kind: EventKind) {
+        let event = Event {
+            origin: "elsewhere".to_string(),
+            run: 0,
+            seq,
+            server: "elsewhere".to_string(),
+            name: name.to_string(),
+            kind,
+        };
+        self.send(&Frame::Event { event }).await;
+    }
+}
+
+fn join(room: &str) -> EventKind {
+    EventKind::Join {
+        room: room.to_string(),
+    }
+}
+
+fn chat(room: &str, text: &str) -> EventKind {
+    EventKind::Chat {
+        room: room.to_string(),
+        text: text.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn members_on_linked_servers_share_rooms() {
+    let (a, a_links) = start("a", &[]).await;
+    let (b, _) = start("b", &[a_links]).await;
+
+    let mut alice = Member::named(a, "alice").await;
+    let mut bob = Member::named(b, "bob").await;
+    wait_to_meet(&mut alice, &["bob"]).await;
+    wait_to_meet(&mut bob, &["alice"]).await;
+
+    bob.say("hi alice").await;
+    alice.wait_for("[bob] hi alice").await;
+    alice.say("hi bob").await;
+    bob.wait_for("[alice] hi bob").await;
+
+    bob.say("/join games").await;
+    alice.wait_for("* bob has left the room").await;
+}
+
+#[tokio::test]
+async fn events_go_around_a_loop_of_links_only_once() {
+    let (a, a_links) = start("a", &[]).await;
+    let (b, b_links) = start("b", &[a_links]).await;
+    let (c, _) = start("c", &[a_links, b_links]).await;
+
+    let mut alice = Member::named(a, "alice").await;
+    let mut bob = Member::named(b, "bob").await;
+    let mut carol = Member::named(c, "carol").await;
+    wait_to_meet(&mut alice, &["bob", "carol"]).await;
+    wait_to_meet(&mut bob, &["alice", "carol"]).await;
+    wait_to_meet(&mut carol, &["alice", "bob"]).await;
+
+    carol.say("hello").await;
+    bob.wait_for("[carol] hello").await;
+    // Anything carol said that bob's server passed on to alice's got there before this.
+    bob.say("done").await;
+    let before = alice.wait_for("[bob] done").await;
+    let hellos = before
+        .iter()
+        .filter(|line| *line == "[carol] hello")
+        .count();
+    assert_eq!(
+        hellos, 1,
+        "Alice heard carol {} times: {:?}",
+        hellos, before
+    );
+}
+
+#[tokio::test]
+async fn colliding_names_are_qualified_with_their_server() {
+    let (a, a_links) = start("a", &[]).await;
+    let mut alice = Member::named(a, "alice").await;
+    alice.wait_for("* The room contains: ").await;
+
+    let mut link = Link::establish(a_links).await;
+    link.send_event(1, "alice", join("lobby")).await;
+    alice
+        .wait_for("* alice@elsewhere has entered the room")
+        .await;
+    link.send_event(2, "alice", chat("lobby", "which one of us is real"))
+        .await;
+    alice
+        .wait_for("[alice@elsewhere] which one of us is real")
+        .await;
+}
+
+#[tokio::test]
+async fn names_taken_on_linked_servers_cant_be_picked() {
+    let (a, a_links) = start("a", &[]).await;
+    let mut alice = Member::named(a, "alice").await;
+    alice.wait_for("* The room contains: ").await;
+
+    let mut link = Link::establish(a_links).await;
+    link.send_event(1, "zed", join("lobby")).await;
+    alice.wait_for("* zed has entered the room").await;
+
+    let mut zed = Member::named(a, "zed").await;
+    let hung_up = zed.read_line().await;
+    assert_eq!(hung_up, None, "Got in as zed");
+}
+
+#[tokio::test]
+async fn members_behind_a_dropped_link_leave() {
+    let (a, a_links) = start("a", &[]).await;
+    let mut alice = Member::named(a, "alice").await;
+    alice.wait_for("* The room contains: ").await;
+
+    let mut link = Link::establish(a_links).await;
+    link.send_event(1, "zed", join("lobby")).await;
+    alice.wait_for("* zed has entered the room").await;
+    link.send_event(2, "zed", chat("lobby", "bye")).await;
+    alice.wait_for("[zed] bye").await;
+
+    drop(link);
+    alice
+        .wait_for("* Netsplit: lost the link to elsewhere")
+        .await;
+    alice.wait_for("* zed has left the room").await;
+}
+
+#[tokio::test]
+async fn linking_without_the_secret_is_refused() {
+    let (_, a_links) = start("a", &[]).await;
+    let mut link = Link::connect(a_links, "elsewhere", "guess").await;
+    // The server still proves itself, but hangs up once it sees we can't.
+    let Some(Frame::Auth { .. }) = link.recv().await else {
+        panic!("Server didn't prove it knows the secret");
+    };
+    assert_eq!(link.recv().await, None);
+}
+
+#[tokio::test]
+async fn endless_frames_are_turned_down_before_the_handshake_times_out() {
+    let (_, a_links) = start("a", &[]).await;
+    let (read_half, write_half) = TcpStream::connect(a_links).await.unwrap().into_split();
+    let mut link = Link {
+        lines: BufReader::new(read_half).lines(),
+        write_half,
+    };
+    let Some(Frame::Hello { .. }) = link.recv().await else {
+        panic!("Server didn't say hello");
+    };
+    // The server hangs up partway through, so this might not all make it.
+    let endless = "a".repeat(2 * FederationConfig::MAX_FRAME_BYTES);
+    _ = link.write_half.write_all(endless.as_bytes()).await;
+    let hung_up = timeout(
+        FederationConfig::HANDSHAKE_TIMEOUT / 2,
+        link.lines.next_line(),
+    )
+    .await
+    .expect("Server kept reading the frame");
+    assert!(!matches!(hung_up, Ok(Some(_))), "{:?}", hung_up);
+}
@@ -0,0 +1,130 @@
+//! A member's token bucket lets a burst through, then warns, mutes and finally disconnects
+//! them if they keep at it, and forgives them once they've calmed down.
+
+use std::time::{Duration, Instant};
+
+use budget_chat::flood::{FloodGuard, FloodPolicy, Verdict};
+
+fn policy() -> FloodPolicy {
+    FloodPolicy::default()
+        .with_burst(3)
+        .with_rate(1.0)
+        .with_mute_after(2)
+        .with_mute_for(Duration::from_secs(10))
+        .with_disconnect_after(2)
+}
+
+/// Send a line at each of the given times, and get back what was made of each.
+fn verdicts(guard: &mut FloodGuard, times: &[Instant]) -> Vec<Verdict> {
+    times.iter().map(|&at| guard.check_at(at)).collect()
+}
+
+#[test]
+fn bursts_are_let_through_and_then_warned_about() {
+    let mut guard = FloodGuard::new(policy());
+    let now = Instant::now();
+    assert_eq!(
+        verdicts(&mut guard, &[now, now, now, now]),
+        [
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Warn
+        ]
+    );
+    // A second later the member has earned one more line.
+    let later = now + Duration::from_secs(1);
+    assert_eq!(verdicts(&mut guard, &[later]), [Verdict::Allow]);
+}
+
+#[test]
+fn flooding_mutes_and_then_disconnects() {
+    let mut guard = FloodGuard::new(policy());
+    let now = Instant::now();
+    assert_eq!(
+        verdicts(&mut guard, &[now, now, now, now, now, now]),
+        [
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Warn,
+            Verdict::Mute(Duration::from_secs(10)),
+            Verdict::Muted,
+        ]
+    );
+
+    // Plenty of tokens by the time the mute is over, but this isn't their first offence.
+    let unmuted = now + Duration::from_secs(10);
+    assert_eq!(
+        verdicts(&mut guard, &[unmuted, unmuted, unmuted, unmuted, unmuted]),
+        [
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Warn,
+            Verdict::Disconnect,
+        ]
+    );
+}
+
+#[test]
+fn a_full_bucket_forgives_strikes() {
+    let mut guard = FloodGuard::new(policy());
+    let now = Instant::now();
+    assert_eq!(
+        verdicts(&mut guard, &[now, now, now, now]),
+        [
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Warn
+        ]
+    );
+
+    // Long enough to fill the bucket back up, so the earlier warning is forgotten.
+    let calmer = now + Duration::from_secs(3);
+    assert_eq!(
+        verdicts(&mut guard, &[calmer, calmer, calmer, calmer]),
+        [
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Allow,
+            Verdict::Warn
+        ]
+    );
+}
+
+#[test]
+fn time_going_backwards_earns_nothing() {
+    let mut guard = FloodGuard::new(policy());
+    let now = Instant::now() + Duration::from_secs(60);
+    assert_eq!(
+        verdicts(&mut guard, &[now, now, now]),
+        [Verdict::Allow, Verdict::Allow, Verdict::Allow]
+    );
+    let earlier = now - Duration::from_secs(30);
+    assert_eq!(verdicts(&mut guard, &[earlier]), [Verdict::Warn]);
+}
+
+#[test]
+fn policies_never_go_below_one() {
+    let zeroes = FloodPolicy::default()
+        .with_burst(0)
+        .with_mute_after(0)
+        .with_disconnect_after(0);
+    assert_eq!(
+        zeroes,
+        FloodPolicy::default()
+            .with_burst(1)
+            .with_mute_after(1)
+            .with_disconnect_after(1)
+    );
+
+    // Somebody can always say something.
+    let mut guard = FloodGuard::new(zeroes);
+    let now = Instant::now();
+    assert_eq!(
+        verdicts(&mut guard, &[now, now]),
+        [Verdict::Allow, Verdict::Disconnect]
+    );
+}
@@ -0,0 +1,86 @@
+//! Rooms remember the last few things said in them, and replay them to members as they enter.
+
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use budget_chat::{
+    history::{History, HistoryConfig},
+    server::ServerConfig,
+};
+use common::{start, Member};
+
+fn messages(history: &History) -> Vec<&str> {
+    history
+        .recent()
+        .map(|entry| entry.message.as_str())
+        .collect()
+}
+
+#[test]
+fn only_the_most_recent_messages_are_kept() {
+    let mut history = History::new(HistoryConfig::new(2));
+    for message in ["[alice] one", "[bob] two", "[alice] three"] {
+        history.record(message);
+    }
+    assert_eq!(history.len(), 2);
+    assert_eq!(messages(&history), ["[bob] two", "[alice] three"]);
+}
+
+#[test]
+fn no_capacity_means_no_history() {
+    let mut history = History::new(HistoryConfig::default());
+    history.record("[alice] anyone?");
+    assert!(history.is_empty());
+    assert_eq!(history.recent().count(), 0);
+}
+
+#[test]
+fn old_messages_are_never_replayed() {
+    let mut history = History::new(HistoryConfig::new(10).with_max_age(Duration::from_secs(60)));
+    let now = SystemTime::now();
+    history.record_at(now - Duration::from_secs(3600), "[alice] an hour ago");
+    history.record_at(now - Duration::from_secs(10), "[bob] just now");
+    // The clock went backwards since this was said, which shouldn't hide it.
+    history.record_at(now + Duration::from_secs(3600), "[carol] from the future");
+    assert_eq!(history.len(), 3);
+    assert_eq!(
+        messages(&history),
+        ["[bob] just now", "[carol] from the future"]
+    );
+}
+
+#[tokio::test]
+async fn members_are_caught_up_as_they_enter() {
+    let addr = start(ServerConfig {
+        history: HistoryConfig::new(2),
+        ..Default::default()
+    })
+    .await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    for line in ["one", "two", "three"] {
+        alice.say(line).await;
+        bob.expect(&format!("[alice] {}", line)).await;
+    }
+
+    let mut carol = Member::named(addr, "carol").await;
+    // Who's in the room comes in no particular order.
+    let listing = carol.next_line().await;
+    assert!(
+        listing == "* The room contains: alice, bob"
+            || listing == "* The room contains: bob, alice",
+        "{}",
+        listing
+    );
+    carol.expect("* [history] [alice] two").await;
+    carol.expect("* [history] [alice] three").await;
+
+    // What was said in the lobby stays in the lobby.
+    carol.say("/join games").await;
+    carol.expect("* The room contains: ").await;
+    carol.say("/rooms").await;
+    carol.expect("* Rooms: games (1), lobby (2)").await;
+}
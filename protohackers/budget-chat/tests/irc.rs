@@ -0,0 +1,230 @@
+//! IRC clients register like any other member, see rooms as channels, and chat with
+//! raw TCP members in the same rooms.
+
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use budget_chat::{
+    bot::Dice,
+    flood::FloodPolicy,
+    irc::IrcMessage,
+    names::{is_display_name, qualified},
+    server::ServerConfig,
+};
+use common::{Member, WAIT};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::timeout,
+};
+
+/// Start a server with dice sitting in the lobby, and get back where raw TCP members
+/// and IRC clients connect to it.
+async fn start(config: ServerConfig) -> (SocketAddr, SocketAddr) {
+    let irc_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let irc = irc_listener.local_addr().unwrap();
+    let addr = common::start_with(config, |chat| {
+        chat.with_bot("dice", "lobby", Dice).with_irc(irc_listener)
+    })
+    .await;
+    (addr, irc)
+}
+
+/// An IRC client, speaking the protocol a line at a time.
+struct Irc {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+}
+
+impl Irc {
+    /// Connect and register, reading up to the end of the welcome.
+    async fn register(addr: SocketAddr, nick: &str) -> Self {
+        let (read_half, write_half) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut irc = Self {
+            lines: BufReader::new(read_half).lines(),
+            write_half,
+        };
+        irc.say(&format!("NICK {}", nick)).await;
+        irc.say(&format!("USER {} 0 * :{}", nick, nick)).await;
+        irc.expect(&format!(
+            ":budget-chat 001 {} :Welcome to budgetchat, {}",
+            nick, nick
+        ))
+        .await;
+        irc.wait_for(&format!(":budget-chat 422 {} :MOTD File is missing", nick))
+            .await;
+        irc
+    }
+
+    async fn say(&mut self, line: &str) {
+        self.write_half
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    async fn next_line(&mut self) -> String {
+        timeout(WAIT, self.lines.next_line())
+            .await
+            .expect("Waited too long for a line")
+            .unwrap()
+            .expect("Server hung up")
+            .trim_end_matches('\r')
+            .to_string()
+    }
+
+    async fn expect(&mut self, expected: &str) {
+        assert_eq!(self.next_line().await, expected);
+    }
+
+    async fn wait_for(&mut self, expected: &str) {
+        while self.next_line().await != expected {}
+    }
+}
+
+#[test]
+fn messages_are_parsed_and_shown_as_they_were() {
+    let line = ":alice!alice@budget-chat PRIVMSG #lobby :hi there";
+    let message = IrcMessage::parse(line).unwrap();
+    assert_eq!(message.prefix.as_deref(), Some("alice!alice@budget-chat"));
+    assert_eq!(message.command, "PRIVMSG");
+    assert_eq!(message.params, ["#lobby", "hi there"]);
+    assert_eq!(message.to_string(), line);
+
+    let message = IrcMessage::parse("join #games\r\n").unwrap();
+    assert_eq!(message.command, "JOIN");
+    assert_eq!(message.to_string(), "JOIN #games");
+    assert_eq!(IrcMessage::parse("   "), None);
+}
+
+#[test]
+fn names_are_told_apart_from_sentences() {
+    for name in ["alice", "bob42", "dice", &qualified("alice", "elsewhere")] {
+        assert!(is_display_name(name), "{}", name);
+    }
+    for name in [
+        "",
+        "dice (bot)",
+        "The room",
+        "alice@",
+        "@elsewhere",
+        "al ice@here",
+    ] {
+        assert!(!is_display_name(name), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn irc_clients_and_raw_members_share_rooms() {
+    let (addr, irc) = start(ServerConfig::default()).await;
+
+    let mut alice = Irc::register(irc, "alice").await;
+    alice.expect(":alice!alice@budget-chat JOIN #lobby").await;
+    // Bots are named like anybody else, not the way raw members see them listed.
+    alice
+        .expect(":budget-chat 353 alice = #lobby :alice dice")
+        .await;
+    alice
+        .expect(":budget-chat 366 alice #lobby :End of /NAMES list")
+        .await;
+
+    let mut bob = Member::named(addr, "bob").await;
+    bob.expect("* The room contains: alice, dice (bot)").await;
+    alice.expect(":bob!bob@budget-chat JOIN #lobby").await;
+
+    bob.say("hi alice").await;
+    alice
+        .expect(":bob!bob@budget-chat PRIVMSG #lobby :hi alice")
+        .await;
+    alice.say("PRIVMSG #lobby :hi bob").await;
+    bob.expect("[alice] hi bob").await;
+    alice.say("PRIVMSG bob :psst").await;
+    bob.expect("[alice -> bob] psst").await;
+    bob.say("/msg alice right back").await;
+    alice
+        .expect(":bob!bob@budget-chat PRIVMSG alice :right back")
+        .await;
+
+    alice.say("NAMES").await;
+    alice
+        .expect(":budget-chat 353 alice = #lobby :alice bob dice")
+        .await;
+    alice
+        .expect(":budget-chat 366 alice #lobby :End of /NAMES list")
+        .await;
+
+    alice.say("JOIN #games").await;
+    bob.expect("* alice has left the room").await;
+    alice.expect(":alice!alice@budget-chat PART #lobby").await;
+    alice.expect(":alice!alice@budget-chat JOIN #games").await;
+    alice.expect(":budget-chat 353 alice = #games alice").await;
+    alice
+        .expect(":budget-chat 366 alice #games :End of /NAMES list")
+        .await;
+    alice.say("PRIVMSG #lobby :anyone?").await;
+    alice
+        .expect(":budget-chat 404 alice #lobby :Cannot send to channel")
+        .await;
+    alice.say("JOIN #games!").await;
+    alice
+        .expect(":budget-chat 403 alice #games! :No such channel")
+        .await;
+
+    bob.say("/join games").await;
+    bob.expect("* The room contains: alice").await;
+    alice.expect(":bob!bob@budget-chat JOIN #games").await;
+
+    alice.say("PART #games").await;
+    bob.expect("* alice has left the room").await;
+    alice.expect(":alice!alice@budget-chat PART #games").await;
+    alice.expect(":alice!alice@budget-chat JOIN #lobby").await;
+    alice
+        .expect(":budget-chat 353 alice = #lobby :alice dice")
+        .await;
+    alice
+        .expect(":budget-chat 366 alice #lobby :End of /NAMES list")
+        .await;
+    alice.say("PART #lobby").await;
+    alice
+        .expect(":budget-chat NOTICE #lobby :Everybody is always somewhere, so the lobby can't be left. JOIN another channel instead")
+        .await;
+
+    alice.say("QUIT").await;
+    alice.expect("ERROR :Closing link").await;
+    bob.say("/join lobby").await;
+    bob.expect("* The room contains: dice (bot)").await;
+}
+
+#[tokio::test]
+async fn channels_follow_the_rooms_members_actually_enter() {
+    let (_, irc) = start(ServerConfig {
+        flood_policy: Some(FloodPolicy::default().with_burst(1).with_rate(5.0)),
+        ..Default::default()
+    })
+    .await;
+    let mut alice = Irc::register(irc, "alice").await;
+    alice
+        .wait_for(":budget-chat 366 alice #lobby :End of /NAMES list")
+        .await;
+
+    // The second of these is one line too many, so it never happens.
+    alice.say("JOIN #games").await;
+    alice.say("JOIN #chess").await;
+    alice.expect(":alice!alice@budget-chat PART #lobby").await;
+    alice.expect(":alice!alice@budget-chat JOIN #games").await;
+    alice
+        .wait_for(":budget-chat 366 alice #games :End of /NAMES list")
+        .await;
+    alice
+        .expect(":budget-chat NOTICE alice :Slow down! You are sending messages too fast")
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    alice.say("JOIN #music").await;
+    alice.expect(":alice!alice@budget-chat PART #games").await;
+    alice.expect(":alice!alice@budget-chat JOIN #music").await;
+}
@@ -0,0 +1,191 @@
+//! Operators can kick, ban and mute members, and bans outlive the server if there's somewhere to keep them.
+
+mod common;
+
+use std::{net::SocketAddr, path::PathBuf, time::Instant};
+
+use budget_chat::{
+    moderation::{BanTarget, Bans, Operators},
+    server::ServerConfig,
+};
+use common::{Member, WAIT};
+
+/// Somewhere to keep bans for a single test, starting out empty.
+fn bans_file(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "budget-chat-{}-{}-bans.json",
+        test,
+        std::process::id()
+    ));
+    _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn start(bans: Bans) -> SocketAddr {
+    common::start(ServerConfig {
+        operators: Operators::default().with_password("alice", "hunter2"),
+        bans,
+        ..Default::default()
+    })
+    .await
+}
+
+/// Join as alice, and become an operator.
+async fn operator(addr: SocketAddr) -> Member {
+    let mut alice = Member::join(addr, "alice").await;
+    alice.say("/op hunter2").await;
+    alice.expect("* You are now an operator").await;
+    alice
+}
+
+#[test]
+fn only_the_right_password_makes_an_operator() {
+    let operators = Operators::default().with_password("alice", "hunter2");
+    assert!(operators.check_password("alice", "hunter2"));
+    assert!(!operators.check_password("alice", "hunter"));
+    assert!(!operators.check_password("alice", "hunter22"));
+    assert!(!operators.check_password("bob", "hunter2"));
+}
+
+#[tokio::test]
+async fn only_operators_moderate() {
+    let addr = start(Bans::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    alice.say("/kick alice").await;
+    alice.expect("* Only operators can do that").await;
+    alice.say("/op password1").await;
+    alice
+        .expect("* That's not the operator password for alice")
+        .await;
+}
+
+#[tokio::test]
+async fn kicked_members_are_disconnected() {
+    let addr = start(Bans::default()).await;
+    let mut alice = operator(addr).await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    alice.say("/kick zed").await;
+    alice.expect("* Nobody called zed is here").await;
+    alice.say("/kick BOB").await;
+    alice.expect("* bob was kicked by alice").await;
+    bob.wait_for("* You were kicked by alice").await;
+    assert_eq!(bob.read_line().await, None);
+    alice.expect("* bob has left the room").await;
+
+    // Nothing stops them coming back.
+    Member::connect(addr).await.ask_for("bob").await;
+    alice.expect("* bob has entered the room").await;
+}
+
+#[tokio::test]
+async fn muted_members_are_heard_by_nobody() {
+    let addr = start(Bans::default()).await;
+    let mut alice = operator(addr).await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    alice.say("/mute Bob 1h").await;
+    alice.expect("* bob was muted by alice for 1h").await;
+    bob.expect("* bob was muted by alice for 1h").await;
+    bob.say("let me speak").await;
+    bob.expect("* You are muted").await;
+    bob.say("/msg alice psst").await;
+    bob.expect("* You are muted").await;
+
+    // Had bob been heard, alice would hear them before she hears from herself.
+    alice.say("/msg alice still here").await;
+    alice.expect("[alice -> alice] still here").await;
+}
+
+#[tokio::test]
+async fn mutes_last_until_lifted_even_across_reconnects() {
+    let addr = start(Bans::default()).await;
+    let mut alice = operator(addr).await;
+    let bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    alice.say("/mute bob").await;
+    alice.expect("* bob was muted by alice").await;
+    drop(bob);
+    alice.expect("* bob has left the room").await;
+
+    let mut bob = Member::connect(addr).await;
+    bob.ask_for("bob").await;
+    alice.expect("* bob has entered the room").await;
+    bob.say("I'm back").await;
+    bob.expect("* You are muted").await;
+
+    alice.say("/unmute bob").await;
+    alice.expect("* bob was unmuted by alice").await;
+    bob.expect("* bob was unmuted by alice").await;
+    bob.say("I'm back").await;
+    alice.expect("[bob] I'm back").await;
+    alice.say("/unmute bob").await;
+    alice.expect("* bob isn't muted").await;
+}
+
+#[tokio::test]
+async fn bans_keep_members_out_until_lifted() {
+    let addr = start(Bans::default()).await;
+    let mut alice = operator(addr).await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    alice.say("/ban bob").await;
+    alice.expect("* bob was banned by alice").await;
+    bob.wait_for("* You were banned by alice").await;
+    assert_eq!(bob.read_line().await, None);
+    alice.say("/msg alice caught up").await;
+    let heard = alice.wait_for("[alice -> alice] caught up").await;
+    assert!(heard.contains(&"* Banned bob".to_string()));
+    assert!(heard.contains(&"* bob has left the room".to_string()));
+
+    let mut bob = Member::connect(addr).await;
+    assert_eq!(
+        bob.ask_for("bob").await,
+        "You are banned from this server: bob"
+    );
+    assert_eq!(bob.read_line().await, None);
+    // Names that only differ in case are the same name, so the ban covers them too.
+    let mut bob = Member::connect(addr).await;
+    assert_eq!(
+        bob.ask_for("Bob").await,
+        "You are banned from this server: Bob"
+    );
+    assert_eq!(bob.read_line().await, None);
+
+    alice.say("/unban BOB").await;
+    alice.expect("* Unbanned BOB").await;
+    alice.say("/unban bob").await;
+    alice.expect("* bob isn't banned").await;
+    let mut bob = Member::named(addr, "bob").await;
+    bob.expect("* The room contains: alice").await;
+}
+
+#[tokio::test]
+async fn bans_survive_a_restart() {
+    let path = bans_file("restart");
+    let before = start(Bans::load(&path).unwrap()).await;
+    let mut alice = operator(before).await;
+    alice.say("/ban bob").await;
+    alice.expect("* Banned bob").await;
+
+    // Bans are saved in the background, so give that a moment to land.
+    let started = Instant::now();
+    while !Bans::load(&path)
+        .unwrap()
+        .is_banned(&BanTarget::Name("bob".to_string()))
+    {
+        assert!(started.elapsed() < WAIT, "Ban was never saved");
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // A fresh server reading the same bans, as if the first one had been restarted.
+    let after = start(Bans::load(&path).unwrap()).await;
+    let mut bob = Member::named(after, "bob").await;
+    bob.expect("You are banned from this server: bob").await;
+    assert_eq!(bob.read_line().await, None);
+    _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,147 @@
+//! Names are checked against the policy and handed out to exactly one member at a time,
+//! whatever case they're asked for in.
+
+mod common;
+
+use std::sync::Barrier;
+
+use budget_chat::{
+    names::{ClaimedNames, NamePolicy},
+    server::ServerConfig,
+    ClientInitializationError,
+};
+use common::{start, Member};
+use tokio::task::JoinSet;
+
+#[test]
+fn names_are_checked_against_the_policy() {
+    let policy = NamePolicy::default()
+        .with_max_length(8)
+        .with_reserved(["Admin"]);
+    assert_eq!(policy.check("  alice \t").unwrap(), "alice");
+    assert!(matches!(
+        policy.check("al ice"),
+        Err(ClientInitializationError::InvalidName(_))
+    ));
+    assert!(matches!(
+        policy.check(""),
+        Err(ClientInitializationError::InvalidName(_))
+    ));
+    assert!(matches!(
+        policy.check("alexandra"),
+        Err(ClientInitializationError::NameTooLong { max: 8, .. })
+    ));
+    assert!(matches!(
+        policy.check("ADMIN"),
+        Err(ClientInitializationError::NameReserved(_))
+    ));
+}
+
+#[test]
+fn names_are_unique_whatever_their_case() {
+    let claimed = ClaimedNames::default();
+    claimed.claim("Alice").unwrap();
+    assert!(matches!(
+        claimed.claim("alice"),
+        Err(ClientInitializationError::NameTaken(name)) if name == "alice"
+    ));
+    claimed.release("Alice");
+    claimed.claim("ALICE").unwrap();
+}
+
+#[test]
+fn exactly_one_of_many_racing_claims_wins() {
+    const RACERS: usize = 32;
+    let claimed = ClaimedNames::default();
+    let start = Barrier::new(RACERS);
+    let winners = std::thread::scope(|scope| {
+        let racers: Vec<_> = (0..RACERS)
+            .map(|_| {
+                scope.spawn(|| {
+                    start.wait();
+                    claimed.claim("alice").is_ok()
+                })
+            })
+            .collect();
+        racers
+            .into_iter()
+            .map(|racer| racer.join().unwrap())
+            .filter(|won| *won)
+            .count()
+    });
+    assert_eq!(winners, 1);
+}
+
+#[tokio::test]
+async fn bad_names_are_asked_again_when_reprompting() {
+    let addr = start(ServerConfig {
+        names: NamePolicy::default()
+            .with_reserved(["admin"])
+            .with_reprompt(true),
+        ..Default::default()
+    })
+    .await;
+    let _alice = Member::join(addr, "alice").await;
+
+    let mut bob = Member::connect(addr).await;
+    for (name, reply) in [
+        (
+            "b o b",
+            "Names must be made of only letters and digits: b o b. What shall I call you?",
+        ),
+        (
+            "bobbybobbybobbybob",
+            "Names can be at most 16 characters long: bobbybobbybobbybob. What shall I call you?",
+        ),
+        (
+            "Admin",
+            "Nobody can be called Admin. What shall I call you?",
+        ),
+        (
+            "ALICE",
+            "Somebody is already called ALICE. What shall I call you?",
+        ),
+    ] {
+        bob.say(name).await;
+        bob.expect(reply).await;
+    }
+    bob.say("bob").await;
+    bob.expect("* The room contains: alice").await;
+}
+
+#[tokio::test]
+async fn bad_names_are_hung_up_on_otherwise() {
+    let addr = start(ServerConfig::default()).await;
+    let _alice = Member::join(addr, "alice").await;
+
+    let mut imposter = Member::named(addr, "Alice").await;
+    assert_eq!(imposter.read_line().await, None);
+    let mut rude = Member::named(addr, "!!!").await;
+    assert_eq!(rude.read_line().await, None);
+}
+
+#[tokio::test]
+async fn exactly_one_of_many_racing_members_gets_a_name() {
+    let addr = start(ServerConfig::default()).await;
+    let mut racers = JoinSet::new();
+    for racer in 0..32 {
+        // Every other one shouts, which makes no difference to who gets the name.
+        let name = match racer % 2 {
+            0 => "alice",
+            _ => "ALICE",
+        };
+        racers.spawn(async move {
+            let mut member = Member::named(addr, name).await;
+            let got_in = member.read_line().await.is_some();
+            (got_in, member)
+        });
+    }
+    let mut winners = vec![];
+    while let Some(result) = racers.join_next().await {
+        let (got_in, member) = result.unwrap();
+        if got_in {
+            winners.push(member);
+        }
+    }
+    assert_eq!(winners.len(), 1);
+}
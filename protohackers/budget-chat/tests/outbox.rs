@@ -0,0 +1,118 @@
+//! Every session owns a bounded queue, which the chat rooms fill through handles to it
+//! without ever blocking, whatever the member on the other end is up to.
+
+use std::time::Duration;
+
+use budget_chat::outbox::{
+    Backlog, Outbound, OutboundReceiver, Outbox, OutboxConfig, OverflowPolicy, SessionHandle,
+};
+use tokio::time::timeout;
+
+fn queue(overflow: OverflowPolicy, backlog: &Backlog) -> (SessionHandle, OutboundReceiver) {
+    let config = OutboxConfig::default()
+        .with_limit(2)
+        .with_overflow(overflow);
+    SessionHandle::new(config, backlog.clone())
+}
+
+fn line(text: &str) -> Outbound {
+    Outbound::Line(text.to_string())
+}
+
+/// Everything queued right now, stopping short of waiting for more.
+async fn drain(outbound: &mut OutboundReceiver) -> Vec<Outbound> {
+    let mut messages = vec![];
+    while let Ok(Some(message)) = timeout(Duration::from_millis(10), outbound.recv()).await {
+        messages.push(message);
+    }
+    messages
+}
+
+#[tokio::test]
+async fn full_queues_drop_the_oldest_by_default() {
+    let backlog = Backlog::default();
+    let (session, mut outbound) = queue(OverflowPolicy::DropOldest, &backlog);
+    for text in ["one", "two", "three"] {
+        session.send(text.to_string());
+    }
+    assert_eq!(backlog.queued(), 2);
+    assert_eq!(drain(&mut outbound).await, [line("two"), line("three")]);
+    assert_eq!(backlog.queued(), 0);
+}
+
+#[tokio::test]
+async fn full_queues_can_drop_the_newest_instead() {
+    let backlog = Backlog::default();
+    let (session, mut outbound) = queue(OverflowPolicy::DropNewest, &backlog);
+    for text in ["one", "two", "three"] {
+        session.send(text.to_string());
+    }
+    assert_eq!(drain(&mut outbound).await, [line("one"), line("two")]);
+}
+
+#[tokio::test]
+async fn full_queues_can_hang_up_instead() {
+    let backlog = Backlog::default();
+    let (session, mut outbound) = queue(OverflowPolicy::Disconnect, &backlog);
+    for text in ["one", "two", "three", "four"] {
+        session.send(text.to_string());
+    }
+    timeout(Duration::from_secs(1), outbound.aborted())
+        .await
+        .expect("Queue was never aborted");
+    assert_eq!(outbound.recv().await, None);
+    assert_eq!(backlog.queued(), 0);
+}
+
+#[tokio::test]
+async fn hanging_up_still_lets_the_queue_drain() {
+    let backlog = Backlog::default();
+    let (session, mut outbound) = queue(OverflowPolicy::DropOldest, &backlog);
+    let room = session.clone();
+    room.send("goodbye".to_string());
+    session.hang_up();
+    room.send("too late".to_string());
+    assert_eq!(outbound.recv().await, Some(line("goodbye")));
+    assert_eq!(outbound.recv().await, None);
+}
+
+#[tokio::test]
+async fn queues_are_forgotten_along_with_their_session() {
+    let backlog = Backlog::default();
+    let (session, outbound) = queue(OverflowPolicy::DropOldest, &backlog);
+    session.send("unread".to_string());
+    assert_eq!(backlog.queued(), 1);
+    drop(outbound);
+    assert_eq!(backlog.queued(), 0);
+    session.send("nobody's listening".to_string());
+    assert_eq!(backlog.queued(), 0);
+}
+
+#[tokio::test]
+async fn the_outbox_only_reaches_members_it_has_not_hung_up_on() {
+    let backlog = Backlog::default();
+    let mut outbox = Outbox::new(backlog.clone());
+    let alice = "127.0.0.1:1".parse().unwrap();
+    let bob = "127.0.0.1:2".parse().unwrap();
+    let (session, mut alice_outbound) = queue(OverflowPolicy::DropOldest, &backlog);
+    outbox.register(alice, session);
+    let (session, mut bob_outbound) = queue(OverflowPolicy::DropOldest, &backlog);
+    outbox.register(bob, session);
+
+    outbox.send(alice, "hi alice".to_string());
+    outbox.send(bob, "hi bob".to_string());
+    assert_eq!(outbox.queued_for(&alice), 1);
+    assert_eq!(outbox.queued(), 2);
+
+    outbox.hang_up(alice);
+    assert!(outbox.session(&alice).is_none());
+    outbox.send(alice, "still there?".to_string());
+    assert_eq!(alice_outbound.recv().await, Some(line("hi alice")));
+    assert_eq!(alice_outbound.recv().await, None);
+
+    assert!(!outbox.flush(Duration::ZERO).await);
+    assert_eq!(bob_outbound.recv().await, Some(line("hi bob")));
+    assert!(outbox.flush(Duration::ZERO).await);
+    outbox.hang_up_all();
+    assert_eq!(bob_outbound.recv().await, None);
+}
@@ -0,0 +1,52 @@
+//! Members can see who's around, how long they've been idle, and whether they're away.
+
+mod common;
+
+use budget_chat::server::ServerConfig;
+use common::{start, Member};
+
+#[tokio::test]
+async fn members_see_who_is_here_and_who_is_away() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    assert_eq!(alice.next_line().await, "* bob has entered the room");
+
+    bob.say("/away lunch").await;
+    assert_eq!(bob.next_line().await, "* You are now away: lunch");
+
+    alice.say("/who").await;
+    let who = alice.next_line().await;
+    assert!(who.starts_with("* In lobby: alice (idle "), "{}", who);
+    assert!(who.contains(", bob (away: lunch, idle "), "{}", who);
+
+    alice.say("/msg bob are you there").await;
+    assert_eq!(bob.next_line().await, "[alice -> bob] are you there");
+    assert_eq!(alice.next_line().await, "* bob is away: lunch");
+
+    alice.say("/whois alice").await;
+    let whois = alice.next_line().await;
+    assert!(
+        whois.starts_with("* alice is in lobby, joined "),
+        "{}",
+        whois
+    );
+    assert!(!whois.contains("last message never"), "{}", whois);
+
+    bob.say("/join games").await;
+    bob.say("/whois bob").await;
+    let whois = loop {
+        let line = bob.next_line().await;
+        if line.starts_with("* bob is") {
+            break line;
+        }
+    };
+    assert!(whois.starts_with("* bob is in games, joined "), "{}", whois);
+    assert!(whois.contains("last message never"), "{}", whois);
+    assert!(whois.ends_with(", away: lunch"), "{}", whois);
+
+    bob.say("/back").await;
+    assert_eq!(bob.next_line().await, "* You are back");
+    bob.say("/back").await;
+    assert_eq!(bob.next_line().await, "* You aren't away");
+}
@@ -0,0 +1,85 @@
+//! Members move between named rooms, and only ever hear from those in the same room as them.
+
+mod common;
+
+use budget_chat::{
+    registry::{is_room_name_valid, RoomRegistry},
+    server::ServerConfig,
+};
+use common::{start, Member};
+
+#[test]
+fn room_names_are_short_and_plain() {
+    for name in ["games", "lobby", "rust-lang", "off_topic", "42"] {
+        assert!(is_room_name_valid(name), "{}", name);
+    }
+    let too_long = "a".repeat(RoomRegistry::MAX_ROOM_NAME_LENGTH + 1);
+    for name in ["", "two words", "games!", "café", too_long.as_str()] {
+        assert!(!is_room_name_valid(name), "{}", name);
+    }
+    assert!(is_room_name_valid(
+        &"a".repeat(RoomRegistry::MAX_ROOM_NAME_LENGTH)
+    ));
+}
+
+#[tokio::test]
+async fn members_only_hear_their_own_room() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+    let mut bob = Member::join(addr, "bob").await;
+    alice.expect("* bob has entered the room").await;
+
+    bob.say("/join games").await;
+    alice.expect("* bob has left the room").await;
+    bob.expect("* The room contains: ").await;
+
+    let mut carol = Member::join(addr, "carol").await;
+    alice.expect("* carol has entered the room").await;
+    carol.say("/join games").await;
+    alice.expect("* carol has left the room").await;
+    bob.expect("* carol has entered the room").await;
+    carol.expect("* The room contains: bob").await;
+
+    alice.say("anyone in the lobby?").await;
+    carol.say("hi bob").await;
+    bob.expect("[carol] hi bob").await;
+    bob.say("hi carol").await;
+    // Carol would have heard alice first, had alice's message left the lobby.
+    carol.expect("[bob] hi carol").await;
+
+    alice.say("/rooms").await;
+    alice.expect("* Rooms: games (2), lobby (1)").await;
+
+    bob.say("/leave").await;
+    carol.expect("* bob has left the room").await;
+    alice.expect("* bob has entered the room").await;
+    bob.expect("* The room contains: alice").await;
+
+    // The last one out of a room takes it with them, but the lobby always stays.
+    carol.say("/leave").await;
+    alice.expect("* carol has entered the room").await;
+    carol.say("/rooms").await;
+    carol.wait_for("* Rooms: lobby (3)").await;
+}
+
+#[tokio::test]
+async fn bad_moves_are_explained() {
+    let addr = start(ServerConfig::default()).await;
+    let mut alice = Member::join(addr, "alice").await;
+
+    alice.say("/join lobby").await;
+    alice.expect("* You are already in lobby").await;
+    alice.say("/leave").await;
+    alice.expect("* You are already in lobby").await;
+    alice.say("/join games!").await;
+    alice
+        .expect("* Room names must be 1 to 32 letters, digits, dashes or underscores: games!")
+        .await;
+    alice.say("/join two rooms").await;
+    alice.expect("* Usage: /join <room>").await;
+
+    alice.say("/join games").await;
+    alice.expect("* The room contains: ").await;
+    alice.say("/join games").await;
+    alice.expect("* You are already in games").await;
+}
@@ -0,0 +1,87 @@
+//! Shutting down says goodbye to every member before hanging up on them, and stops letting anyone in.
+
+use std::time::Duration;
+
+use budget_chat::{
+    server::{BudgetChat, ServerConfig},
+    shutdown::ShutdownConfig,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::timeout,
+};
+
+const GOODBYE: &str = "* Back in five minutes";
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Join as a member, and get back what the server says to them, along with the
+/// half of the connection to hold on to for as long as they should stay.
+async fn join(
+    addr: std::net::SocketAddr,
+    name: &str,
+) -> (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+    let (read_half, mut write_half) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    lines.next_line().await.unwrap();
+    write_half
+        .write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    (lines, write_half)
+}
+
+async fn next_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Option<String> {
+    timeout(WAIT, lines.next_line())
+        .await
+        .expect("Waited too long for a line")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn members_are_told_before_being_hung_up_on() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chat = BudgetChat::new(
+        listener,
+        ServerConfig {
+            shutdown: ShutdownConfig::default().with_message(GOODBYE),
+            ..Default::default()
+        },
+    );
+    let shutdown = chat.shutdown_handle();
+    let server = tokio::spawn(chat.run());
+
+    let (mut alice, _alice) = join(addr, "alice").await;
+    assert_eq!(
+        next_line(&mut alice).await.as_deref(),
+        Some("* The room contains: ")
+    );
+    let (mut bob, _bob) = join(addr, "bob").await;
+    assert_eq!(
+        next_line(&mut alice).await.as_deref(),
+        Some("* bob has entered the room")
+    );
+    assert_eq!(
+        next_line(&mut bob).await.as_deref(),
+        Some("* The room contains: alice")
+    );
+
+    shutdown.shutdown();
+    for lines in [&mut alice, &mut bob] {
+        assert_eq!(next_line(lines).await.as_deref(), Some(GOODBYE));
+        assert_eq!(next_line(lines).await, None, "Server didn't hang up");
+    }
+    timeout(WAIT, server)
+        .await
+        .expect("Server didn't stop")
+        .unwrap()
+        .unwrap();
+    assert!(
+        TcpStream::connect(addr).await.is_err(),
+        "Server still lets people in"
+    );
+}
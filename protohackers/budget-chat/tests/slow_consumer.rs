@@ -0,0 +1,165 @@
+//! A member who never reads their socket shouldn't make the server buffer messages for them
+//! forever: their outbound queue stays within its limit no matter how much everyone else says.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use budget_chat::{
+    outbox::{Backlog, OutboxConfig, OverflowPolicy},
+    server::{BudgetChat, ServerConfig},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpSocket, TcpStream,
+    },
+    sync::Semaphore,
+    time::{timeout, Instant},
+};
+
+const LIMIT: usize = 32;
+const MESSAGES: usize = 10_000;
+const MESSAGE_LENGTH: usize = 1_000;
+
+async fn start(overflow: OverflowPolicy) -> (SocketAddr, Backlog) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chat = BudgetChat::new(
+        listener,
+        ServerConfig {
+            outbox: OutboxConfig::default()
+                .with_limit(LIMIT)
+                .with_overflow(overflow),
+            ..Default::default()
+        },
+    );
+    let backlog = chat.backlog().clone();
+    tokio::spawn(chat.run());
+    (addr, backlog)
+}
+
+/// Connect with as small a receive buffer as the OS allows, so the server
+/// runs out of room to write to us quickly.
+async fn connect_without_reading(addr: SocketAddr, name: &str) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(1).unwrap();
+    let mut stream = socket.connect(addr).await.unwrap();
+
+    // Read the welcome a byte at a time, so nothing past it gets pulled off the socket.
+    let mut byte = [0; 1];
+    while byte[0] != b'\n' {
+        stream.read_exact(&mut byte).await.unwrap();
+    }
+    stream
+        .write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    stream
+}
+
+/// Join as a member who reads everything, and return their lines along with a way to talk.
+async fn join(addr: SocketAddr, name: &str) -> (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    lines.next_line().await.unwrap().unwrap();
+    write_half
+        .write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    // Wait for the room listing, so we know we're in.
+    lines.next_line().await.unwrap().unwrap();
+    (lines, write_half)
+}
+
+/// Have one member flood the room while another reads along, keeping track of the most
+/// messages the server was ever holding on to at once. Returns once the reader has seen
+/// the last message.
+///
+/// The flooder never gets more than half the limit ahead of the reader, so only
+/// the member who isn't reading at all can ever fill up their queue.
+async fn flood(addr: SocketAddr, backlog: &Backlog) -> usize {
+    let (mut reader, _reader_writes) = join(addr, "reader").await;
+    let (_flooder_reads, mut flooder) = join(addr, "flooder").await;
+    let window = Arc::new(Semaphore::new(LIMIT / 2));
+
+    let reader_window = window.clone();
+    let read_everything = tokio::spawn(async move {
+        let mut read = 0;
+        while let Some(line) = reader.next_line().await.unwrap() {
+            if line == "[flooder] done" {
+                assert_eq!(read, MESSAGES, "Reader missed some of the flood");
+                return;
+            }
+            if line.starts_with("[flooder]") {
+                read += 1;
+                reader_window.add_permits(1);
+            }
+        }
+        panic!("Reader got disconnected before the flood was over");
+    });
+
+    let send_everything = tokio::spawn(async move {
+        let message = format!("{}\n", "x".repeat(MESSAGE_LENGTH));
+        for _ in 0..MESSAGES {
+            window.acquire().await.unwrap().forget();
+            flooder.write_all(message.as_bytes()).await.unwrap();
+        }
+        flooder.write_all(b"done\n").await.unwrap();
+        flooder
+    });
+
+    let mut most_queued = 0;
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !read_everything.is_finished() {
+        assert!(
+            Instant::now() < deadline,
+            "Flood took too long to go through"
+        );
+        most_queued = most_queued.max(backlog.queued());
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    read_everything.await.unwrap();
+    send_everything.await.unwrap();
+    most_queued
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn dropping_keeps_a_non_reading_member_within_the_limit() {
+    let (addr, backlog) = start(OverflowPolicy::DropOldest).await;
+    let _sloth = connect_without_reading(addr, "sloth").await;
+
+    let most_queued = flood(addr, &backlog).await;
+
+    // Everyone but the sloth keeps up, so the sloth's queue is the only one that can fill up.
+    assert!(
+        most_queued <= 3 * LIMIT,
+        "Server held on to {} messages at once",
+        most_queued
+    );
+    // The sloth really did fall behind, and is still stuck at the limit.
+    assert_eq!(backlog.queued(), LIMIT);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn disconnecting_hangs_up_on_a_non_reading_member() {
+    let (addr, backlog) = start(OverflowPolicy::Disconnect).await;
+    let mut sloth = connect_without_reading(addr, "sloth").await;
+
+    let most_queued = flood(addr, &backlog).await;
+    assert!(
+        most_queued <= 3 * LIMIT,
+        "Server held on to {} messages at once",
+        most_queued
+    );
+    assert_eq!(backlog.queued(), 0);
+
+    // Whatever made it into the socket before we were hung up on is still there to read,
+    // but after that, the server is gone.
+    let mut rest = vec![];
+    timeout(Duration::from_secs(10), sloth.read_to_end(&mut rest))
+        .await
+        .expect("Server never hung up on the sloth")
+        .ok();
+}
@@ -0,0 +1,198 @@
+//! The full screen client keeps track of who's here and what was said, remembers what was
+//! typed, and comes back under the same name whenever the connection drops.
+
+use std::{net::SocketAddr, time::Duration};
+
+use budget_chat::{
+    client::{stay_connected, ConnectionUpdate, ReconnectConfig, ServerEvent},
+    server::{BudgetChat, ServerConfig},
+    shutdown::ShutdownHandle,
+    tui::{author_colour, Action, ChatView, Entry},
+};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::{backend::TestBackend, Terminal};
+use tokio::{net::TcpListener, sync::mpsc, time::timeout};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+fn press(view: &mut ChatView, code: KeyCode) -> Option<Action> {
+    view.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+}
+
+fn type_line(view: &mut ChatView, line: &str) -> Option<Action> {
+    for char in line.chars() {
+        press(view, KeyCode::Char(char));
+    }
+    press(view, KeyCode::Enter)
+}
+
+#[test]
+fn members_follow_listings_joins_and_leaves() {
+    let mut view = ChatView::new("alice");
+    view.apply(ConnectionUpdate::Connected);
+    view.apply(ConnectionUpdate::Event(ServerEvent::RoomListing(vec![
+        "bob".into(),
+        "dice (bot)".into(),
+    ])));
+    view.apply(ConnectionUpdate::Event(ServerEvent::Join("carol".into())));
+    view.apply(ConnectionUpdate::Event(ServerEvent::Leave("bob".into())));
+    assert_eq!(
+        view.members().collect::<Vec<_>>(),
+        ["alice", "carol", "dice (bot)"]
+    );
+
+    view.apply(ConnectionUpdate::Event(ServerEvent::Message {
+        from: "carol".into(),
+        text: "hi".into(),
+    }));
+    view.sent("hello carol");
+    view.sent("/who");
+    let said: Vec<_> = view
+        .scrollback()
+        .filter(|entry| entry.author.is_some())
+        .cloned()
+        .collect();
+    assert_eq!(
+        said,
+        [
+            Entry::said("carol", "hi"),
+            Entry::said("alice", "hello carol")
+        ]
+    );
+
+    view.apply(ConnectionUpdate::Disconnected("The server hung up".into()));
+    assert!(!view.is_connected());
+    assert_eq!(view.members().count(), 0);
+}
+
+#[test]
+fn input_remembers_what_was_sent() {
+    let mut view = ChatView::new("alice");
+    assert_eq!(
+        type_line(&mut view, "first"),
+        Some(Action::Send("first".into()))
+    );
+    assert_eq!(
+        type_line(&mut view, "second"),
+        Some(Action::Send("second".into()))
+    );
+    assert_eq!(type_line(&mut view, "   "), None);
+
+    for char in "draft".chars() {
+        press(&mut view, KeyCode::Char(char));
+    }
+    press(&mut view, KeyCode::Up);
+    assert_eq!(view.input.text(), "second");
+    press(&mut view, KeyCode::Up);
+    press(&mut view, KeyCode::Up);
+    assert_eq!(view.input.text(), "first");
+    press(&mut view, KeyCode::Down);
+    press(&mut view, KeyCode::Down);
+    assert_eq!(view.input.text(), "draft");
+
+    press(&mut view, KeyCode::Home);
+    press(&mut view, KeyCode::Delete);
+    press(&mut view, KeyCode::Char('c'));
+    assert_eq!(view.input.text(), "craft");
+    assert_eq!(press(&mut view, KeyCode::Esc), Some(Action::Quit));
+}
+
+#[test]
+fn everyone_gets_a_colour_of_their_own() {
+    assert_eq!(author_colour("alice"), author_colour("alice"));
+    let colours: std::collections::HashSet<_> = ["alice", "bob", "carol", "dave", "erin"]
+        .into_iter()
+        .map(author_colour)
+        .collect();
+    assert!(colours.len() > 1);
+}
+
+#[test]
+fn screen_shows_scrollback_members_and_input() {
+    let mut view = ChatView::new("alice");
+    view.apply(ConnectionUpdate::Connected);
+    view.apply(ConnectionUpdate::Event(ServerEvent::RoomListing(vec![
+        "bob".into(),
+    ])));
+    view.apply(ConnectionUpdate::Event(ServerEvent::Message {
+        from: "bob".into(),
+        text: "anyone around?".into(),
+    }));
+    for char in "yes".chars() {
+        press(&mut view, KeyCode::Char(char));
+    }
+
+    let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+    terminal.draw(|frame| view.render(frame)).unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    for expected in [
+        "budget-chat: connected",
+        "[bob] anyone around?",
+        "Members (2)",
+        "alice",
+        "yes",
+    ] {
+        assert!(screen.contains(expected), "{} isn't on screen", expected);
+    }
+}
+
+async fn start(listener: TcpListener) -> ShutdownHandle {
+    let chat = BudgetChat::new(listener, ServerConfig::default());
+    let shutdown = chat.shutdown_handle();
+    tokio::spawn(chat.run());
+    shutdown
+}
+
+async fn next_update(updates: &mut mpsc::Receiver<ConnectionUpdate>) -> ConnectionUpdate {
+    timeout(WAIT, updates.recv())
+        .await
+        .expect("Waited too long for an update")
+        .expect("Stopped staying connected")
+}
+
+#[tokio::test]
+async fn reconnects_under_the_same_name() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let shutdown = start(listener).await;
+
+    let config = ReconnectConfig::new(&addr.to_string(), "alice")
+        .with_delays(Duration::from_millis(50), Duration::from_millis(200));
+    let (_outgoing, outgoing_rx) = mpsc::channel(16);
+    let (updates_tx, mut updates) = mpsc::channel(16);
+    tokio::spawn(stay_connected(config, outgoing_rx, updates_tx));
+
+    assert_eq!(next_update(&mut updates).await, ConnectionUpdate::Connected);
+    next_update(&mut updates).await;
+    assert_eq!(
+        next_update(&mut updates).await,
+        ConnectionUpdate::Event(ServerEvent::RoomListing(vec![]))
+    );
+
+    shutdown.shutdown();
+    let disconnected = loop {
+        match next_update(&mut updates).await {
+            ConnectionUpdate::Event(_) => continue,
+            update => break update,
+        }
+    };
+    assert!(matches!(disconnected, ConnectionUpdate::Disconnected(_)));
+
+    let _shutdown = start(TcpListener::bind(addr).await.unwrap()).await;
+    loop {
+        if next_update(&mut updates).await == ConnectionUpdate::Connected {
+            break;
+        }
+    }
+    next_update(&mut updates).await;
+    assert_eq!(
+        next_update(&mut updates).await,
+        ConnectionUpdate::Event(ServerEvent::RoomListing(vec![]))
+    );
+}
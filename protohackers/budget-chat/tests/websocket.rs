@@ -0,0 +1,162 @@
+//! Browsers get the web client over plain HTTP, and upgrade to a websocket to chat
+//! alongside raw TCP members, one line per text frame.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use budget_chat::server::{ServerConfig, Staging};
+use common::{Member, WAIT};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_tungstenite::{
+    connect_async, tungstenite::Message as Frame, MaybeTlsStream, WebSocketStream,
+};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Start a server, and get back where raw TCP members and browsers connect to it.
+async fn start() -> (SocketAddr, SocketAddr) {
+    let websocket_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let websocket = websocket_listener.local_addr().unwrap();
+    let addr = common::start_with(ServerConfig::default(), |chat| {
+        chat.with_websocket(websocket_listener)
+    })
+    .await;
+    (addr, websocket)
+}
+
+/// Send a plain HTTP request, and get back everything the server said before hanging up.
+async fn request(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(WAIT, stream.read_to_string(&mut response))
+        .await
+        .expect("Waited too long for a response")
+        .unwrap();
+    response
+}
+
+async fn upgrade(addr: SocketAddr) -> WebSocket {
+    let (websocket, response) = connect_async(format!("ws://{}/", addr)).await.unwrap();
+    assert_eq!(response.status(), 101);
+    websocket
+}
+
+/// The next text frame, or nothing once the server closes the websocket.
+async fn next_text(websocket: &mut WebSocket) -> Option<String> {
+    loop {
+        let frame = timeout(WAIT, websocket.next())
+            .await
+            .expect("Waited too long for a frame");
+        match frame {
+            Some(Ok(Frame::Text(text))) => return Some(text.to_string()),
+            Some(Ok(Frame::Close(_))) | Some(Err(_)) | None => return None,
+            Some(Ok(_)) => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn endless_header_lines_are_turned_down() {
+    let (_, websocket) = start().await;
+    let mut stream = TcpStream::connect(websocket).await.unwrap();
+    let head = format!("GET / HTTP/1.1\r\nX-Padding: {}", "a".repeat(16 * 1024));
+    stream.write_all(head.as_bytes()).await.unwrap();
+    // No newline is ever coming, and the server doesn't wait for one past its limit.
+    let mut response = String::new();
+    timeout(WAIT, stream.read_to_string(&mut response))
+        .await
+        .expect("Server kept reading the header line")
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn browsers_are_served_the_web_client() {
+    let (_, websocket) = start().await;
+    for path in ["/", "/index.html"] {
+        let response = request(
+            websocket,
+            &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path),
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/html; charset=utf-8"));
+        assert_eq!(body, include_str!("../static/index.html"));
+    }
+}
+
+#[tokio::test]
+async fn anything_else_over_http_is_turned_down() {
+    let (_, websocket) = start().await;
+    let response = request(websocket, "GET /admin HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        response
+    );
+    let response = request(websocket, "POST / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{}",
+        response
+    );
+    let response = request(websocket, "nonsense\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn websockets_go_through_staging_like_anyone_else() {
+    let (addr, websocket) = start().await;
+    let mut bob = Member::join(addr, "bob").await;
+
+    let mut alice = upgrade(websocket).await;
+    assert_eq!(next_text(&mut alice).await.unwrap(), Staging::WELCOME);
+    alice.send(Frame::text("alice")).await.unwrap();
+    assert_eq!(
+        next_text(&mut alice).await.unwrap(),
+        "* The room contains: bob"
+    );
+    bob.expect("* alice has entered the room").await;
+
+    bob.say("hi alice").await;
+    assert_eq!(next_text(&mut alice).await.unwrap(), "[bob] hi alice");
+
+    // Names are as taken over websockets as anywhere else.
+    let mut imposter = upgrade(websocket).await;
+    assert_eq!(next_text(&mut imposter).await.unwrap(), Staging::WELCOME);
+    imposter.send(Frame::text("bob")).await.unwrap();
+    assert_eq!(next_text(&mut imposter).await, None);
+}
+
+#[tokio::test]
+async fn line_breaks_in_frames_are_flattened() {
+    let (addr, websocket) = start().await;
+    let mut bob = Member::join(addr, "bob").await;
+    let mut alice = upgrade(websocket).await;
+    next_text(&mut alice).await;
+    alice.send(Frame::text("alice")).await.unwrap();
+    next_text(&mut alice).await;
+    bob.expect("* alice has entered the room").await;
+
+    alice
+        .send(Frame::text("hello\n[bob] I owe alice money\r\n"))
+        .await
+        .unwrap();
+    bob.expect("[alice] hello [bob] I owe alice money").await;
+}
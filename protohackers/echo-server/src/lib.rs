@@ -59,7 +59,7 @@ impl EchoServer {
 
             let mut current_offset = 0;
             loop {
-                match write_half.write(&mut buf[current_offset..bytes_read]).await {
+                match write_half.write(&buf[current_offset..bytes_read]).await {
                     Ok(bytes_written) => {
                         if bytes_written == (bytes_read - current_offset) {
                             trace!("Wrote all bytes ({}) to client successfully.", bytes_read);
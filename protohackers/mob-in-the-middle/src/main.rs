@@ -5,7 +5,7 @@ use tokio::{
     select,
     sync::mpsc::{UnboundedReceiver, UnboundedSender},
 };
-use tracing::{debug, trace, info};
+use tracing::{trace, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use lazy_static::lazy_static;
@@ -0,0 +1,61 @@
+//! Compare the throughput of a connection speaking each of the [Encoding]s.
+//!
+//! Run with `cargo bench -p prime-time --bench encodings`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prime_time::{data::IsPrimeRequest, Encoding, EncodingMode, PrimeTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const REQUESTS_PER_CONNECTION: usize = 1_000;
+
+/// Everything a client would write on a single connection.
+fn client_payload(encoding: Encoding) -> Vec<u8> {
+    (0..REQUESTS_PER_CONNECTION)
+        .flat_map(|number| {
+            let request = IsPrimeRequest {
+                method: "isPrime".to_string(),
+                number: number as f64,
+            };
+            encoding.encode(&request).unwrap()
+        })
+        .collect()
+}
+
+/// Write all the requests, and read back every response until the server hangs up.
+async fn round_trip(encoding: Encoding, payload: &'static [u8]) -> usize {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(PrimeTime::serve_with(server, None, EncodingMode::Fixed(encoding)));
+
+    let (mut reader, mut writer) = tokio::io::split(&mut client);
+    let write = async move {
+        writer.write_all(payload).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let read = async move {
+        let mut responses = Vec::new();
+        reader.read_to_end(&mut responses).await.unwrap();
+        responses.len()
+    };
+    let (_, bytes_read) = tokio::join!(write, read);
+    server.await.unwrap();
+    bytes_read
+}
+
+fn bench_encodings(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("connection");
+    group.throughput(Throughput::Elements(REQUESTS_PER_CONNECTION as u64));
+
+    for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+        let payload: &'static [u8] = client_payload(encoding).leak();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", encoding)),
+            &encoding,
+            |b, &encoding| b.to_async(&runtime).iter(|| round_trip(encoding, payload)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encodings);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+//! Alternative binary encodings for the prime-time protocol.
+//!
+//! JSON requests and responses are newline-delimited, while MessagePack and CBOR ones
+//! are framed by a 4-byte big-endian length prefix followed by the encoded payload.
+//! The payloads carry the same fields as [IsPrimeRequest](crate::data::IsPrimeRequest)
+//! and [IsPrimeResponse](crate::data::IsPrimeResponse).
+
+use bytes::{Buf, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::PrimeTimeError;
+
+/// The largest binary frame payload we're willing to buffer. A request is
+/// a method name and a number, so anything bigger is not a request.
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// The size of the length prefix of a binary frame.
+const LENGTH_PREFIX: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+/// How a connection decides which [Encoding] it speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingMode {
+    /// Every connection speaks the given encoding.
+    Fixed(Encoding),
+    /// Every connection may start with a one-byte preamble (see [Encoding::preamble])
+    /// to pick its encoding. Connections without one speak JSON.
+    Preamble,
+}
+
+impl Default for EncodingMode {
+    fn default() -> Self {
+        Self::Fixed(Encoding::Json)
+    }
+}
+
+impl Encoding {
+    /// The first byte a client sends to pick this encoding when
+    /// connected to a server in [EncodingMode::Preamble].
+    ///
+    /// JSON has no preamble since a JSON request can never start with a control character.
+    pub fn preamble(self) -> Option<u8> {
+        match self {
+            Self::Json => None,
+            Self::MessagePack => Some(0x01),
+            Self::Cbor => Some(0x02),
+        }
+    }
+
+    pub fn from_preamble(byte: u8) -> Option<Self> {
+        [Self::MessagePack, Self::Cbor]
+            .into_iter()
+            .find(|encoding| encoding.preamble() == Some(byte))
+    }
+
+    /// Encode a value into a complete frame, ready to be written to the wire.
+    pub fn encode<T: Serialize>(self, value: &T) -> std::io::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        match self {
+            Self::Json => {
+                serde_json::to_writer(&mut frame, value)?;
+                frame.push(b'\n');
+                return Ok(frame);
+            },
+            Self::MessagePack => {
+                frame.extend_from_slice(&[0; LENGTH_PREFIX]);
+                rmp_serde::encode::write_named(&mut frame, value)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            },
+            Self::Cbor => {
+                frame.extend_from_slice(&[0; LENGTH_PREFIX]);
+                ciborium::ser::into_writer(value, &mut frame)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            }
+        }
+        let length = (frame.len() - LENGTH_PREFIX) as u32;
+        frame[..LENGTH_PREFIX].copy_from_slice(&length.to_be_bytes());
+        Ok(frame)
+    }
+
+    /// Decode the payload of a binary frame (without its length prefix).
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, PrimeTimeError> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(payload)?),
+            Self::MessagePack => rmp_serde::from_slice(payload)
+                .map_err(|err| PrimeTimeError::Decode(self, err.to_string())),
+            Self::Cbor => ciborium::de::from_reader(payload)
+                .map_err(|err| PrimeTimeError::Decode(self, err.to_string())),
+        }
+    }
+
+    /// A frame that is guaranteed to not decode into a response.
+    pub fn malformed_frame(self) -> &'static [u8] {
+        match self {
+            // Write buncha random corrupt data.
+            Self::Json => &[1, 2, b'\n'],
+            // An empty payload is never a valid response.
+            Self::MessagePack | Self::Cbor => &[0; LENGTH_PREFIX],
+        }
+    }
+}
+
+/// Split a complete length-prefixed frame payload off the front of the buffer, if there is one.
+pub fn parse_binary_frame(encoding: Encoding, buffer: &mut BytesMut) -> Result<Option<BytesMut>, PrimeTimeError> {
+    if buffer.len() < LENGTH_PREFIX {
+        return Ok(None);
+    }
+    let length = u32::from_be_bytes(buffer[..LENGTH_PREFIX].try_into().unwrap()) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(PrimeTimeError::Decode(encoding, format!("frame of {} bytes is too large", length)));
+    }
+    if buffer.len() < LENGTH_PREFIX + length {
+        return Ok(None);
+    }
+    buffer.advance(LENGTH_PREFIX);
+    Ok(Some(buffer.split_to(length)))
+}
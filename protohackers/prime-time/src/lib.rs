@@ -1,130 +1,168 @@
-use std::{net::SocketAddr, io::Cursor};
+use std::{net::SocketAddr, io::Cursor, sync::Arc, time::Instant};
 use tracing::{info, error, warn, trace, debug};
 
-use tokio::{net::{TcpStream, TcpListener}, io::{AsyncReadExt, AsyncWriteExt}};
+use tokio::{
+    net::{TcpStream, TcpListener},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    select,
+    sync::{mpsc, watch},
+};
 use bytes::{BytesMut, Buf};
 
 use self::data::IsPrimeResponse;
+pub use self::codec::{Encoding, EncodingMode};
+use self::metrics::Metrics;
 
+pub mod codec;
+pub mod metrics;
+pub mod udp;
 
 
+/// A buffered wrapper around any byte stream (a [TcpStream] by default)
+/// that reads [data::IsPrimeRequest]s and writes [data::IsPrimeResponse]s
+/// in a given [Encoding] (newline-delimited JSON by default).
 #[derive(Debug)]
-struct Connection {
-    stream: TcpStream,
+pub struct Connection<S = TcpStream> {
+    stream: S,
     buffer: BytesMut,
     received_eof: bool,
+    sent_malformed: bool,
+    encoding: Encoding,
 }
 
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S> Connection<S> 
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn new(stream: S) -> Self {
         Self {
             stream,
             buffer: BytesMut::with_capacity(4096),
             received_eof: false,
+            sent_malformed: false,
+            encoding: Encoding::Json,
         }
     }
 
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Wait for the first byte the client sends, and if it is the preamble of
+    /// one of the binary [Encoding]s, consume it and switch to that encoding.
+    /// Anything else is left buffered to be read as JSON.
+    pub async fn negotiate_encoding(&mut self) -> Result<Encoding, PrimeTimeError> {
+        while self.buffer.is_empty() && !self.received_eof {
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                self.received_eof = true;
+            }
+        }
+        if let Some(encoding) = self.buffer.first().copied().and_then(Encoding::from_preamble) {
+            trace!(?encoding, "Client picked an encoding with a preamble.");
+            self.buffer.advance(1);
+            self.encoding = encoding;
+        }
+        Ok(self.encoding)
+    }
+
+    /// Whether we've written a malformed response on this connection.
+    pub fn sent_malformed(&self) -> bool {
+        self.sent_malformed
+    }
+
+    /// Give the underlying stream back.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<data::IsPrimeRequest>, PrimeTimeError> {
-        let span = tracing::trace_span!("read_frame", ?self.buffer);
+        if self.encoding != Encoding::Json {
+            return self.read_binary_frame().await;
+        }
         loop {
-            match self.parse_frame() {
-                Ok(Some(frame)) => {
-                    return Ok(Some(frame));
-                },
-                Ok(None) => {
-                    span.in_scope(|| {
-                        trace!("Not enough data to parse. Waiting for more...");
-                    });
-                },
-                Err(PrimeTimeError::Serde(serde_err)) => {
-                    span.in_scope(|| {
-                        error!(category = ?serde_err.classify(), "Serde error: {}", serde_err);
-                    });
-                    if serde_err.is_data() || serde_err.is_syntax() {
-                        return Err(PrimeTimeError::Serde(serde_err));
-                    }
-                    if serde_err.is_eof() && !self.buffer.is_empty() && self.received_eof {
-                        return Err(PrimeTimeError::Serde(serde_err));
-                    }
-                },
-                _ => {}
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
             }
-            let bytes_read = self.stream.read_buf(&mut self.buffer).await?; 
+            if self.received_eof {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                // A line that never got its newline can never become a request,
+                // so it's as good as a malformed one.
+                warn!("We still had something buffered when we got an EOF: {:?}", self.buffer);
+                return Err(PrimeTimeError::Malformed(self.buffer.split().to_vec()));
+            }
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
             trace!("Bytes read: {}, buffer: {:#?}", bytes_read, self.buffer);
 
             if bytes_read == 0 {
                 self.received_eof = true;
+            }
+        }
+    }
+
+    /// Read a length-prefixed request in one of the binary encodings.
+    async fn read_binary_frame(&mut self) -> Result<Option<data::IsPrimeRequest>, PrimeTimeError> {
+        loop {
+            if let Some(payload) = codec::parse_binary_frame(self.encoding, &mut self.buffer)? {
+                return self.encoding.decode(&payload).map(Some);
+            }
+            if self.received_eof {
                 if self.buffer.is_empty() {
                     return Ok(None);
                 }
-                // If there is still data buffered and we got an EOF, it probably means we couldn't convert it to 
-                // valid request. Just treat it as malformed request.
-                if let Err(err) = self.parse_frame() {
-                    warn!("We still had something buffered when we got an EOF and we couldn't parse it: {}", err);
-                    self.write_frame(None).await?;
-                    return Ok(None);
-                }
-
-                return Err(
-                    std::io::Error::new(
-                        std::io::ErrorKind::ConnectionReset, 
-                        "client sent some partial bytes that we couldn't make sense of and then reset the connection before sending a complete frame."
-                    ).into()
-                );
+                // Whatever is left can never become a complete frame, so it's as good as malformed.
+                return Err(PrimeTimeError::Decode(self.encoding, "connection closed in the middle of a frame".to_string()));
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                self.received_eof = true;
             }
         }
     }
 
+    /// Split the first complete line off the buffer, if there is one, and parse it as a request.
     pub fn parse_frame(&mut self) -> Result<Option<data::IsPrimeRequest>, PrimeTimeError> {
-        let mut buf = Cursor::new(&self.buffer[..]);
-        if self.buffer.is_empty() {
+        let Some(newline) = self.buffer.iter().position(|&byte| byte == b'\n') else {
+            trace!("Not enough data to parse. Waiting for more...");
             return Ok(None);
-        }
-
-        match data::IsPrimeRequest::check(&mut buf) {
-            Ok(_) => {
-                let len = buf.position() as usize;
-                buf.set_position(0);
-                let frame = data::IsPrimeRequest::parse(&mut buf)?;
-                self.buffer.advance(len);
-                self.buffer.advance(1); // Skip the newline.
-                Ok(Some(frame))
-            },
-            Err(err) => {
-                Err(err)
-            }
-        }
+        };
+        let line = self.buffer.split_to(newline + 1);
+        let mut buf = Cursor::new(&line[..newline]);
+        data::IsPrimeRequest::parse(&mut buf)
+            .inspect_err(|err| error!("Failed to parse request: {}", err))
+            .map(Some)
     }
 
     pub async fn write_frame(&mut self, frame: Option<data::IsPrimeResponse>) -> std::io::Result<()> {
         match frame {
             Some(response) => {
-                let as_bytes = serde_json::to_vec(&response)?;
+                let as_bytes = self.encoding.encode(&response)?;
                 self.stream.write_all(&as_bytes).await?;
             },
             None => {
-                // Write buncha random corrupt data.
-                self.stream.write_all(&[1, 2]).await?;
+                self.stream.write_all(self.encoding.malformed_frame()).await?;
+                self.sent_malformed = true;
             }
         }
-        self.stream.write(&[b'\n']).await?;
         Ok(())
     }
 }
 
 pub mod math {
-    pub const TOLERANCE: f64 = 1e-6;
-
+    /// Non-integers can not be prime.
     pub fn is_prime_f64(number: f64) -> bool {
-        if (number.floor() - number).abs() > TOLERANCE {
+        if number.fract() != 0.0 || number < 0.0 {
             return false;
         }
-        is_prime(number.floor() as u64)
+        is_prime(number as u64)
     }
 
     pub fn is_prime(number: u64) -> bool {
-        let _start_time = std::time::Instant::now();
         if matches!(number, 2 | 3 | 5 | 7 | 11) {
             return true;
         }
@@ -133,33 +171,130 @@ pub mod math {
         }
         let start = 2;
         let end = ((number as f64).sqrt().ceil() + 1.0) as u64;
-        
-
-        // tracing::trace!("Checked primality of {} (prime: {}) in {:#?}", number, result, start_time.elapsed());
 
         (start..=end)
-        .all(|divisor| number % divisor != 0)
+        .all(|divisor| !number.is_multiple_of(divisor))
     }
 }
 
 
+/// Accepts TCP connections and answers prime-time requests on each of them
+/// until a shutdown is requested through a [ShutdownHandle].
 #[derive(Debug)]
 pub struct PrimeTime {
-    pub listener: TcpListener
+    pub listener: TcpListener,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+    reports_tx: Option<mpsc::UnboundedSender<ConnectionReport>>,
+    encoding_mode: EncodingMode,
+    metrics: Option<Metrics>,
 }
 
+/// A cloneable handle to ask a running [PrimeTime] to stop accepting connections.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// Stop accepting new connections. Connections that are already
+    /// being served are left to finish on their own.
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// How a single connection ended.
 #[derive(Debug)]
-struct Handler {
-    connection: Connection,
-    remote_addr: SocketAddr,
+pub enum ConnectionOutcome {
+    /// The client hung up after all its requests were answered.
+    Closed,
+    /// The client sent a malformed request, so we answered with a
+    /// malformed response and hung up.
+    Malformed,
+    /// The connection failed before it could complete.
+    Failed(PrimeTimeError),
 }
 
-impl Handler {
-    pub async fn run(&mut self) -> Result<(), PrimeTimeError> {
-        let span = tracing::trace_span!("Connection", remote_addr=self.remote_addr.to_string());
+/// What happened on a single connection once it is over.
+#[derive(Debug)]
+pub struct ConnectionReport {
+    pub remote_addr: Option<SocketAddr>,
+    pub requests_served: usize,
+    pub outcome: ConnectionOutcome,
+}
+
+/// Drives the request/response loop of a single [Connection].
+#[derive(Debug)]
+pub struct Handler<S = TcpStream> {
+    connection: Connection<S>,
+    remote_addr: Option<SocketAddr>,
+    requests_served: usize,
+    encoding_mode: EncodingMode,
+    metrics: Option<Metrics>,
+}
+
+impl<S> Handler<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn new(stream: S, remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            connection: Connection::new(stream),
+            remote_addr,
+            requests_served: 0,
+            encoding_mode: EncodingMode::default(),
+            metrics: None,
+        }
+    }
+
+    /// Record how long requests take, and how they were answered.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_encoding_mode(mut self, encoding_mode: EncodingMode) -> Self {
+        if let EncodingMode::Fixed(encoding) = encoding_mode {
+            self.connection.set_encoding(encoding);
+        }
+        self.encoding_mode = encoding_mode;
+        self
+    }
+
+    /// The number of well-formed requests we've answered so far.
+    pub fn requests_served(&self) -> usize {
+        self.requests_served
+    }
+
+    /// Give the underlying stream back.
+    pub fn into_inner(self) -> S {
+        self.connection.into_inner()
+    }
+
+    /// Run until the connection is over, and report how it went.
+    pub async fn serve(mut self) -> ConnectionReport {
+        let outcome = match self.run().await {
+            Ok(outcome) => outcome,
+            Err(err) => ConnectionOutcome::Failed(err),
+        };
+        ConnectionReport {
+            remote_addr: self.remote_addr,
+            requests_served: self.requests_served,
+            outcome,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<ConnectionOutcome, PrimeTimeError> {
+        let span = tracing::trace_span!("Connection", remote_addr = ?self.remote_addr);
+        if self.encoding_mode == EncodingMode::Preamble {
+            let encoding = self.connection.negotiate_encoding().await?;
+            span.in_scope(|| {
+                debug!(?encoding, "Negotiated encoding.");
+            });
+        }
         loop {
             match self.connection.read_frame().await {
                 Ok(Some(frame)) => {
+                    let request_started_at = Instant::now();
                     span.in_scope(|| {
                         trace!(malformed = frame.is_malformed(), frame = ?frame);
                     });
@@ -169,14 +304,24 @@ impl Handler {
                         self.connection.stream.shutdown().await?;
                         break;
                     }
+                    let primality_check_started_at = Instant::now();
+                    let prime = math::is_prime_f64(frame.number);
+                    if let Some(metrics) = &self.metrics {
+                        metrics.observe_primality_check(primality_check_started_at.elapsed());
+                    }
+
                     let prime_response = IsPrimeResponse {
-                        prime: math::is_prime_f64(frame.number),
+                        prime,
                         method: "isPrime".to_string()
                     };
                     span.in_scope(|| {
                         trace!(request = ?frame, response = ?prime_response);
                     });
                     self.connection.write_frame(Some(prime_response)).await?;
+                    self.requests_served += 1;
+                    if let Some(metrics) = &self.metrics {
+                        metrics.observe_request(frame.number, prime, request_started_at.elapsed());
+                    }
                 },
                 Ok(None) => {
                     span.in_scope(|| {
@@ -195,15 +340,22 @@ impl Handler {
                                     serde_err
                                 );
                             });
-
-                            if serde_err.is_data() || serde_err.is_syntax() {
-                                self.connection.write_frame(None).await?;
-                                break;
-                            }
-                            if serde_err.is_eof() && !self.connection.buffer.is_empty() && self.connection.received_eof {
-                                self.connection.write_frame(None).await?;
-                                break;
-                            }
+                            self.connection.write_frame(None).await?;
+                            break;
+                        },
+                        PrimeTimeError::Malformed(bytes) => {
+                            span.in_scope(|| {
+                                debug!(?bytes, "Will treat this as a malformed request. Found an incomplete line.");
+                            });
+                            self.connection.write_frame(None).await?;
+                            break;
+                        },
+                        PrimeTimeError::Decode(encoding, reason) => {
+                            span.in_scope(|| {
+                                debug!(?encoding, "Will treat this as a malformed request. Failed to decode: {}", reason);
+                            });
+                            self.connection.write_frame(None).await?;
+                            break;
                         },
                         _ => {
                             return Err(err);
@@ -212,31 +364,105 @@ impl Handler {
                 }
             }
         }
-        debug!("Finished handling connection from {}", self.remote_addr);
-        Ok(())
+        debug!("Finished handling connection from {:?}", self.remote_addr);
+        // We hang up right after the first malformed response, so there can only be one.
+        if self.connection.sent_malformed() {
+            if let Some(metrics) = &self.metrics {
+                metrics.observe_malformed();
+            }
+        }
+
+        match self.connection.sent_malformed() {
+            true => Ok(ConnectionOutcome::Malformed),
+            false => Ok(ConnectionOutcome::Closed),
+        }
     }
 }
 
 impl PrimeTime {
     pub fn new(listener: TcpListener) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
         Self {
-            listener
+            listener,
+            shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+            reports_tx: None,
+            encoding_mode: EncodingMode::default(),
+            metrics: None,
         }
     }
 
+    /// Record how long requests take, and how they were answered, on every connection.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Pick the encoding(s) that connections accepted by [PrimeTime::run] speak.
+    pub fn with_encoding_mode(mut self, encoding_mode: EncodingMode) -> Self {
+        self.encoding_mode = encoding_mode;
+        self
+    }
+
+    /// Get a handle that can stop [PrimeTime::run] from elsewhere.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown_tx.clone())
+    }
+
+    /// Get notified with a [ConnectionReport] every time a connection accepted by
+    /// [PrimeTime::run] is over. Only the most recent subscriber is notified.
+    pub fn subscribe_reports(&mut self) -> mpsc::UnboundedReceiver<ConnectionReport> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.reports_tx = Some(tx);
+        rx
+    }
+
+    /// Answer newline-delimited JSON prime-time requests on any byte stream until
+    /// the client hangs up, or sends something malformed.
+    pub async fn serve<S>(stream: S, remote_addr: Option<SocketAddr>) -> ConnectionReport
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+    {
+        Self::serve_with(stream, remote_addr, EncodingMode::default()).await
+    }
+
+    /// Same as [PrimeTime::serve], but in the given [EncodingMode].
+    pub async fn serve_with<S>(stream: S, remote_addr: Option<SocketAddr>, encoding_mode: EncodingMode) -> ConnectionReport
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+    {
+        Handler::new(stream, remote_addr)
+            .with_encoding_mode(encoding_mode)
+            .serve()
+            .await
+    }
+
+    /// Accept connections and serve each of them in the background,
+    /// until a shutdown is requested.
     pub async fn run(&mut self) -> Result<(), PrimeTimeError> {
         info!("Accepting inbound connections at {:#?}.", self.listener.local_addr()?);
         loop {
-            let (socket, remote_addr) = self.listener.accept().await?;
-            debug!("Accepted connection from {}", remote_addr);
-            let mut handler = Handler {
-                connection: Connection::new(socket),
-                remote_addr
+            if *self.shutdown_rx.borrow() {
+                info!("Shutdown requested. No longer accepting connections.");
+                return Ok(());
+            }
+            let (socket, remote_addr) = select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = self.shutdown_rx.changed() => continue,
             };
+            debug!("Accepted connection from {}", remote_addr);
+            let reports_tx = self.reports_tx.clone();
+            let handler = Handler::new(socket, Some(remote_addr))
+                .with_encoding_mode(self.encoding_mode)
+                .with_metrics(self.metrics.clone());
             tokio::spawn(async move {
-                if let Err(err) = handler.run().await {
+                let report = handler.serve().await;
+                if let ConnectionOutcome::Failed(ref err) = report.outcome {
                     error!(cause =? err, "connection error");
                 }
+                if let Some(reports_tx) = reports_tx {
+                    _ = reports_tx.send(report);
+                }
             });
         }
     }
@@ -251,14 +477,16 @@ pub enum PrimeTimeError {
     #[error("Received Malformed data: {0:#?}")]
     Malformed(Vec<u8>),
     #[error(transparent)]
-    Serde(#[from] serde_json::Error)
+    Serde(#[from] serde_json::Error),
+    #[error("Failed to decode a {0:?} frame: {1}")]
+    Decode(Encoding, String),
 }
 
 pub mod data {
     use std::io::Cursor;
     use serde::Deserialize;
 
-    use super::{PrimeTimeError, math::TOLERANCE};
+    use super::PrimeTimeError;
 
 
     #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
@@ -274,19 +502,19 @@ pub mod data {
     }
 
     impl IsPrimeRequest {
-        pub fn check(buffer: &mut Cursor<&[u8]>) -> Result<(), PrimeTimeError> {
-            // trace!("Checking buffer: {:#?}", buffer);
-            let mut de = serde_json::Deserializer::from_reader(buffer);
-            Self::deserialize(&mut de)?;
-            Ok(())
-        }
+        /// Parse a single line (without its newline) as a request.
         pub fn parse(buffer: &mut Cursor<&[u8]>) -> Result<Self, PrimeTimeError> {
             let mut de = serde_json::Deserializer::from_reader(buffer);
-            let request = Self::deserialize(&mut de)?;
+            // Going through a map first, since serde would otherwise
+            // happily take a `["isPrime", 7]` for a request.
+            let fields = serde_json::Map::deserialize(&mut de)?;
+            // Nothing but whitespace may follow the request on its line.
+            de.end()?;
+            let request = Self::deserialize(serde_json::Value::Object(fields))?;
             Ok(request)
         }
         pub fn is_malformed(&self) -> bool {
-            self.method != "isPrime" || (self.number.floor() - self.number).abs() > TOLERANCE
+            self.method != "isPrime"
         }
     }
 
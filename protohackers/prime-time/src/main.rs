@@ -1,6 +1,7 @@
 use clap::Parser;
-use tokio::net::TcpListener;
-use tracing::info;
+use prime_time::{metrics::Metrics, udp::PrimeTimeUdp, Encoding, EncodingMode, PrimeTime, PrimeTimeError, ShutdownHandle};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 
@@ -12,16 +13,72 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 )]
 pub struct Args {
     #[clap(default_value_t = 12001, short, long)]
-    port: u16
+    port: u16,
+    /// Let clients on the main port pick MessagePack or CBOR with a one-byte preamble.
+    #[clap(long)]
+    preamble: bool,
+    /// Also serve length-prefixed MessagePack requests on this port.
+    #[clap(long)]
+    msgpack_port: Option<u16>,
+    /// Also serve length-prefixed CBOR requests on this port.
+    #[clap(long)]
+    cbor_port: Option<u16>,
+    /// Also serve newline-delimited JSON requests in UDP datagrams on this port.
+    #[clap(long)]
+    udp_port: Option<u16>,
+    /// The largest datagram (in bytes) we accept and send on the UDP port.
+    #[clap(long, default_value_t = PrimeTimeUdp::DEFAULT_MAX_DATAGRAM_SIZE)]
+    max_datagram_size: usize,
+    /// Serve Prometheus metrics over HTTP on this port.
+    #[clap(long)]
+    metrics_port: Option<u16>,
+    /// Log every request that takes longer than this many milliseconds to handle.
+    #[clap(long)]
+    slow_request_threshold_ms: Option<u64>,
 }
 
-pub async fn start_prime_time() {
+/// Ask the server behind the handle to shut down once we receive a Ctrl-C.
+fn shutdown_on_ctrl_c(shutdown: ShutdownHandle) {
+    tokio::spawn(async move {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", err);
+            return;
+        }
+        info!("Received Ctrl-C. Shutting down...");
+        shutdown.shutdown();
+    });
+}
+
+/// Serve prime-time on the given port until we receive a Ctrl-C.
+pub async fn start_prime_time(port: u16, encoding_mode: EncodingMode, metrics: Metrics) -> Result<(), PrimeTimeError> {
+    let addr: std::net::SocketAddr = ([0; 8], port).into();
+    let listener = TcpListener::bind(&addr).await?;
+
+    let mut prime_time = PrimeTime::new(listener)
+        .with_encoding_mode(encoding_mode)
+        .with_metrics(metrics);
+    shutdown_on_ctrl_c(prime_time.shutdown_handle());
+
+    prime_time.run().await
+}
+
+/// Serve prime-time over UDP on the given port until we receive a Ctrl-C.
+pub async fn start_prime_time_udp(port: u16, max_datagram_size: usize, metrics: Metrics) -> Result<(), PrimeTimeError> {
+    let addr: std::net::SocketAddr = ([0; 8], port).into();
+    let socket = UdpSocket::bind(&addr).await?;
+
+    let mut prime_time = PrimeTimeUdp::new(socket)
+        .with_max_datagram_size(max_datagram_size)
+        .with_metrics(metrics);
+    shutdown_on_ctrl_c(prime_time.shutdown_handle());
+
+    prime_time.run().await
 }
 
 
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), PrimeTimeError> {
     tracing_subscriber::registry()
     .with(
         tracing_subscriber::EnvFilter::try_from_default_env()
@@ -33,7 +90,44 @@ async fn main() {
     let args = Args::parse();
     info!("Args: {:#?}", args);
 
-    let addr: std::net::SocketAddr = ([0; 8], args.port).into();
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    prime_time::PrimeTime::new(listener).run().await.unwrap();
+    let mut metrics = Metrics::new();
+    if let Some(threshold) = args.slow_request_threshold_ms {
+        metrics = metrics.with_slow_request_threshold(std::time::Duration::from_millis(threshold));
+    }
+    if let Some(port) = args.metrics_port {
+        let addr: std::net::SocketAddr = ([0; 8], port).into();
+        let listener = TcpListener::bind(&addr).await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics.serve(listener).await {
+                error!("Stopped serving metrics: {}", err);
+            }
+        });
+    }
+
+    let mut servers = vec![(
+        args.port,
+        match args.preamble {
+            true => EncodingMode::Preamble,
+            false => EncodingMode::Fixed(Encoding::Json),
+        }
+    )];
+    if let Some(port) = args.msgpack_port {
+        servers.push((port, EncodingMode::Fixed(Encoding::MessagePack)));
+    }
+    if let Some(port) = args.cbor_port {
+        servers.push((port, EncodingMode::Fixed(Encoding::Cbor)));
+    }
+
+    let mut handles: Vec<_> = servers
+        .into_iter()
+        .map(|(port, encoding_mode)| tokio::spawn(start_prime_time(port, encoding_mode, metrics.clone())))
+        .collect();
+    if let Some(port) = args.udp_port {
+        handles.push(tokio::spawn(start_prime_time_udp(port, args.max_datagram_size, metrics.clone())));
+    }
+    for handle in handles {
+        handle.await.unwrap()?;
+    }
+    Ok(())
 }
@@ -0,0 +1,143 @@
+//! Latency and response metrics for prime-time, exported in the Prometheus text format.
+
+use std::time::Duration;
+
+use prometheus::{exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+
+/// Cheap to clone, so every [Handler](crate::Handler) can carry its own handle
+/// to the same set of metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    primality_check_seconds: Histogram,
+    request_seconds: Histogram,
+    responses: IntCounterVec,
+    slow_request_threshold: Option<Duration>,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics")
+            .field("slow_request_threshold", &self.slow_request_threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // From a microsecond to a few seconds.
+        let buckets = exponential_buckets(1e-6, 4.0, 12).unwrap();
+
+        let primality_check_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "prime_time_primality_check_duration_seconds",
+                "Time spent checking whether a requested number is prime.",
+            )
+            .buckets(buckets.clone()),
+        )
+        .unwrap();
+        let request_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "prime_time_request_duration_seconds",
+                "Time spent answering a well-formed request once it has been parsed, up to writing its response.",
+            )
+            .buckets(buckets),
+        )
+        .unwrap();
+        let responses = IntCounterVec::new(
+            Opts::new("prime_time_responses_total", "Responses sent, by result."),
+            &["result"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(primality_check_seconds.clone())).unwrap();
+        registry.register(Box::new(request_seconds.clone())).unwrap();
+        registry.register(Box::new(responses.clone())).unwrap();
+
+        Self {
+            registry,
+            primality_check_seconds,
+            request_seconds,
+            responses,
+            slow_request_threshold: None,
+        }
+    }
+
+    /// Log every request that takes longer than this to handle.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    pub fn observe_primality_check(&self, elapsed: Duration) {
+        self.primality_check_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_request(&self, number: f64, prime: bool, elapsed: Duration) {
+        self.request_seconds.observe(elapsed.as_secs_f64());
+        let result = match prime {
+            true => "prime",
+            false => "not_prime",
+        };
+        self.responses.with_label_values(&[result]).inc();
+
+        if self.slow_request_threshold.is_some_and(|threshold| elapsed > threshold) {
+            warn!(number, prime, ?elapsed, "Slow request.");
+        }
+    }
+
+    pub fn observe_malformed(&self) {
+        self.responses.with_label_values(&["malformed"]).inc();
+    }
+
+    /// Everything we've recorded so far, in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Answer every HTTP request on the listener with the current metrics,
+    /// regardless of the path, so it can be scraped by Prometheus.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        info!("Serving metrics at http://{}/metrics", listener.local_addr()?);
+        loop {
+            let (mut socket, remote_addr) = listener.accept().await?;
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                // We serve the same page for every request, so we don't care what it looks like.
+                let mut request = [0; 1024];
+                if let Err(err) = socket.read(&mut request).await {
+                    warn!(peer = %remote_addr, "Failed to read metrics request: {}", err);
+                    return;
+                }
+                let body = metrics.encode();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    TextEncoder::new().format_type(),
+                    body.len(),
+                    body
+                );
+                if let Err(err) = socket.write_all(response.as_bytes()).await {
+                    warn!(peer = %remote_addr, "Failed to write metrics response: {}", err);
+                    return;
+                }
+                _ = socket.shutdown().await;
+            });
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! A UDP flavour of prime-time for fire-and-forget clients.
+//!
+//! Each datagram holds one or more newline-delimited JSON requests, and is answered
+//! with datagram(s) holding the responses. A datagram is treated exactly like a
+//! TCP connection that sends the datagram and hangs up, so the malformed-request
+//! rules of the [Handler] apply: the first malformed request is answered with a
+//! malformed response, and the rest of the datagram is ignored.
+
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    select,
+    sync::{watch, Semaphore},
+};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{codec::Encoding, metrics::Metrics, Handler, PrimeTimeError, ShutdownHandle};
+
+/// Answers prime-time requests that arrive in datagrams, until a shutdown
+/// is requested through a [ShutdownHandle].
+#[derive(Debug)]
+pub struct PrimeTimeUdp {
+    pub socket: Arc<UdpSocket>,
+    max_datagram_size: usize,
+    /// Bounds how many datagrams are being answered at once. Once they all are,
+    /// we stop receiving, and leave it to the socket to drop whatever doesn't fit in its buffer.
+    in_flight: Arc<Semaphore>,
+    metrics: Option<Metrics>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl PrimeTimeUdp {
+    /// Comfortably below the MTU of most links, so datagrams don't get fragmented.
+    pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1000;
+
+    pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+    pub fn new(socket: UdpSocket) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Self {
+            socket: Arc::new(socket),
+            max_datagram_size: Self::DEFAULT_MAX_DATAGRAM_SIZE,
+            in_flight: Arc::new(Semaphore::new(Self::DEFAULT_MAX_IN_FLIGHT)),
+            metrics: None,
+            shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+        }
+    }
+
+    /// The largest datagram we accept, and the largest reply datagram we send.
+    /// Bigger requests are answered with a malformed response, and replies that
+    /// don't fit are split into several datagrams at response boundaries.
+    pub fn with_max_datagram_size(mut self, max_datagram_size: usize) -> Self {
+        self.max_datagram_size = max_datagram_size;
+        self
+    }
+
+    /// The most datagrams we answer at once.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(max_in_flight));
+        self
+    }
+
+    /// Record how long requests take, and how they were answered, for every datagram.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Get a handle that can stop [PrimeTimeUdp::run] from elsewhere.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown_tx.clone())
+    }
+
+    /// Answer every request in a datagram, and get back the reply datagrams to send.
+    pub async fn answer(
+        datagram: &[u8],
+        remote_addr: Option<SocketAddr>,
+        max_datagram_size: usize,
+        metrics: Option<Metrics>,
+    ) -> Vec<Vec<u8>> {
+        if datagram.len() > max_datagram_size {
+            debug!(size = datagram.len(), "Datagram is too large. Will treat this as a malformed request.");
+            if let Some(metrics) = metrics {
+                metrics.observe_malformed();
+            }
+            return vec![Encoding::Json.malformed_frame().to_vec()];
+        }
+
+        let mut handler = Handler::new(DatagramStream::new(datagram), remote_addr).with_metrics(metrics);
+        if let Err(err) = handler.run().await {
+            // There's no real IO underneath, so this shouldn't happen.
+            error!(cause = ?err, "Failed to answer datagram");
+        }
+        split_into_datagrams(&handler.into_inner().reply, max_datagram_size)
+    }
+
+    /// Receive datagrams and answer each of them in the background, no more than
+    /// so many at once, until a shutdown is requested.
+    pub async fn run(&mut self) -> Result<(), PrimeTimeError> {
+        info!("Accepting datagrams at {:#?}.", self.socket.local_addr()?);
+        // One extra byte so we can tell a datagram that is too large from one that fits exactly.
+        let mut buf = vec![0; self.max_datagram_size + 1];
+        loop {
+            if *self.shutdown_rx.borrow() {
+                info!("Shutdown requested. No longer accepting datagrams.");
+                return Ok(());
+            }
+            let permit = select! {
+                permit = self.in_flight.clone().acquire_owned() => permit.expect("The semaphore is never closed"),
+                _ = self.shutdown_rx.changed() => continue,
+            };
+            let (bytes_read, remote_addr) = select! {
+                received = self.socket.recv_from(&mut buf) => received?,
+                _ = self.shutdown_rx.changed() => continue,
+            };
+            trace!(bytes_read, peer = %remote_addr, "Received datagram.");
+
+            let datagram = buf[..bytes_read].to_vec();
+            let socket = self.socket.clone();
+            let max_datagram_size = self.max_datagram_size;
+            let metrics = self.metrics.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                for reply in Self::answer(&datagram, Some(remote_addr), max_datagram_size, metrics).await {
+                    if let Err(err) = socket.send_to(&reply, remote_addr).await {
+                        warn!(peer = %remote_addr, "Failed to send reply datagram: {}", err);
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Pack newline-delimited responses into as few datagrams as possible,
+/// without splitting a response across datagrams.
+fn split_into_datagrams(reply: &[u8], max_datagram_size: usize) -> Vec<Vec<u8>> {
+    let mut datagrams: Vec<Vec<u8>> = vec![];
+    for line in reply.split_inclusive(|&byte| byte == b'\n') {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + line.len() <= max_datagram_size => {
+                datagram.extend_from_slice(line);
+            },
+            _ => datagrams.push(line.to_vec()),
+        }
+    }
+    datagrams
+}
+
+/// A datagram posing as a connection that sends the datagram and hangs up.
+/// Whatever gets written to it becomes the reply.
+#[derive(Debug)]
+struct DatagramStream {
+    request: Cursor<Vec<u8>>,
+    reply: Vec<u8>,
+}
+
+impl DatagramStream {
+    fn new(datagram: &[u8]) -> Self {
+        let mut request = datagram.to_vec();
+        // The end of the datagram also ends its last request.
+        if request.last().is_some_and(|&byte| byte != b'\n') {
+            request.push(b'\n');
+        }
+        Self {
+            request: Cursor::new(request),
+            reply: vec![],
+        }
+    }
+}
+
+impl AsyncRead for DatagramStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.request).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DatagramStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.reply.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
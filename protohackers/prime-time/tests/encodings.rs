@@ -0,0 +1,130 @@
+//! Clients that start with a preamble byte get to talk MessagePack or CBOR, framed by
+//! a length prefix, and anything that can't be a frame is answered as malformed.
+
+use std::time::Duration;
+
+use prime_time::{
+    codec::MAX_FRAME_LENGTH, data::IsPrimeRequest, Connection, ConnectionOutcome, ConnectionReport,
+    Encoding, EncodingMode, PrimeTime,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// What the server answers with, in whichever encoding.
+#[derive(Debug, Deserialize, PartialEq)]
+struct Response {
+    method: String,
+    prime: bool,
+}
+
+fn request(number: f64) -> IsPrimeRequest {
+    IsPrimeRequest {
+        method: "isPrime".to_string(),
+        number,
+    }
+}
+
+/// Serve a connection that picks its encoding with a preamble, send it everything
+/// and hang up, and get back everything it answered with along with its report.
+async fn exchange(sent: &[u8]) -> (Vec<u8>, ConnectionReport) {
+    let (mut client, server) = duplex(1024);
+    let served = tokio::spawn(PrimeTime::serve_with(server, None, EncodingMode::Preamble));
+    client.write_all(sent).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = vec![];
+    timeout(WAIT, client.read_to_end(&mut received))
+        .await
+        .expect("Waited too long for the server to hang up")
+        .unwrap();
+    let report = timeout(WAIT, served).await.unwrap().unwrap();
+    (received, report)
+}
+
+/// Split length-prefixed frames apart, and decode every one of them.
+fn decode_all(encoding: Encoding, mut received: &[u8]) -> Vec<Response> {
+    let mut responses = vec![];
+    while !received.is_empty() {
+        let length = u32::from_be_bytes(received[..4].try_into().unwrap()) as usize;
+        responses.push(encoding.decode(&received[4..4 + length]).unwrap());
+        received = &received[4 + length..];
+    }
+    responses
+}
+
+async fn round_trip(encoding: Encoding) {
+    let mut sent = vec![encoding.preamble().unwrap()];
+    for number in [7.0, 8.0, 7.5] {
+        sent.extend(encoding.encode(&request(number)).unwrap());
+    }
+    let (received, report) = exchange(&sent).await;
+
+    let answer = |prime| Response {
+        method: "isPrime".to_string(),
+        prime,
+    };
+    assert_eq!(
+        decode_all(encoding, &received),
+        [answer(true), answer(false), answer(false)]
+    );
+    assert_eq!(report.requests_served, 3);
+    assert!(matches!(report.outcome, ConnectionOutcome::Closed), "{:?}", report.outcome);
+}
+
+/// Send the given bytes after picking the encoding, and make sure the server answers
+/// with a malformed frame and hangs up without serving anything.
+async fn assert_malformed(encoding: Encoding, sent: &[u8]) {
+    let mut preambled = vec![encoding.preamble().unwrap()];
+    preambled.extend_from_slice(sent);
+    let (received, report) = exchange(&preambled).await;
+    assert_eq!(received, encoding.malformed_frame());
+    assert_eq!(report.requests_served, 0);
+    assert!(matches!(report.outcome, ConnectionOutcome::Malformed), "{:?}", report.outcome);
+}
+
+#[tokio::test]
+async fn message_pack_round_trips() {
+    round_trip(Encoding::MessagePack).await;
+}
+
+#[tokio::test]
+async fn cbor_round_trips() {
+    round_trip(Encoding::Cbor).await;
+}
+
+#[tokio::test]
+async fn unknown_preambles_are_read_as_json() {
+    let (mut client, server) = duplex(1024);
+    client.write_all(&[0x03]).await.unwrap();
+    let mut connection = Connection::new(server);
+    assert_eq!(connection.negotiate_encoding().await.unwrap(), Encoding::Json);
+
+    // Which makes for a malformed request, since JSON can't start with a control character.
+    let (received, report) = exchange(b"\x03{\"method\":\"isPrime\",\"number\":7}\n").await;
+    assert_eq!(received, Encoding::Json.malformed_frame());
+    assert_eq!(report.requests_served, 0);
+    assert!(matches!(report.outcome, ConnectionOutcome::Malformed), "{:?}", report.outcome);
+}
+
+#[tokio::test]
+async fn frames_longer_than_the_limit_are_turned_down() {
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        let length = (MAX_FRAME_LENGTH as u32 + 1).to_be_bytes();
+        assert_malformed(encoding, &length).await;
+    }
+}
+
+#[tokio::test]
+async fn hanging_up_partway_through_a_frame_is_malformed() {
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        let frame = encoding.encode(&request(7.0)).unwrap();
+        // In the middle of the length prefix.
+        assert_malformed(encoding, &frame[..2]).await;
+        // In the middle of the body.
+        assert_malformed(encoding, &frame[..frame.len() - 1]).await;
+    }
+}
@@ -0,0 +1,124 @@
+//! Requests are counted and timed, slow ones are logged, and it's all served to Prometheus.
+
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use prime_time::{metrics::Metrics, Handler};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Answer the given requests, and hang up.
+async fn answer(metrics: &Metrics, requests: &[u8]) {
+    let (mut client, server) = duplex(1024);
+    client.write_all(requests).await.unwrap();
+    client.shutdown().await.unwrap();
+    Handler::new(server, None)
+        .with_metrics(Some(metrics.clone()))
+        .serve()
+        .await;
+}
+
+/// Keeps everything logged to it, so tests can look for what they expect.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn logged(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+/// Answer the given requests, and get back whatever was logged about them.
+async fn answer_and_log(metrics: &Metrics, requests: &[u8]) -> String {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    answer(metrics, requests).await;
+    captured.logged()
+}
+
+#[tokio::test]
+async fn responses_are_counted_by_result() {
+    let metrics = Metrics::new();
+    answer(
+        &metrics,
+        b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"isPrime\",\"number\":11}\n{\"method\":\"isPrime\",\"number\":9}\n",
+    )
+    .await;
+    answer(&metrics, b"{\"method\":\"isPrime\",\"number\":2}\nnot json\n").await;
+
+    let encoded = metrics.encode();
+    for expected in [
+        "prime_time_responses_total{result=\"prime\"} 3",
+        "prime_time_responses_total{result=\"not_prime\"} 1",
+        "prime_time_responses_total{result=\"malformed\"} 1",
+        "prime_time_request_duration_seconds_count 4",
+        "prime_time_primality_check_duration_seconds_count 4",
+    ] {
+        assert!(encoded.contains(expected), "{} isn't in:\n{}", expected, encoded);
+    }
+}
+
+#[tokio::test]
+async fn only_slow_requests_are_logged() {
+    let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+
+    let patient = Metrics::new().with_slow_request_threshold(Duration::from_secs(3600));
+    let logged = answer_and_log(&patient, request).await;
+    assert!(!logged.contains("Slow request."), "{}", logged);
+
+    let impatient = Metrics::new().with_slow_request_threshold(Duration::ZERO);
+    let logged = answer_and_log(&impatient, request).await;
+    assert!(logged.contains("Slow request."), "{}", logged);
+    assert!(logged.contains("number=7.0"), "{}", logged);
+}
+
+#[tokio::test]
+async fn metrics_are_served_over_http() {
+    let metrics = Metrics::new();
+    answer(&metrics, b"{\"method\":\"isPrime\",\"number\":5}\n").await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics.serve(listener));
+
+    let mut scraper = TcpStream::connect(addr).await.unwrap();
+    scraper
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    timeout(WAIT, scraper.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(
+        response.contains("prime_time_responses_total{result=\"prime\"} 1"),
+        "{}",
+        response
+    );
+}
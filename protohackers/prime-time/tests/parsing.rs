@@ -0,0 +1,375 @@
+//! Differential tests for the newline-delimited JSON protocol: random request lines are
+//! split at arbitrary byte boundaries, fed through a [Connection] over an in-memory stream,
+//! and whatever comes out is checked against an independent [oracle] of the protocol.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::{pin, Pin},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use prime_time::{Connection, ConnectionOutcome, Handler};
+use proptest::prelude::*;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A straightforward reading of the protocol, written without looking at the server.
+mod oracle {
+    use serde_json::Value;
+
+    /// What a single line of input amounts to.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Line {
+        /// A JSON object with a string `method` and a numeric `number`, whatever the method is.
+        Request { method: String, number: f64 },
+        /// Anything else.
+        Malformed,
+    }
+
+    impl Line {
+        pub fn parse(line: &[u8]) -> Self {
+            let Ok(Value::Object(fields)) = serde_json::from_slice::<Value>(line) else {
+                return Self::Malformed;
+            };
+            match (fields.get("method"), fields.get("number")) {
+                (Some(Value::String(method)), Some(Value::Number(number))) => Self::Request {
+                    method: method.clone(),
+                    number: number.as_f64().unwrap(),
+                },
+                _ => Self::Malformed,
+            }
+        }
+    }
+
+    pub fn is_prime(number: f64) -> bool {
+        if number.fract() != 0.0 || number < 2.0 {
+            return false;
+        }
+        let number = number as u64;
+        let mut divisor = 2;
+        while divisor * divisor <= number {
+            if number.is_multiple_of(divisor) {
+                return false;
+            }
+            divisor += 1;
+        }
+        true
+    }
+
+    /// Split the input into complete lines, and whatever is left after the last newline.
+    pub fn lines(input: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+        let mut lines: Vec<&[u8]> = input.split(|&byte| byte == b'\n').collect();
+        let rest = lines.pop().unwrap();
+        (lines, rest)
+    }
+
+    /// Everything the server is expected to write back, given everything the client wrote,
+    /// and whether the client hung up afterwards.
+    pub fn responses(input: &[u8], eof: bool) -> Vec<u8> {
+        let (lines, rest) = lines(input);
+        let mut output = vec![];
+        for line in lines {
+            match Line::parse(line) {
+                Line::Request { method, number } if method == "isPrime" => {
+                    let response = format!(r#"{{"method":"isPrime","prime":{}}}"#, is_prime(number));
+                    output.extend_from_slice(response.as_bytes());
+                    output.push(b'\n');
+                },
+                _ => {
+                    output.extend_from_slice(MALFORMED);
+                    return output;
+                }
+            }
+        }
+        // A line that never got its newline is not a request.
+        if eof && !rest.is_empty() {
+            output.extend_from_slice(MALFORMED);
+        }
+        output
+    }
+
+    /// The server promises nothing about a malformed response,
+    /// other than it is not a valid response.
+    pub const MALFORMED: &[u8] = &[1, 2, b'\n'];
+}
+
+/// Hands out reads in exactly the chunks it was given, then either hangs up, or
+/// stays open forever without sending anything else. Everything written to it is kept.
+#[derive(Debug)]
+struct ChunkedStream {
+    chunks: VecDeque<Vec<u8>>,
+    eof: bool,
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl ChunkedStream {
+    fn new(input: &[u8], splits: &[usize], eof: bool) -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let mut splits: Vec<usize> = splits.iter().map(|split| split % (input.len() + 1)).collect();
+        splits.push(0);
+        splits.push(input.len());
+        splits.sort();
+        splits.dedup();
+
+        let chunks = splits
+            .windows(2)
+            .map(|window| input[window[0]..window[1]].to_vec())
+            .collect();
+        let written: Arc<Mutex<Vec<u8>>> = Default::default();
+        (Self { chunks, eof, written: written.clone() }, written)
+    }
+}
+
+impl AsyncRead for ChunkedStream {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let Some(mut chunk) = self.chunks.pop_front() else {
+            return match self.eof {
+                true => Poll::Ready(Ok(())),
+                // Nobody will ever wake us up, just like a client that went quiet.
+                false => Poll::Pending,
+            };
+        };
+        let len = chunk.len().min(buf.remaining());
+        buf.put_slice(&chunk[..len]);
+        if len < chunk.len() {
+            self.chunks.push_front(chunk.split_off(len));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ChunkedStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Drive a future as far as it goes without waiting on anything, since a [ChunkedStream]
+/// that stays open never wakes anyone up.
+fn poll_until_stuck<F: Future>(future: F) -> Option<F::Output> {
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    // Safety: the waker does nothing, so there's nothing for it to break.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    }
+}
+
+/// A number, written in any of the ways JSON allows.
+fn number() -> impl Strategy<Value = String> {
+    prop_oneof![
+        (0u32..200).prop_map(|n| n.to_string()),
+        any::<u32>().prop_map(|n| n.to_string()),
+        (-1000i32..0).prop_map(|n| n.to_string()),
+        (0u32..200).prop_map(|n| format!("{}.0", n)),
+        (0u32..200).prop_map(|n| format!("{}e0", n)),
+        (1u32..20).prop_map(|n| format!("{}E+1", n)),
+        (0u32..200, 1u32..1000).prop_map(|(n, frac)| format!("{}.{}", n, frac)),
+        Just("-0".to_string()),
+        Just("0.7e1".to_string()),
+        Just("7.0000000001".to_string()),
+        Just("6.9999999999".to_string()),
+        Just("1e-7".to_string()),
+    ]
+}
+
+/// Anything that should never be taken as a number.
+fn not_a_number() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just(r#""7""#.to_string()),
+        Just("true".to_string()),
+        Just("null".to_string()),
+        Just("[7]".to_string()),
+        Just("{}".to_string()),
+        Just("NaN".to_string()),
+        Just("07".to_string()),
+        Just("+7".to_string()),
+        Just("1e400".to_string()),
+    ]
+}
+
+fn method() -> impl Strategy<Value = String> {
+    prop_oneof![
+        8 => Just(r#""isPrime""#.to_string()),
+        1 => Just(r#""IsPrime""#.to_string()),
+        1 => Just(r#""isPrime ""#.to_string()),
+        1 => Just(r#""""#.to_string()),
+        1 => Just("7".to_string()),
+        1 => Just("null".to_string()),
+    ]
+}
+
+/// Whitespace JSON allows between tokens.
+fn whitespace() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => Just(String::new()),
+        1 => Just(" ".to_string()),
+        1 => Just("\t".to_string()),
+        1 => Just("\r".to_string()),
+    ]
+}
+
+/// A JSON object built from the given fields, in any order, with any whitespace.
+fn object(fields: Vec<(&'static str, String)>) -> impl Strategy<Value = String> {
+    let whitespace = proptest::collection::vec(whitespace(), 4 * fields.len() + 2);
+    (Just(fields).prop_shuffle(), whitespace).prop_map(|(fields, whitespace)| {
+        let mut whitespace = whitespace.into_iter();
+        let mut next = || whitespace.next().unwrap();
+
+        let mut object = format!("{}{{", next());
+        let members: Vec<String> = fields
+            .into_iter()
+            .map(|(key, value)| format!("{}\"{}\"{}:{}{}{}", next(), key, next(), next(), value, next()))
+            .collect();
+        object.push_str(&members.join(","));
+        object.push('}');
+        object.push_str(&next());
+        object
+    })
+}
+
+/// A single line of input, without its newline.
+fn line() -> impl Strategy<Value = Vec<u8>> {
+    let valid = (method(), number())
+        .prop_flat_map(|(method, number)| object(vec![("method", method), ("number", number)]));
+    let extra_fields = (number(), any::<bool>()).prop_flat_map(|(number, flag)| {
+        object(vec![
+            ("method", r#""isPrime""#.to_string()),
+            ("number", number),
+            ("extra", flag.to_string()),
+            ("nested", r#"{"number":"no"}"#.to_string()),
+        ])
+    });
+    let wrong_number = not_a_number()
+        .prop_flat_map(|number| object(vec![("method", r#""isPrime""#.to_string()), ("number", number)]));
+    let missing_field = prop_oneof![
+        number().prop_flat_map(|number| object(vec![("number", number)])),
+        object(vec![("method", r#""isPrime""#.to_string())]),
+        Just("{}".to_string()),
+    ];
+    let not_an_object = prop_oneof![
+        Just(String::new()),
+        Just("[]".to_string()),
+        Just(r#"["isPrime", 7]"#.to_string()),
+        Just("7".to_string()),
+        Just(r#""isPrime""#.to_string()),
+        "[a-z{}\":, ]{0,20}",
+    ];
+    let valid_with_trailer = (number(), prop_oneof![Just("x"), Just("}"), Just("{}"), Just(",")])
+        .prop_map(|(number, trailer)| format!(r#"{{"method":"isPrime","number":{}}}{}"#, number, trailer));
+    let garbage = proptest::collection::vec(any::<u8>().prop_filter("not a newline", |&byte| byte != b'\n'), 0..20);
+
+    prop_oneof![
+        8 => valid.prop_map(String::into_bytes),
+        2 => extra_fields.prop_map(String::into_bytes),
+        1 => wrong_number.prop_map(String::into_bytes),
+        1 => missing_field.prop_map(String::into_bytes),
+        1 => not_an_object.prop_map(String::into_bytes),
+        1 => valid_with_trailer.prop_map(String::into_bytes),
+        1 => garbage,
+    ]
+}
+
+/// Everything a client sends on a connection: a bunch of lines, perhaps
+/// followed by an incomplete one.
+fn input() -> impl Strategy<Value = Vec<u8>> {
+    (proptest::collection::vec(line(), 0..8), proptest::option::of(line())).prop_map(|(lines, rest)| {
+        let mut input = vec![];
+        for line in lines {
+            input.extend_from_slice(&line);
+            input.push(b'\n');
+        }
+        if let Some(rest) = rest {
+            input.extend_from_slice(&rest);
+        }
+        input
+    })
+}
+
+/// Where to split the input across reads.
+fn splits() -> impl Strategy<Value = Vec<usize>> {
+    proptest::collection::vec(any::<usize>(), 0..16)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1024))]
+
+    /// The server writes back exactly what the oracle says it should.
+    #[test]
+    fn responses_match_oracle(input in input(), splits in splits(), eof in any::<bool>()) {
+        let (stream, written) = ChunkedStream::new(&input, &splits, eof);
+        let mut handler = Handler::new(stream, None);
+        let outcome = poll_until_stuck(handler.run());
+
+        let written = written.lock().unwrap().clone();
+        let expected = oracle::responses(&input, eof);
+        prop_assert_eq!(
+            String::from_utf8_lossy(&written),
+            String::from_utf8_lossy(&expected),
+            "input: {:?}", String::from_utf8_lossy(&input)
+        );
+
+        // The server only gets to finish if the client hung up, or it found something malformed.
+        let hung_up_on_malformed = expected.ends_with(oracle::MALFORMED);
+        match outcome {
+            Some(Ok(ConnectionOutcome::Closed)) => prop_assert!(eof && !hung_up_on_malformed),
+            Some(Ok(ConnectionOutcome::Malformed)) => prop_assert!(hung_up_on_malformed),
+            Some(outcome) => prop_assert!(false, "unexpected outcome: {:?}", outcome),
+            None => prop_assert!(!eof && !hung_up_on_malformed),
+        }
+    }
+
+    /// Reading frames off a connection yields the requests the oracle finds, in order,
+    /// regardless of how the input was split across reads.
+    #[test]
+    fn read_frame_matches_oracle(input in input(), splits in splits()) {
+        let (stream, _) = ChunkedStream::new(&input, &splits, true);
+        let mut connection = Connection::new(stream);
+
+        let (lines, rest) = oracle::lines(&input);
+        for line in lines {
+            let frame = poll_until_stuck(connection.read_frame()).expect("the stream never blocks");
+            match (oracle::Line::parse(line), frame) {
+                (oracle::Line::Request { method, number }, Ok(Some(frame))) => {
+                    prop_assert_eq!(frame.method, method);
+                    prop_assert_eq!(frame.number.to_bits(), number.to_bits());
+                },
+                (oracle::Line::Malformed, Err(_)) => return Ok(()),
+                (expected, actual) => prop_assert!(
+                    false,
+                    "line {:?}: expected {:?}, got {:?}", String::from_utf8_lossy(line), expected, actual
+                ),
+            }
+        }
+        let last = poll_until_stuck(connection.read_frame()).expect("the stream never blocks");
+        match rest.is_empty() {
+            true => prop_assert!(matches!(last, Ok(None)), "expected EOF, got {:?}", last),
+            false => prop_assert!(last.is_err(), "expected an incomplete line to be malformed, got {:?}", last),
+        }
+    }
+}
+
+#[test]
+fn oracle_knows_small_primes() {
+    let primes: Vec<u32> = (0..30).filter(|&n| oracle::is_prime(n as f64)).collect();
+    assert_eq!(primes, vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+}
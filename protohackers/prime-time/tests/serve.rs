@@ -0,0 +1,74 @@
+//! Serving any byte stream, reporting how each connection went, and shutting down on request.
+
+use std::time::Duration;
+
+use prime_time::{ConnectionOutcome, PrimeTime};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn serves_an_in_memory_stream_and_reports_on_it() {
+    let (mut client, server) = duplex(1024);
+    let served = tokio::spawn(PrimeTime::serve(server, None));
+
+    client
+        .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"isPrime\",\"number\":8}\n")
+        .await
+        .unwrap();
+    client.shutdown().await.unwrap();
+    let mut responses = String::new();
+    client.read_to_string(&mut responses).await.unwrap();
+    assert_eq!(
+        responses,
+        "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"isPrime\",\"prime\":false}\n"
+    );
+
+    let report = timeout(WAIT, served).await.unwrap().unwrap();
+    assert_eq!(report.remote_addr, None);
+    assert_eq!(report.requests_served, 2);
+    assert!(matches!(report.outcome, ConnectionOutcome::Closed), "{:?}", report.outcome);
+}
+
+#[tokio::test]
+async fn reports_malformed_requests() {
+    let (mut client, server) = duplex(1024);
+    let served = tokio::spawn(PrimeTime::serve(server, None));
+
+    client
+        .write_all(b"{\"method\":\"isPrime\",\"number\":2}\n{\"method\":\"isComposite\",\"number\":2}\n")
+        .await
+        .unwrap();
+    let report = timeout(WAIT, served).await.unwrap().unwrap();
+    assert_eq!(report.requests_served, 1);
+    assert!(matches!(report.outcome, ConnectionOutcome::Malformed), "{:?}", report.outcome);
+}
+
+#[tokio::test]
+async fn shutting_down_stops_accepting_and_reports_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut prime_time = PrimeTime::new(listener);
+    let shutdown = prime_time.shutdown_handle();
+    let mut reports = prime_time.subscribe_reports();
+    let running = tokio::spawn(async move { prime_time.run().await });
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"{\"method\":\"isPrime\",\"number\":13}\n")
+        .await
+        .unwrap();
+    client.shutdown().await.unwrap();
+    let report = timeout(WAIT, reports.recv()).await.unwrap().unwrap();
+    assert_eq!(report.remote_addr, Some(client.local_addr().unwrap()));
+    assert_eq!(report.requests_served, 1);
+    assert!(matches!(report.outcome, ConnectionOutcome::Closed), "{:?}", report.outcome);
+
+    shutdown.shutdown();
+    timeout(WAIT, running).await.unwrap().unwrap().unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
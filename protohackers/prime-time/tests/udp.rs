@@ -0,0 +1,85 @@
+//! Every datagram is answered like a connection that sends it and hangs up,
+//! with replies packed into datagrams no bigger than the limit.
+
+use std::time::Duration;
+
+use prime_time::{udp::PrimeTimeUdp, Encoding};
+use tokio::{net::UdpSocket, time::timeout};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+const SEVEN: &str = "{\"method\":\"isPrime\",\"number\":7}\n";
+const EIGHT: &str = "{\"method\":\"isPrime\",\"number\":8}\n";
+const PRIME: &str = "{\"method\":\"isPrime\",\"prime\":true}\n";
+const NOT_PRIME: &str = "{\"method\":\"isPrime\",\"prime\":false}\n";
+
+async fn answer(datagram: &str, max_datagram_size: usize) -> Vec<String> {
+    PrimeTimeUdp::answer(datagram.as_bytes(), None, max_datagram_size, None)
+        .await
+        .into_iter()
+        .map(|reply| String::from_utf8(reply).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn every_request_in_a_datagram_is_answered() {
+    let datagram = format!("{}{}{}", SEVEN, EIGHT, SEVEN.trim_end());
+    assert_eq!(
+        answer(&datagram, PrimeTimeUdp::DEFAULT_MAX_DATAGRAM_SIZE).await,
+        [format!("{}{}{}", PRIME, NOT_PRIME, PRIME)]
+    );
+}
+
+#[tokio::test]
+async fn replies_stop_at_a_malformed_request() {
+    let datagram = format!("{}{{\"method\":\"isComposite\",\"number\":7}}\n{}", SEVEN, EIGHT);
+    let malformed = String::from_utf8(Encoding::Json.malformed_frame().to_vec()).unwrap();
+    assert_eq!(
+        answer(&datagram, PrimeTimeUdp::DEFAULT_MAX_DATAGRAM_SIZE).await,
+        [format!("{}{}", PRIME, malformed)]
+    );
+}
+
+#[tokio::test]
+async fn replies_are_split_between_responses() {
+    // Both requests fit in a datagram, but both responses don't.
+    let max_datagram_size = SEVEN.len() + EIGHT.len();
+    assert!(PRIME.len() + NOT_PRIME.len() > max_datagram_size);
+    let datagram = format!("{}{}{}", SEVEN, EIGHT, SEVEN);
+    let datagram = &datagram[..max_datagram_size];
+
+    assert_eq!(answer(datagram, max_datagram_size).await, [PRIME, NOT_PRIME]);
+}
+
+#[tokio::test]
+async fn oversized_datagrams_are_malformed() {
+    let datagram = SEVEN.repeat(4);
+    assert_eq!(
+        answer(&datagram, datagram.len() - 1).await,
+        [String::from_utf8(Encoding::Json.malformed_frame().to_vec()).unwrap()]
+    );
+}
+
+#[tokio::test]
+async fn datagrams_are_answered_over_the_socket() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut prime_time = PrimeTimeUdp::new(server).with_max_in_flight(1);
+    let shutdown = prime_time.shutdown_handle();
+    let running = tokio::spawn(async move { prime_time.run().await });
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(addr).await.unwrap();
+    let mut buf = vec![0; PrimeTimeUdp::DEFAULT_MAX_DATAGRAM_SIZE];
+    for (request, response) in [(SEVEN, PRIME), (EIGHT, NOT_PRIME)] {
+        client.send(request.as_bytes()).await.unwrap();
+        let received = timeout(WAIT, client.recv(&mut buf))
+            .await
+            .expect("Waited too long for a reply")
+            .unwrap();
+        assert_eq!(&buf[..received], response.as_bytes());
+    }
+
+    shutdown.shutdown();
+    timeout(WAIT, running).await.unwrap().unwrap().unwrap();
+}